@@ -1,15 +1,26 @@
 #[cfg(all(not(debug_assertions), not(target_arch = "wasm32")))]
 use crate::backend::load_directory;
 use crate::ui::{
-  BookTextStyle, DocumentColors, Note, PanelState, UIState, BLUISH,
-  DARKISH_BLUISH, DARK_BLUISH, LIGHTISH_BLUISH, LIGHT_BLUISH,
+  BookTextStyle, DocumentColors, GotoTarget, NavigationHistory, PanelState,
+  UIState, BLUISH, DARKISH_BLUISH, DARK_BLUISH, LIGHTISH_BLUISH, LIGHT_BLUISH,
 };
 use crate::{
-  backend::{LocalBookInfo, Shelf},
+  backend::{CachedChapter, LocalBookInfo, Shelf},
+  fonts::{self, FallbackChain, RegisteredFonts, UserFont},
+  index::{LibraryHit, LibraryIndex},
+  pages::PageNumbers,
+  progress::ReadingSpeed,
+  search::BookSearch,
+  toc::TocEntry,
   ui,
 };
+#[cfg(target_arch = "wasm32")]
+use crate::{
+  font_storage::{self, StoredFonts},
+  side_buttons::{self, SideButtonPresses},
+};
 use eframe::{
-  egui::{self, style::WidgetVisuals, FontDefinitions},
+  egui::{self, style::WidgetVisuals},
   epaint::{FontFamily, Rounding},
   epi,
 };
@@ -17,26 +28,87 @@ use egui::{vec2, Color32, Stroke, Vec2};
 use egui_extras::RetainedImage;
 use epub::doc::EpubDoc;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::io::Cursor;
-use std::{collections::HashMap, sync::Arc};
 
 #[derive(Serialize, Deserialize)]
 pub struct Pend {
   pub ui_state: UIState,
   pub library_path: String,
+  /// Directory that fonts added by the user are loaded from (and copied into)
+  #[serde(default = "default_fonts_path")]
+  pub fonts_path: String,
+  /// Fonts added by the user. Only saved on the web (without their data,
+  /// which is kept in the browser's storage), as on native they are loaded
+  /// from `fonts_path`
+  #[serde(default)]
+  #[cfg_attr(not(target_arch = "wasm32"), serde(skip))]
+  pub user_fonts: Vec<UserFont>,
+  /// Data of the user's fonts read from the browser's storage
+  #[cfg(target_arch = "wasm32")]
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub stored_fonts: StoredFonts,
+  /// Presses of the mouse's back & forward buttons, which the reader moves
+  /// through its history with
+  #[cfg(target_arch = "wasm32")]
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub side_button_presses: SideButtonPresses,
+  /// Fonts drawing the characters that the reader's fonts don't have
+  #[serde(default)]
+  pub font_fallbacks: Vec<FallbackChain>,
   pub shelves: Vec<Shelf>,
   #[serde(skip_serializing)]
   #[serde(skip_deserializing)]
   pub epub_cache: HashMap<String, EpubDoc<Cursor<Vec<u8>>>>,
   pub shelf_search: String,
+  /// Full-text index of the books in the library directory
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub library_index: LibraryIndex,
+  /// Results of searching the text of the library for `shelf_search`
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub library_hits: Vec<LibraryHit>,
   #[serde(skip_serializing)]
   #[serde(skip_deserializing)]
   pub book_covers: HashMap<String, RetainedImage>,
+  /// Images from within books: UUID -> (path within the epub -> image)
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub book_images: HashMap<String, HashMap<String, Option<RetainedImage>>>,
+  /// UUID -> (spine index -> parsed chapter)
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub chapter_cache: HashMap<String, HashMap<usize, CachedChapter>>,
+  /// UUID -> number of characters in each chapter
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub chapter_lengths: HashMap<String, Vec<usize>>,
+  /// UUID -> table of contents
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub book_tocs: HashMap<String, Vec<TocEntry>>,
+  /// UUID -> page numbers
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub page_numbers: HashMap<String, PageNumbers>,
+  /// UUID -> positions jumped away from in the reader
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub history: HashMap<String, NavigationHistory>,
+  /// Fonts of the selected book that are in use by egui
+  #[serde(skip_serializing)]
+  #[serde(skip_deserializing)]
+  pub registered_fonts: RegisteredFonts,
   pub selected_book_uuid: Option<String>,
   pub book_style: BookTextStyle,
   pub book_userdata: HashMap<String, LocalBookInfo>,
-  pub goto_target: Option<Note>,
+  pub goto_target: Option<GotoTarget>,
+  #[serde(default)]
+  pub reading_speed: ReadingSpeed,
   pub theme: DocumentColors,
   pub book_cover_width_multiplier: f32,
   /// UUID original shelf name, title
@@ -44,6 +116,10 @@ pub struct Pend {
   pub reorganizing_shelf: bool,
 }
 
+fn default_fonts_path() -> String {
+  "./fonts".into()
+}
+
 impl Default for Pend {
   fn default() -> Self {
     Self {
@@ -53,16 +129,45 @@ impl Default for Pend {
         reader_focus_mode: false,
         display_ofl_popup: false,
         display_raw_text: false,
+        confirm_external_link: None,
+        footnote_popup: None,
+        reader_selection: None,
+        book_search: BookSearch::default(),
+        reader_page: (0, 0),
+        reader_scroll: None,
+        reader_book: None,
+        reader_view: None,
+        goto_percent: 0.0,
+        goto_page: String::new(),
+        reader_shown: None,
+        reading_mark: None,
       },
       library_path: "./library".into(),
+      fonts_path: default_fonts_path(),
+      user_fonts: Vec::new(),
+      #[cfg(target_arch = "wasm32")]
+      stored_fonts: StoredFonts::default(),
+      #[cfg(target_arch = "wasm32")]
+      side_button_presses: SideButtonPresses::default(),
+      font_fallbacks: Vec::new(),
       shelves: Vec::new(),
       epub_cache: HashMap::new(),
       shelf_search: String::new(),
+      library_index: LibraryIndex::default(),
+      library_hits: Vec::new(),
       book_covers: HashMap::new(),
+      book_images: HashMap::new(),
+      chapter_cache: HashMap::new(),
+      chapter_lengths: HashMap::new(),
+      book_tocs: HashMap::new(),
+      page_numbers: HashMap::new(),
+      history: HashMap::new(),
+      registered_fonts: RegisteredFonts::default(),
       selected_book_uuid: None,
       book_style: BookTextStyle::default(),
       book_userdata: HashMap::new(),
       goto_target: None,
+      reading_speed: ReadingSpeed::default(),
       theme: DocumentColors::default(),
       book_cover_width_multiplier: 1.0,
       dragged_book: None,
@@ -149,48 +254,26 @@ impl epi::App for Pend {
     });
 
     // Font setup
-    let mut fonts = FontDefinitions::default();
-
-    fonts.font_data.insert(
-      "work_sans_medium".to_string(),
-      egui::FontData::from_static(include_bytes!(
-        "../compiletime_resources/WorkSans-Medium.ttf"
-      )),
-    );
-
-    fonts.font_data.insert(
-      "merriweather_regular".to_string(),
-      egui::FontData::from_static(include_bytes!(
-        "../compiletime_resources/Merriweather-Regular.ttf"
-      )),
-    );
-
-    fonts.font_data.insert(
-      "noto_mono_regular".to_string(),
-      egui::FontData::from_static(include_bytes!(
-        "../compiletime_resources/NotoSansMono-Regular.ttf"
-      )),
-    );
-
-    fonts
-      .families
-      .entry(eframe::epaint::FontFamily::Proportional)
-      .or_default()
-      .insert(0, "work_sans_medium".into());
-
-    fonts
-      .families
-      .entry(FontFamily::Name(Arc::from("Merriweather")))
-      .or_default()
-      .insert(0, "merriweather_regular".into());
-
-    fonts
-      .families
-      .entry(FontFamily::Monospace)
-      .or_default()
-      .insert(0, "noto_mono_regular".into());
-
-    ctx.set_fonts(fonts);
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+      self.user_fonts = fonts::load_font_directory(&self.fonts_path);
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+      // Older versions saved fonts along with the rest of the state, from
+      // which they are moved into the browser's storage
+      for font in self.user_fonts.iter().filter(|font| !font.data.is_empty()) {
+        font_storage::save(&font.name, &font.data);
+      }
+      let unread = self
+        .user_fonts
+        .iter()
+        .filter(|font| font.data.is_empty())
+        .map(|font| font.name.clone())
+        .collect();
+      self.stored_fonts = font_storage::load(ctx, unread);
+    }
+    fonts::set_fonts(ctx, self, &[]);
 
     // Load local book directory (only in native && release mode)
     #[cfg(all(not(debug_assertions), not(target_arch = "wasm32")))]
@@ -198,6 +281,7 @@ impl epi::App for Pend {
 
     #[cfg(target_arch = "wasm32")]
     {
+      self.side_button_presses = side_buttons::listen(ctx);
       self.shelves.clear();
       self.book_userdata.clear();
       self.book_covers.clear();
@@ -206,6 +290,17 @@ impl epi::App for Pend {
 
   fn update(&mut self, ctx: &egui::Context, _frame: &epi::Frame) {
     ui::main(ctx, self);
+    fonts::register_fonts(ctx, self);
+    // Side buttons only do anything in the reader, which takes the presses
+    // it needs while it is shown
+    #[cfg(target_arch = "wasm32")]
+    self.side_button_presses.borrow_mut().clear();
+
+    // Books are indexed a chapter per frame, so the app stays usable while a
+    // library is indexed
+    if self.library_index.index_pending(&mut self.epub_cache) {
+      ctx.request_repaint();
+    }
   }
 
   fn save(&mut self, storage: &mut dyn epi::Storage) {
@@ -238,7 +333,14 @@ impl Pend {
     for shelf in self.shelves.iter_mut() {
       shelf.uuids.retain(|u| *u != uuid);
     }
-    // Remove cover
+    // Remove cover & images
     self.book_covers.remove(&uuid);
+    self.book_images.remove(&uuid);
+    self.chapter_cache.remove(&uuid);
+    self.chapter_lengths.remove(&uuid);
+    self.book_tocs.remove(&uuid);
+    self.page_numbers.remove(&uuid);
+    self.history.remove(&uuid);
+    self.library_hits.retain(|hit| hit.uuid != uuid);
   }
 }
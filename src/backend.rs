@@ -1,23 +1,30 @@
-use std::{collections::HashMap, fmt::Display, fs, io::Cursor};
+use std::{
+  collections::{hash_map::Entry, HashMap, HashSet},
+  fmt::Display,
+  fs,
+  io::Cursor,
+  ops::Range,
+  path::Path,
+  sync::Arc,
+};
 
-use egui::Color32;
+use egui::{Color32, Galley};
 use egui_extras::RetainedImage;
 use epub::doc::EpubDoc;
 use glob::glob;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use crate::{ui::Note, Pend};
-
-/// Denotes type of formatting to be applied to a line group
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
-pub enum FormattingInfo {
-  Title,
-  Heading,
-  Heading2,
-  Bold,
-  Italic,
-}
+use crate::{
+  css::Stylesheet,
+  document::{language_tag, Block, Document},
+  index::{LibraryIndex, INDEX_FILE_NAME},
+  location::{TextPosition, TextRange},
+  toc::load_toc,
+  ui::Note,
+  xhtml::decode_entities,
+  Pend,
+};
 
 /// Contains custom content a user creates for each book (notes, highlighted lines, etc.)
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -25,10 +32,28 @@ pub struct LocalBookInfo {
   pub notes: Vec<Note>,
   /// Last page the user viewed
   pub chapter: usize,
-  /// (Chapter, Line), Color of the highlight
-  pub highlights: HashMap<(usize, usize), Color32>,
-  /// (Chapter, Line), info
-  pub formatting_info: HashMap<(usize, usize), FormattingInfo>,
+  /// Where in `chapter` the user was reading
+  #[serde(default)]
+  pub position: Option<TextPosition>,
+  /// Fraction of the book's text before `position`, from 0 to 1
+  #[serde(default)]
+  pub progress: f32,
+  #[serde(default, rename = "text_highlights")]
+  pub highlights: Vec<Highlight>,
+  #[serde(default)]
+  pub bookmarks: Vec<Bookmark>,
+  /// The reader's own CSS for this book, applied after that for every book
+  #[serde(default)]
+  pub user_css: String,
+  /// Lay out this book without its own stylesheets
+  #[serde(default)]
+  pub ignore_publisher_styles: bool,
+  /// Highlights saved by older versions, which covered whole lines:
+  /// (Chapter, Line), Color of the highlight. Lines are numbered as older
+  /// versions split chapters into them, not by block. These are converted
+  /// into `highlights` when the book is loaded
+  #[serde(default, rename = "highlights", skip_serializing)]
+  pub legacy_highlights: HashMap<(usize, usize), Color32>,
 }
 
 impl LocalBookInfo {
@@ -37,8 +62,58 @@ impl LocalBookInfo {
     Self {
       notes: Vec::new(),
       chapter: 1,
-      highlights: HashMap::new(),
-      formatting_info: HashMap::new(),
+      position: None,
+      progress: 0.0,
+      highlights: Vec::new(),
+      bookmarks: Vec::new(),
+      user_css: String::new(),
+      ignore_publisher_styles: false,
+      legacy_highlights: HashMap::new(),
+    }
+  }
+}
+
+/// A highlighted range of text
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Highlight {
+  pub range: TextRange,
+  pub color: Color32,
+}
+
+/// A place in a book marked to return to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Bookmark {
+  pub position: TextPosition,
+  /// Name given to the bookmark by the user
+  #[serde(default)]
+  pub label: String,
+  /// The start of the text the bookmark is at
+  pub excerpt: String,
+  /// Fraction of the book's text before `position`, used to mark the
+  /// bookmark on the slider
+  pub progress: f32,
+}
+
+impl Bookmark {
+  #[must_use]
+  pub fn new(
+    position: TextPosition,
+    document: &Document,
+    progress: f32,
+  ) -> Self {
+    let excerpt = position
+      .resolve(document)
+      .and_then(|(block, char_index)| {
+        let text = document.blocks.get(block)?.text();
+        Some(text.chars().skip(char_index).take(48).collect::<String>())
+      })
+      .unwrap_or_default();
+
+    Self {
+      position,
+      label: String::new(),
+      excerpt: excerpt.trim().to_string(),
+      progress,
     }
   }
 }
@@ -92,79 +167,479 @@ impl Shelf {
   }
 }
 
-/// Turns calibre html into usable text / formatting info
-pub fn parse_calibre(
-  input: &str,
+/// Resolves a (relative) reference made from within a file of an epub, into
+/// the path of the referenced file within the epub's archive.
+///
+/// Any fragment (`#...`) is removed and percent-encoding is decoded
+pub fn resolve_href(base_file: &str, href: &str) -> String {
+  let href = href.split('#').next().unwrap_or_default();
+  let href = percent_decode(href);
+
+  // Links to a fragment of the same file (`#note-1`)
+  if href.is_empty() {
+    return base_file.to_string();
+  }
+
+  let mut components: Vec<&str> = if href.starts_with('/') {
+    Vec::new()
+  } else {
+    // Everything apart from the name of the base file
+    let mut base: Vec<&str> = base_file.split('/').collect();
+    base.pop();
+    base
+  };
+
+  for component in href.split('/') {
+    match component {
+      "" | "." => {}
+      ".." => {
+        components.pop();
+      }
+      component => components.push(component),
+    }
+  }
+
+  components.join("/")
+}
+
+/// Whether a link points outside of the book (a website, email, etc.)
+pub fn is_external_link(href: &str) -> bool {
+  href.contains("://") || href.starts_with("mailto:")
+}
+
+/// Decodes `%XX` escapes in a URL. Invalid escapes are left as-is
+pub fn percent_decode(input: &str) -> String {
+  let bytes = input.as_bytes();
+  let mut output = Vec::with_capacity(bytes.len());
+  let mut index = 0;
+
+  while index < bytes.len() {
+    if bytes[index] == b'%' && index + 2 < bytes.len() {
+      if let Some(byte) = std::str::from_utf8(&bytes[index + 1..index + 3])
+        .ok()
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+      {
+        output.push(byte);
+        index += 3;
+        continue;
+      }
+    }
+
+    output.push(bytes[index]);
+    index += 1;
+  }
+
+  String::from_utf8_lossy(&output).into_owned()
+}
+
+/// Encodes text for use within a URL's query, escaping everything apart from
+/// unreserved characters
+pub fn percent_encode(input: &str) -> String {
+  input
+    .bytes()
+    .map(|byte| match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        (byte as char).to_string()
+      }
+      _ => format!("%{:02X}", byte),
+    })
+    .collect()
+}
+
+/// The path of the chapter currently open in an epub, with `/` separators
+pub fn current_chapter_path(book: &EpubDoc<Cursor<Vec<u8>>>) -> String {
+  book
+    .get_current_path()
+    .map(|path| path.to_string_lossy().replace('\\', "/"))
+    .unwrap_or_default()
+}
+
+/// The path of a chapter (spine item) of an epub, with `/` separators
+pub fn chapter_path(book: &EpubDoc<Cursor<Vec<u8>>>, chapter: usize) -> String {
+  book
+    .spine
+    .get(chapter)
+    .and_then(|id| book.resources.get(id))
+    .map(|(path, _)| path.to_string_lossy().replace('\\', "/"))
+    .unwrap_or_default()
+}
+
+/// Finds the spine index (chapter) of the file at a path within an epub
+pub fn spine_index_of(
+  book: &EpubDoc<Cursor<Vec<u8>>>,
+  path: &str,
+) -> Option<usize> {
+  book.spine.iter().position(|id| {
+    book
+      .resources
+      .get(id)
+      .filter(|(resource_path, _)| {
+        resource_path.to_string_lossy().replace('\\', "/") == path
+      })
+      .is_some()
+  })
+}
+
+/// Reads and parses a chapter (spine item) of a book along with its
+/// stylesheets, without changing the
+/// book's current chapter
+pub fn chapter_document(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  chapter: usize,
+) -> Option<Document> {
+  user_styled_chapter_document(book, chapter, &Stylesheet::default())
+}
+
+/// Like [`chapter_document`], with the reader's own stylesheets applied over
+/// the book's
+pub fn user_styled_chapter_document(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
   chapter: usize,
+  user_stylesheet: &Stylesheet,
+) -> Option<Document> {
+  let id = book.spine.get(chapter)?.clone();
+  let xhtml = book.get_resource_str(&id).ok()?;
+  let path = chapter_path(book, chapter);
+
+  let mut document = Document::from_xhtml_styled(
+    &xhtml,
+    |href| {
+      let bytes = book.get_resource_by_path(resolve_href(&path, href)).ok()?;
+      String::from_utf8(bytes).ok()
+    },
+    user_stylesheet,
+  );
+
+  // Text the chapter doesn't give a language for is in that of the book
+  if let Some(language) = book
+    .mdata("language")
+    .and_then(|language| language_tag(&language))
+  {
+    for block in &mut document.blocks {
+      block.language.get_or_insert_with(|| language.clone());
+    }
+  }
+
+  Some(document)
+}
+
+/// Number of characters of text in each chapter (spine item) of a book
+pub fn chapter_lengths(book: &mut EpubDoc<Cursor<Vec<u8>>>) -> Vec<usize> {
+  (0..book.spine.len())
+    .map(|chapter| {
+      chapter_document(book, chapter).map_or(0, |document| {
+        document.blocks.iter().map(Block::char_count).sum()
+      })
+    })
+    .collect()
+}
+
+/// Converts the highlights & notes of older versions, which were attached to
+/// whole lines of a chapter, into ranges of text. Lines are found by their
+/// text, so a line from the `<head>` (such as the `<title>`) ends up on the
+/// first text in the body that matches it, usually the heading. Highlights on
+/// lines whose text isn't in the chapter at all are dropped, and notes on
+/// them are kept without a range
+fn migrate_line_anchors(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
   book_info: &mut LocalBookInfo,
-) -> String {
-  let mut output = String::new();
-  let mut lines_removed = 0;
-
-  for (line_number, line) in input.lines().enumerate() {
-    let rx = Regex::new(r"<(.*?)>").unwrap();
-
-    // Best I could come up with for handling breaks; improve this later
-    let line = line.replace("<br/>", "  [break]  ");
-    let processed = rx.replace_all(&line, "");
-    let processed_line = processed.trim();
-
-    // Processes the parsed HTML using my custom parsing (could be improved ;)
-    // and converts it into formatting info
-    if !processed_line.is_empty() {
-      for captures in rx.captures_iter(&line) {
-        for capture in captures.iter().flatten() {
-          if let Some(capture) = match capture.as_str() {
-            // Not (currently) implimented and/or explicitly nothing
-            // This avoids `<img` being read as `<i` for example
-            x if x.contains("<img") => None,
-            x if x.contains("<body") => None,
-            x if x.contains("<p>") => None,
-            // Working formatting
-            x if x.contains("<title") => Some(FormattingInfo::Title),
-            x if x.contains("<h1") => Some(FormattingInfo::Heading),
-            x if x.contains("<h2") => Some(FormattingInfo::Heading2),
-            x if x.contains("<i") => Some(FormattingInfo::Italic),
-            x if x.contains("<b") => Some(FormattingInfo::Bold),
-            _ => None,
-          } {
-            book_info
-              .formatting_info
-              .insert((chapter, line_number - lines_removed), capture);
-          }
-        }
+) {
+  let chapters: HashSet<usize> = book_info
+    .legacy_highlights
+    .keys()
+    .map(|(chapter, _)| *chapter)
+    .chain(
+      book_info
+        .notes
+        .iter()
+        .filter(|note| note.range.is_none())
+        .map(|note| note.chapter as usize),
+    )
+    .collect();
+
+  // Chapter -> the range & text of each of its lines
+  let mut lines = HashMap::new();
+  for chapter in chapters {
+    let xhtml = book
+      .spine
+      .get(chapter)
+      .cloned()
+      .and_then(|id| book.get_resource_str(&id).ok());
+    if let (Some(xhtml), Some(document)) =
+      (xhtml, chapter_document(book, chapter))
+    {
+      lines.insert(chapter, locate_legacy_lines(&xhtml, &document, chapter));
+    }
+  }
+  let line_anchor = |chapter: usize, line: usize| {
+    lines
+      .get(&chapter)
+      .and_then(|lines| lines.get(line))
+      .and_then(Option::as_ref)
+  };
+
+  for ((chapter, line), color) in book_info.legacy_highlights.drain() {
+    if let Some((range, _)) = line_anchor(chapter, line) {
+      book_info.highlights.push(Highlight {
+        range: range.clone(),
+        color,
+      });
+    }
+  }
+
+  for note in book_info.notes.iter_mut().filter(|n| n.range.is_none()) {
+    if let Some((range, excerpt)) =
+      line_anchor(note.chapter as usize, note.line as usize)
+    {
+      note.excerpt = excerpt.clone();
+      note.range = Some(range.clone());
+    }
+  }
+}
+
+/// The lines older versions split a chapter's XHTML into, which their
+/// highlights & notes were numbered by: each line of the source with its tags
+/// removed, leaving out those that are then empty
+fn legacy_lines(xhtml: &str) -> Vec<String> {
+  let tag = Regex::new(r"<(.*?)>").unwrap();
+
+  xhtml
+    .lines()
+    .map(|line| {
+      let line = line.replace("<br/>", "  [break]  ");
+      tag.replace_all(&line, "").trim().to_string()
+    })
+    .filter(|line| !line.is_empty())
+    .collect()
+}
+
+/// Finds each of the lines older versions split a chapter into (see
+/// [`legacy_lines`]) in the chapter's document, as the range of text it
+/// covers along with that text. Lines are matched by their text without
+/// whitespace, each looked for after the one before it so that repeated
+/// lines are told apart
+fn locate_legacy_lines(
+  xhtml: &str,
+  document: &Document,
+  chapter: usize,
+) -> Vec<Option<(TextRange, String)>> {
+  // The chapter's characters other than whitespace, along with their
+  // (block, character index)
+  let mut characters = Vec::new();
+  for (block_index, block) in document.blocks.iter().enumerate() {
+    for (char_index, character) in block.text().chars().enumerate() {
+      if !character.is_whitespace() {
+        characters.push((character, block_index, char_index));
       }
     }
+  }
+
+  let mut cursor = 0;
+  legacy_lines(xhtml)
+    .iter()
+    .map(|line| {
+      let line: Vec<char> = decode_entities(&line.replace("[break]", ""))
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+      let start = find_chars(&characters, &line, cursor)?;
+      let end = start + line.len();
+      cursor = end;
+
+      let (_, start_block, start_index) = characters[start];
+      let (_, end_block, end_index) = characters[end - 1];
+      let range = TextRange {
+        start: TextPosition::new(chapter, document, start_block, start_index),
+        end: TextPosition::new(chapter, document, end_block, end_index + 1),
+      };
+
+      let text = (start_block..=end_block)
+        .map(|index| {
+          let from = if index == start_block { start_index } else { 0 };
+          let to = if index == end_block {
+            end_index + 1
+          } else {
+            usize::MAX
+          };
+          document.blocks[index]
+            .text()
+            .chars()
+            .skip(from)
+            .take(to - from)
+            .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+      Some((range, text))
+    })
+    .collect()
+}
+
+/// Finds where a sequence of characters starts in a chapter's characters,
+/// preferring the first match at or after `from`
+fn find_chars(
+  characters: &[(char, usize, usize)],
+  sequence: &[char],
+  from: usize,
+) -> Option<usize> {
+  if sequence.is_empty() {
+    return None;
+  }
+  let last = characters.len().checked_sub(sequence.len())?;
+  let matches_at = |start: &usize| {
+    characters[*start..*start + sequence.len()]
+      .iter()
+      .map(|(character, ..)| character)
+      .eq(sequence)
+  };
+
+  (from..=last)
+    .find(matches_at)
+    .or_else(|| (0..from.min(last + 1)).find(matches_at))
+}
+
+/// A chapter that has been parsed, along with the layouts of its blocks from
+/// the last time it was displayed
+pub struct CachedChapter {
+  pub document: Arc<Document>,
+  /// Block index -> layout
+  pub layouts: HashMap<usize, BlockLayout>,
+  /// Block index -> (hash of the font & width it was measured with, height),
+  /// used to size blocks that are scrolled out of view without laying them out
+  pub heights: HashMap<usize, (u64, f32)>,
+  /// How the chapter was last split into pages, for the paginated reading
+  /// mode
+  pub pages: Option<Pagination>,
+}
+
+/// Where the pages of a chapter start & end
+pub struct Pagination {
+  /// Hash of everything the pages depend on (font, page size, hidden blocks)
+  pub key: u64,
+  /// Offset of the top of each page from the top of the chapter
+  pub starts: Vec<f32>,
+  /// Offset of the top of each block from the top of the chapter, or `None`
+  /// for blocks that are hidden
+  pub block_tops: Vec<Option<f32>>,
+  /// Height of the whole chapter
+  pub height: f32,
+}
+
+impl Pagination {
+  /// Range of offsets from the top of the chapter shown on a page
+  pub fn page_range(&self, page: usize) -> Range<f32> {
+    let start = self.starts.get(page).copied().unwrap_or(self.height);
+    let end = self.starts.get(page + 1).copied().unwrap_or(self.height);
+
+    start..end
+  }
+
+  /// The page showing an offset from the top of the chapter
+  pub fn page_at(&self, offset: f32) -> usize {
+    self
+      .starts
+      .iter()
+      .rposition(|start| *start <= offset)
+      .unwrap_or(0)
+  }
+}
+
+/// The laid out text of a block
+#[derive(Clone)]
+pub struct BlockLayout {
+  /// Hash of everything the layout depends on (style, width, highlights),
+  /// used to tell when it needs to be redone
+  pub key: u64,
+  pub galley: Arc<Galley>,
+  /// Bold text, drawn over `galley`
+  pub bold_galley: Option<Arc<Galley>>,
+  /// Indices of the characters of the block's text that its lines were
+  /// wrapped before. The galleys have a newline added before each of them
+  pub wraps: Vec<usize>,
+}
+
+/// Gets a chapter of a book from a cache of its chapters (by spine index),
+/// parsing it (with the reader's CSS, `user_css`) if this is the first time it
+/// has been requested. The cache has to be cleared when `user_css` changes
+pub fn cached_chapter<'a>(
+  cache: &'a mut HashMap<usize, CachedChapter>,
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  chapter: usize,
+  user_css: &str,
+) -> Option<&'a mut CachedChapter> {
+  match cache.entry(chapter) {
+    Entry::Occupied(entry) => Some(entry.into_mut()),
+    Entry::Vacant(entry) => {
+      let mut user_stylesheet = Stylesheet::default();
+      user_stylesheet.extend_user(user_css);
+      let document =
+        user_styled_chapter_document(book, chapter, &user_stylesheet)?;
 
-    if processed_line.is_empty() {
-      lines_removed += 1;
-    } else {
-      output.push_str(processed_line);
-      output.push('\n');
+      Some(entry.insert(CachedChapter {
+        document: Arc::new(document),
+        layouts: HashMap::new(),
+        heights: HashMap::new(),
+        pages: None,
+      }))
     }
   }
+}
+
+/// Gets an image referenced by a chapter of a book (at `chapter_path`),
+/// decoding and caching it if this is the first time it has been requested.
+///
+/// Images that fail to load (or decode) are cached as `None` so the attempt
+/// is not repeated every frame
+pub fn load_chapter_image<'a>(
+  cache: &'a mut HashMap<String, Option<RetainedImage>>,
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  chapter_path: &str,
+  src: &str,
+) -> Option<&'a RetainedImage> {
+  let path = resolve_href(chapter_path, src);
 
-  output
+  cache
+    .entry(path.clone())
+    .or_insert_with(|| {
+      book
+        .get_resource_by_path(&path)
+        .ok()
+        .and_then(|bytes| RetainedImage::from_image_bytes(&path, &bytes).ok())
+    })
+    .as_ref()
 }
 
-/// Loads all epubs in a given directory (and all subfolders)
+/// Loads all epubs in a given directory (and all subfolders), along with the
+/// directory's index of their text
 pub fn load_directory<P: Into<String> + Display>(
   state: &mut Pend,
   directory: P,
 ) {
+  state.library_index =
+    LibraryIndex::load(&format!("{}/{}", directory, INDEX_FILE_NAME));
+  let mut found_paths = HashSet::new();
+
   // Finds all epub files in the user's library directory
   for file_path in glob(&format!("{}/**/*.epub", directory)).unwrap().flatten()
   {
     let epub =
-      EpubDoc::from_reader(Cursor::new(fs::read(file_path).unwrap())).unwrap();
+      EpubDoc::from_reader(Cursor::new(fs::read(&file_path).unwrap())).unwrap();
+    found_paths.insert(file_path.to_string_lossy().to_string());
 
-    register_epub(state, epub);
+    register_epub(state, epub, Some(&file_path));
   }
+
+  state.library_index.retain_paths(&found_paths);
 }
 
 /// Performs the neccesary steps to load an epub into the program and set up
-/// metadata / cover / etc
-pub fn register_epub(state: &mut Pend, mut epub: EpubDoc<Cursor<Vec<u8>>>) {
+/// metadata / cover / etc. `path` is the file the epub was read from, if any
+pub fn register_epub(
+  state: &mut Pend,
+  mut epub: EpubDoc<Cursor<Vec<u8>>>,
+  path: Option<&Path>,
+) {
   let uuid = epub.unique_identifier.as_ref().unwrap().clone();
 
   // Fallback image (if not already present)
@@ -191,6 +666,16 @@ pub fn register_epub(state: &mut Pend, mut epub: EpubDoc<Cursor<Vec<u8>>>) {
     });
   }
 
+  // Read the table of contents
+  if !state.book_tocs.contains_key(&uuid) {
+    state.book_tocs.insert(uuid.clone(), load_toc(&mut epub));
+  }
+
+  // Highlights & notes from older versions need to be anchored to text
+  if let Some(book_info) = state.book_userdata.get_mut(&uuid) {
+    migrate_line_anchors(&mut epub, book_info);
+  }
+
   // Add file uuid to library if not already added
   if !state
     .shelves
@@ -199,9 +684,10 @@ pub fn register_epub(state: &mut Pend, mut epub: EpubDoc<Cursor<Vec<u8>>>) {
     .any(|x| x == uuid)
   {
     state.shelves[0].uuids.push(uuid.clone());
-
-    state.epub_cache.insert(uuid.clone(), epub);
   }
+  // Books already on a shelf (from a previous session) still need loading
+  state.epub_cache.entry(uuid.clone()).or_insert(epub);
+  state.library_index.queue_book(&uuid, path);
 
   // If the book in question does not have userdata already: create an empty
   state
@@ -209,3 +695,66 @@ pub fn register_epub(state: &mut Pend, mut epub: EpubDoc<Cursor<Vec<u8>>>) {
     .entry(uuid)
     .or_insert_with(|| LocalBookInfo::default());
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const LEGACY_CHAPTER: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head>
+<title>Chapter One</title>
+</head>
+<body>
+<h1>Chapter One</h1>
+<p>First &amp; foremost,<br/>a line.</p>
+<p>
+Second paragraph
+spread over lines.
+</p>
+<p>Repeated</p>
+<p>Repeated</p>
+</body>
+</html>"#;
+
+  #[test]
+  fn legacy_lines_are_numbered_like_older_versions() {
+    assert_eq!(
+      legacy_lines(LEGACY_CHAPTER),
+      [
+        "Chapter One",
+        "Chapter One",
+        "First &amp; foremost,  [break]  a line.",
+        "Second paragraph",
+        "spread over lines.",
+        "Repeated",
+        "Repeated",
+      ]
+    );
+  }
+
+  #[test]
+  fn legacy_lines_are_found_by_their_text() {
+    let document = Document::from_xhtml(LEGACY_CHAPTER);
+    let lines = locate_legacy_lines(LEGACY_CHAPTER, &document, 3);
+    let range = |line: usize| lines[line].as_ref().unwrap().0.clone();
+    let resolve = |line: usize| {
+      let range = range(line);
+      (
+        range.start.resolve(&document).unwrap(),
+        range.end.resolve(&document).unwrap(),
+      )
+    };
+
+    assert_eq!(lines.len(), 7);
+    // The title isn't part of the document, but the heading has its text
+    assert_eq!(range(0), TextRange::block(3, &document, 0));
+    assert_eq!(range(1), TextRange::block(3, &document, 0));
+    assert_eq!(range(2), TextRange::block(3, &document, 1));
+    assert_eq!(lines[2].as_ref().unwrap().1, "First & foremost,\na line.");
+    assert_eq!(resolve(3), ((2, 0), (2, 16)));
+    assert_eq!(resolve(4), ((2, 17), (2, 35)));
+    assert_eq!(range(5), TextRange::block(3, &document, 3));
+    assert_eq!(range(6), TextRange::block(3, &document, 4));
+  }
+}
@@ -0,0 +1,905 @@
+//! A small CSS engine covering the subset of CSS that matters for reading:
+//! selectors made of tag names, classes and `id`s (combined with descendant
+//! and child combinators), and the properties `text-align`, `font-style`,
+//! `font-weight`, `font-variant`, `font-family`, `text-indent`, `margin` and
+//! `display: none`. Stylesheets written by the reader may also set
+//! `font-size`, `color` and `background-color`, which the publisher's are
+//! never allowed to. The fonts declared by `@font-face` rules are found
+//! separately, by [`font_faces`].
+//!
+//! Like [`crate::xhtml`], parsing never fails; anything that isn't understood
+//! (unsupported selectors, properties or values) is skipped.
+
+use crate::xhtml::Element;
+use egui::Color32;
+
+/// Lengths are kept in ems. Percentages are taken to be of a line 40ems long
+const PERCENT_IN_EMS: f32 = 0.4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextAlign {
+  Left,
+  Center,
+  Right,
+  /// Shown as left aligned, as egui loses the indent of justified text
+  Justify,
+}
+
+/// Size of the text of a block, relative to the reader's font size or not
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FontSize {
+  /// Multiple of the reader's font size
+  Scale(f32),
+  /// Absolute size, in points
+  Points(f32),
+}
+
+impl FontSize {
+  /// The size of text inside of an element of this size, with its own size
+  /// set to `inner`
+  pub fn nest(outer: Option<Self>, inner: Self) -> Self {
+    match (outer, inner) {
+      (Some(Self::Scale(outer)), Self::Scale(inner)) => {
+        Self::Scale(outer * inner)
+      }
+      (Some(Self::Points(outer)), Self::Scale(inner)) => {
+        Self::Points(outer * inner)
+      }
+      (_, inner) => inner,
+    }
+  }
+
+  /// The size in points, given the reader's font size
+  pub fn points(self, base: f32) -> f32 {
+    match self {
+      Self::Scale(scale) => base * scale,
+      Self::Points(points) => points,
+    }
+  }
+}
+
+/// A single property set by a stylesheet
+#[derive(Debug, Clone, PartialEq)]
+pub enum Declaration {
+  TextAlign(TextAlign),
+  Italic(bool),
+  Bold(bool),
+  SmallCaps(bool),
+  /// Indent of the first line, in ems
+  TextIndent(f32),
+  /// In ems
+  MarginLeft(f32),
+  /// In ems
+  MarginRight(f32),
+  /// Whether the element has `display: none`
+  Hidden(bool),
+  FontSize(FontSize),
+  /// Names of font families in order of preference, lowercased
+  FontFamily(Vec<String>),
+  Color(Color32),
+  BackgroundColor(Color32),
+}
+
+impl Declaration {
+  /// Whether only the reader's stylesheets may set the property, so that
+  /// books can't override the reader's font size & colors
+  fn is_user_only(&self) -> bool {
+    matches!(
+      self,
+      Self::FontSize(_) | Self::Color(_) | Self::BackgroundColor(_)
+    )
+  }
+}
+
+/// One step of a selector, e.g. `p.note#first`
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Compound {
+  /// `None` for `*` or when no tag is given
+  tag: Option<String>,
+  classes: Vec<String>,
+  id: Option<String>,
+}
+
+impl Compound {
+  fn matches(&self, element: &Element) -> bool {
+    if matches!(&self.tag, Some(tag) if *tag != element.name) {
+      return false;
+    }
+    if self.id.is_some() && element.attr("id") != self.id.as_deref() {
+      return false;
+    }
+
+    let classes = element.attr("class").unwrap_or_default();
+    self.classes.iter().all(|class| {
+      classes
+        .split_ascii_whitespace()
+        .any(|element_class| element_class == class)
+    })
+  }
+}
+
+/// A selector: compound steps from the outermost to the element itself,
+/// each (after the first) with whether it has to be the direct child of the
+/// step before it
+#[derive(Debug, Clone, PartialEq)]
+struct Selector {
+  steps: Vec<(Compound, bool)>,
+  /// (`id`s, classes, tags)
+  specificity: (usize, usize, usize),
+}
+
+impl Selector {
+  fn parse(input: &str) -> Option<Self> {
+    let mut steps = Vec::new();
+    let mut is_child = false;
+
+    let spaced = input.replace('>', " > ");
+    for part in spaced.split_ascii_whitespace() {
+      if part == ">" {
+        is_child = true;
+        continue;
+      }
+
+      steps.push((parse_compound(part)?, is_child));
+      is_child = false;
+    }
+
+    if steps.is_empty() || is_child {
+      return None;
+    }
+
+    let specificity =
+      steps
+        .iter()
+        .fold((0, 0, 0), |(ids, classes, tags), (step, _)| {
+          (
+            ids + usize::from(step.id.is_some()),
+            classes + step.classes.len(),
+            tags + usize::from(step.tag.is_some()),
+          )
+        });
+
+    Some(Self { steps, specificity })
+  }
+
+  /// Whether the selector matches an element, given its ancestors (from the
+  /// root down to its parent)
+  fn matches(&self, element: &Element, ancestors: &[&Element]) -> bool {
+    let (last, rest) = match self.steps.split_last() {
+      Some(split) => split,
+      None => return false,
+    };
+    if !last.0.matches(element) {
+      return false;
+    }
+
+    matches_ancestors(rest, last.1, ancestors)
+  }
+}
+
+/// Whether the steps of a selector before the element's own match its
+/// ancestors. `is_child` is whether the step after `steps` must be a direct
+/// child of the last of them
+fn matches_ancestors(
+  steps: &[(Compound, bool)],
+  is_child: bool,
+  ancestors: &[&Element],
+) -> bool {
+  let (last, rest) = match steps.split_last() {
+    Some(split) => split,
+    None => return true,
+  };
+
+  for (index, ancestor) in ancestors.iter().enumerate().rev() {
+    if last.0.matches(ancestor)
+      && matches_ancestors(rest, last.1, &ancestors[..index])
+    {
+      return true;
+    }
+    if is_child {
+      return false;
+    }
+  }
+
+  false
+}
+
+/// Parses a step of a selector like `p.note#first`. Returns `None` for
+/// anything else (attribute selectors, pseudo-classes, ...), so that rules
+/// using it never apply
+fn parse_compound(input: &str) -> Option<Compound> {
+  let mut compound = Compound::default();
+  let mut rest = input;
+
+  let tag_end = rest.find(['.', '#']).unwrap_or(rest.len());
+  match &rest[..tag_end] {
+    "" | "*" => {}
+    tag if is_identifier(tag) => compound.tag = Some(tag.to_lowercase()),
+    _ => return None,
+  }
+  rest = &rest[tag_end..];
+
+  while !rest.is_empty() {
+    let end = rest[1..].find(['.', '#']).map_or(rest.len(), |end| end + 1);
+    let name = &rest[1..end];
+    if !is_identifier(name) {
+      return None;
+    }
+
+    if rest.starts_with('.') {
+      compound.classes.push(name.to_string());
+    } else {
+      compound.id = Some(name.to_string());
+    }
+    rest = &rest[end..];
+  }
+
+  Some(compound)
+}
+
+fn is_identifier(name: &str) -> bool {
+  !name.is_empty()
+    && name
+      .chars()
+      .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+  selectors: Vec<Selector>,
+  /// Whether the rule was written by the reader rather than the publisher
+  is_user: bool,
+  /// Declarations along with whether they are `!important`
+  declarations: Vec<(Declaration, bool)>,
+}
+
+/// The rules of one or more stylesheets, in the order they were written
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stylesheet {
+  rules: Vec<Rule>,
+}
+
+impl Stylesheet {
+  pub fn parse(input: &str) -> Self {
+    let mut stylesheet = Self::default();
+    stylesheet.extend(input);
+    stylesheet
+  }
+
+  /// Adds the rules of another of the publisher's stylesheets, which take
+  /// precedence over the existing ones where they are equally specific
+  pub fn extend(&mut self, input: &str) {
+    self.extend_rules(input, false);
+  }
+
+  /// Adds the rules of a stylesheet written by the reader, which take
+  /// precedence over all of the publisher's rules
+  pub fn extend_user(&mut self, input: &str) {
+    self.extend_rules(input, true);
+  }
+
+  /// Adds all of the rules of another stylesheet
+  pub fn append(&mut self, other: &Self) {
+    self.rules.extend(other.rules.iter().cloned());
+  }
+
+  fn extend_rules(&mut self, input: &str, is_user: bool) {
+    let input = strip_comments(input);
+    let mut rest = input.as_str();
+
+    while let Some(open) = rest.find('{') {
+      let prelude = &rest[..open];
+
+      // At-rules are skipped along with any block they have
+      if let Some(at_rule) = prelude.rfind('@') {
+        if let Some(end) = prelude[at_rule..].find(';') {
+          rest = &rest[at_rule + end + 1..];
+          continue;
+        }
+        rest = &rest[open + matching_brace(&rest[open..])..];
+        continue;
+      }
+
+      let close = rest[open..].find('}').map_or(rest.len(), |end| open + end);
+      let selectors: Vec<Selector> = prelude
+        .trim()
+        .split(',')
+        .filter_map(Selector::parse)
+        .collect();
+      if !selectors.is_empty() {
+        let mut declarations = parse_declarations(&rest[open + 1..close]);
+        if !is_user {
+          declarations.retain(|(declaration, _)| !declaration.is_user_only());
+        }
+
+        self.rules.push(Rule {
+          selectors,
+          is_user,
+          declarations,
+        });
+      }
+
+      rest = &rest[(close + 1).min(rest.len())..];
+    }
+  }
+
+  /// The declarations that apply to an element, in the order they take
+  /// effect (so later ones override earlier ones). The element's `style`
+  /// attribute is included
+  pub fn declarations(
+    &self,
+    element: &Element,
+    ancestors: &[&Element],
+  ) -> Vec<Declaration> {
+    // (Is the reader's, is important, specificity, order) of each declaration
+    let mut found = Vec::new();
+
+    for (order, rule) in self.rules.iter().enumerate() {
+      let specificity = rule
+        .selectors
+        .iter()
+        .filter(|selector| selector.matches(element, ancestors))
+        .map(|selector| selector.specificity)
+        .max();
+
+      if let Some(specificity) = specificity {
+        for (declaration, important) in &rule.declarations {
+          found.push((
+            (rule.is_user, *important, specificity, order),
+            declaration.clone(),
+          ));
+        }
+      }
+    }
+
+    // Inline styles beat any selector
+    if let Some(style) = element.attr("style") {
+      let specificity = (usize::MAX, 0, 0);
+      for (declaration, important) in parse_declarations(style) {
+        if !declaration.is_user_only() {
+          found
+            .push(((false, important, specificity, usize::MAX), declaration));
+        }
+      }
+    }
+
+    found.sort_by_key(|(priority, _)| *priority);
+    found
+      .into_iter()
+      .map(|(_, declaration)| declaration)
+      .collect()
+  }
+}
+
+/// Parses the declarations within a rule (or a `style` attribute), e.g.
+/// `text-align: center; margin: 0 2em`
+fn parse_declarations(input: &str) -> Vec<(Declaration, bool)> {
+  let mut declarations = Vec::new();
+
+  for declaration in input.split(';') {
+    let (property, value) = match declaration.split_once(':') {
+      Some(split) => split,
+      None => continue,
+    };
+    let property = property.trim().to_lowercase();
+    let value = value.trim().to_lowercase();
+    let (value, important) = match value.strip_suffix("!important") {
+      Some(value) => (value.trim_end(), true),
+      None => (value.as_str(), false),
+    };
+
+    for parsed in parse_property(&property, value) {
+      declarations.push((parsed, important));
+    }
+  }
+
+  declarations
+}
+
+/// The declarations a property (with its value) sets, if it is supported
+fn parse_property(property: &str, value: &str) -> Vec<Declaration> {
+  let declaration = match property {
+    "text-align" => Declaration::TextAlign(match value {
+      "left" | "start" => TextAlign::Left,
+      "center" => TextAlign::Center,
+      "right" | "end" => TextAlign::Right,
+      "justify" => TextAlign::Justify,
+      _ => return Vec::new(),
+    }),
+    "font-style" => Declaration::Italic(matches!(value, "italic" | "oblique")),
+    "font-weight" => Declaration::Bold(match value {
+      "bold" | "bolder" => true,
+      "normal" | "lighter" => false,
+      weight => match weight.parse::<u16>() {
+        Ok(weight) => weight >= 600,
+        Err(_) => return Vec::new(),
+      },
+    }),
+    "font-variant" | "font-variant-caps" => {
+      Declaration::SmallCaps(value.contains("small-caps"))
+    }
+    "text-indent" => match parse_length(value) {
+      Some(length) => Declaration::TextIndent(length),
+      None => return Vec::new(),
+    },
+    "margin-left" | "margin-inline-start" => match parse_length(value) {
+      Some(length) => Declaration::MarginLeft(length),
+      None => return Vec::new(),
+    },
+    "margin-right" | "margin-inline-end" => match parse_length(value) {
+      Some(length) => Declaration::MarginRight(length),
+      None => return Vec::new(),
+    },
+    // Only the horizontal margins are used; the space between blocks is left
+    // to the reader's line spacing
+    "margin" => {
+      let lengths: Option<Vec<f32>> =
+        value.split_ascii_whitespace().map(parse_length).collect();
+      let (right, left) = match lengths.as_deref() {
+        Some([all]) => (*all, *all),
+        Some([_, sides] | [_, sides, _]) => (*sides, *sides),
+        Some([_, right, _, left]) => (*right, *left),
+        _ => return Vec::new(),
+      };
+      return vec![
+        Declaration::MarginLeft(left),
+        Declaration::MarginRight(right),
+      ];
+    }
+    "display" => Declaration::Hidden(value == "none"),
+    "font-family" => Declaration::FontFamily(
+      value
+        .split(',')
+        .map(unquote)
+        .filter(|family| !family.is_empty())
+        .map(str::to_string)
+        .collect(),
+    ),
+    "font-size" => match parse_font_size(value) {
+      Some(size) => Declaration::FontSize(size),
+      None => return Vec::new(),
+    },
+    "color" => match parse_color(value) {
+      Some(color) => Declaration::Color(color),
+      None => return Vec::new(),
+    },
+    "background-color" | "background" => match parse_color(value) {
+      Some(color) => Declaration::BackgroundColor(color),
+      None => return Vec::new(),
+    },
+    _ => return Vec::new(),
+  };
+
+  vec![declaration]
+}
+
+/// Parses a length into ems. `auto` is treated as no length
+fn parse_length(value: &str) -> Option<f32> {
+  if value == "auto" {
+    return Some(0.0);
+  }
+
+  let (number, unit) = split_unit(value)?;
+
+  Some(match unit {
+    "em" | "rem" => number,
+    "ex" | "ch" => number / 2.0,
+    "px" => number / 16.0,
+    "pt" => number / 12.0,
+    "%" => number * PERCENT_IN_EMS,
+    "" if number == 0.0 => 0.0,
+    _ => return None,
+  })
+}
+
+/// Parses a `font-size`, either a length or a keyword like `large`
+fn parse_font_size(value: &str) -> Option<FontSize> {
+  let scale = match value {
+    "xx-small" => 0.6,
+    "x-small" => 0.75,
+    "small" => 0.89,
+    "medium" => 1.0,
+    "large" => 1.2,
+    "x-large" => 1.5,
+    "xx-large" => 2.0,
+    "smaller" => 0.83,
+    "larger" => 1.2,
+    _ => {
+      let (number, unit) = split_unit(value)?;
+      let size = match unit {
+        "em" | "rem" => FontSize::Scale(number),
+        "%" => FontSize::Scale(number / 100.0),
+        "px" => FontSize::Points(number),
+        "pt" => FontSize::Points(number * 4.0 / 3.0),
+        _ => return None,
+      };
+      return Some(size).filter(|size| size.points(1.0) > 0.0);
+    }
+  };
+
+  Some(FontSize::Scale(scale))
+}
+
+/// Parses a color written as `#rgb`, `#rrggbb`, `rgb(r, g, b)` or one of the
+/// basic color names
+fn parse_color(value: &str) -> Option<Color32> {
+  if let Some(hex) = value.strip_prefix('#') {
+    let digits: Option<Vec<u8>> = hex
+      .chars()
+      .map(|c| c.to_digit(16).map(|digit| digit as u8))
+      .collect();
+    return match digits?.as_slice() {
+      [r, g, b] => Some(Color32::from_rgb(r * 17, g * 17, b * 17)),
+      [r1, r2, g1, g2, b1, b2] => {
+        Some(Color32::from_rgb(r1 * 16 + r2, g1 * 16 + g2, b1 * 16 + b2))
+      }
+      _ => None,
+    };
+  }
+
+  if let Some(arguments) = value
+    .strip_prefix("rgb(")
+    .or_else(|| value.strip_prefix("rgba("))
+    .and_then(|rest| rest.strip_suffix(')'))
+  {
+    let channels: Option<Vec<u8>> = arguments
+      .split([',', ' ', '/'])
+      .filter(|channel| !channel.is_empty())
+      .take(3)
+      .map(|channel| match channel.strip_suffix('%') {
+        Some(percent) => percent
+          .parse::<f32>()
+          .ok()
+          .map(|percent| (percent.clamp(0.0, 100.0) * 2.55).round() as u8),
+        None => channel
+          .parse::<f32>()
+          .ok()
+          .map(|channel| channel.clamp(0.0, 255.0).round() as u8),
+      })
+      .collect();
+    return match channels?.as_slice() {
+      [r, g, b] => Some(Color32::from_rgb(*r, *g, *b)),
+      _ => None,
+    };
+  }
+
+  Some(match value {
+    "black" => Color32::BLACK,
+    "white" => Color32::WHITE,
+    "gray" | "grey" => Color32::from_gray(128),
+    "silver" => Color32::from_gray(192),
+    "red" => Color32::from_rgb(255, 0, 0),
+    "maroon" => Color32::from_rgb(128, 0, 0),
+    "green" => Color32::from_rgb(0, 128, 0),
+    "blue" => Color32::from_rgb(0, 0, 255),
+    "navy" => Color32::from_rgb(0, 0, 128),
+    "brown" => Color32::from_rgb(165, 42, 42),
+    _ => return None,
+  })
+}
+
+/// A font declared by an `@font-face` rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct FontFace {
+  /// Name of the font's family, lowercased
+  pub family: String,
+  /// The first `url()` of the rule's `src`, as written
+  pub src: String,
+  pub bold: bool,
+  pub italic: bool,
+}
+
+/// Finds the fonts declared by the `@font-face` rules of a stylesheet. Rules
+/// without a family or a (non `data:`) url are skipped
+pub fn font_faces(input: &str) -> Vec<FontFace> {
+  let input = strip_comments(input);
+  // ASCII lowercasing keeps byte offsets the same
+  let lowercase = input.to_ascii_lowercase();
+  let mut faces = Vec::new();
+  let mut position = 0;
+
+  while let Some(start) = lowercase[position..].find("@font-face") {
+    let start = position + start;
+    let open = match input[start..].find('{') {
+      Some(open) => start + open,
+      None => break,
+    };
+    let close = input[open..]
+      .find('}')
+      .map_or(input.len(), |end| open + end);
+    position = (close + 1).min(input.len());
+
+    let mut face = FontFace {
+      family: String::new(),
+      src: String::new(),
+      bold: false,
+      italic: false,
+    };
+    for declaration in input[open + 1..close].split(';') {
+      let (property, value) = match declaration.split_once(':') {
+        Some(split) => split,
+        None => continue,
+      };
+      let value = value.trim();
+
+      match property.trim().to_lowercase().as_str() {
+        "font-family" => face.family = unquote(value).to_lowercase(),
+        "src" => {
+          face.src = value
+            .find("url(")
+            .and_then(|start| {
+              let url = &value[start + 4..];
+              url.find(')').map(|end| unquote(&url[..end]).to_string())
+            })
+            .unwrap_or_default();
+        }
+        "font-weight" => {
+          face.bold = matches!(
+            parse_property("font-weight", &value.to_lowercase())[..],
+            [Declaration::Bold(true)]
+          );
+        }
+        "font-style" => {
+          face.italic =
+            matches!(value.to_lowercase().as_str(), "italic" | "oblique");
+        }
+        _ => {}
+      }
+    }
+
+    if !face.family.is_empty()
+      && !face.src.is_empty()
+      && !face.src.starts_with("data:")
+    {
+      faces.push(face);
+    }
+  }
+
+  faces
+}
+
+/// Removes whitespace and the quotes around a value, if any
+fn unquote(value: &str) -> &str {
+  value.trim().trim_matches(|c| c == '"' || c == '\'').trim()
+}
+
+/// Splits a value like `1.5em` into its number & unit
+fn split_unit(value: &str) -> Option<(f32, &str)> {
+  let unit_start = value
+    .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+    .unwrap_or(value.len());
+  let number = value[..unit_start].parse().ok()?;
+
+  Some((number, &value[unit_start..]))
+}
+
+fn strip_comments(input: &str) -> String {
+  let mut output = String::with_capacity(input.len());
+  let mut rest = input;
+
+  while let Some(start) = rest.find("/*") {
+    output.push_str(&rest[..start]);
+    rest = rest[start + 2..]
+      .find("*/")
+      .map_or("", |end| &rest[start + end + 4..]);
+  }
+
+  output.push_str(rest);
+  output
+}
+
+/// Length of a `{ ... }` block (which may contain nested blocks) at the start
+/// of the input, including its braces
+fn matching_brace(input: &str) -> usize {
+  let mut depth = 0;
+
+  for (index, character) in input.char_indices() {
+    match character {
+      '{' => depth += 1,
+      '}' => {
+        depth -= 1;
+        if depth == 0 {
+          return index + 1;
+        }
+      }
+      _ => {}
+    }
+  }
+
+  input.len()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn element(name: &str, attributes: &[(&str, &str)]) -> Element {
+    let mut element = Element::new(name);
+    element.attributes = attributes
+      .iter()
+      .map(|(key, value)| (key.to_string(), value.to_string()))
+      .collect();
+    element
+  }
+
+  /// The alignment that ends up applying to an element
+  fn align(
+    stylesheet: &Stylesheet,
+    element: &Element,
+    ancestors: &[&Element],
+  ) -> Option<TextAlign> {
+    stylesheet
+      .declarations(element, ancestors)
+      .into_iter()
+      .filter_map(|declaration| match declaration {
+        Declaration::TextAlign(align) => Some(align),
+        _ => None,
+      })
+      .last()
+  }
+
+  #[test]
+  fn more_specific_rules_win_over_later_ones() {
+    let stylesheet = Stylesheet::parse(
+      "p#first { text-align: center }
+      p.note { text-align: right }
+      p { text-align: left }",
+    );
+    let body = element("body", &[]);
+
+    let first = element("p", &[("id", "first"), ("class", "note")]);
+    let note = element("p", &[("class", "other note")]);
+    let plain = element("p", &[]);
+
+    assert_eq!(
+      align(&stylesheet, &first, &[&body]),
+      Some(TextAlign::Center)
+    );
+    assert_eq!(align(&stylesheet, &note, &[&body]), Some(TextAlign::Right));
+    assert_eq!(align(&stylesheet, &plain, &[&body]), Some(TextAlign::Left));
+  }
+
+  #[test]
+  fn later_rules_win_when_equally_specific() {
+    let mut stylesheet = Stylesheet::parse(".a { text-align: left }");
+    stylesheet.extend(".b { text-align: right }");
+    let paragraph = element("p", &[("class", "b a")]);
+
+    assert_eq!(align(&stylesheet, &paragraph, &[]), Some(TextAlign::Right));
+  }
+
+  #[test]
+  fn important_and_inline_declarations_take_precedence() {
+    let stylesheet = Stylesheet::parse(
+      "p#id { text-align: left }
+      p { text-align: right !important }",
+    );
+    let inline = element("p", &[("style", "text-align: center")]);
+    let important = element("p", &[("id", "id")]);
+
+    assert_eq!(align(&stylesheet, &inline, &[]), Some(TextAlign::Right));
+    assert_eq!(align(&stylesheet, &important, &[]), Some(TextAlign::Right));
+
+    let stylesheet = Stylesheet::parse("p#id { text-align: left }");
+    let inline = element("p", &[("id", "id"), ("style", "text-align: right")]);
+    assert_eq!(align(&stylesheet, &inline, &[]), Some(TextAlign::Right));
+  }
+
+  #[test]
+  fn reader_rules_win_over_the_publishers() {
+    let mut stylesheet = Stylesheet::default();
+    stylesheet.extend_user("p { text-align: left }");
+    stylesheet.extend("body p#first.note { text-align: right !important }");
+    let body = element("body", &[]);
+    let paragraph = element("p", &[("id", "first"), ("class", "note")]);
+
+    assert_eq!(
+      align(&stylesheet, &paragraph, &[&body]),
+      Some(TextAlign::Left)
+    );
+  }
+
+  #[test]
+  fn only_the_reader_can_set_sizes_and_colors() {
+    let css = "p { font-size: 2em; color: red; background: #fff }";
+    let paragraph = element("p", &[("style", "color: blue")]);
+
+    let publisher = Stylesheet::parse(css);
+    assert_eq!(publisher.declarations(&paragraph, &[]), Vec::new());
+
+    let mut reader = Stylesheet::default();
+    reader.extend_user(css);
+    assert_eq!(
+      reader.declarations(&paragraph, &[]),
+      [
+        Declaration::FontSize(FontSize::Scale(2.0)),
+        Declaration::Color(Color32::from_rgb(255, 0, 0)),
+        Declaration::BackgroundColor(Color32::WHITE),
+      ]
+    );
+  }
+
+  #[test]
+  fn combinators_match_ancestors() {
+    let stylesheet = Stylesheet::parse(
+      "section p { text-align: right }
+      div > p { text-align: center }
+      p:first-child, p[lang] { text-align: justify }",
+    );
+    let section = element("section", &[]);
+    let div = element("div", &[]);
+    let blockquote = element("blockquote", &[]);
+    let paragraph = element("p", &[]);
+
+    assert_eq!(
+      align(&stylesheet, &paragraph, &[&section, &blockquote]),
+      Some(TextAlign::Right)
+    );
+    assert_eq!(
+      align(&stylesheet, &paragraph, &[&section, &div]),
+      Some(TextAlign::Center)
+    );
+    assert_eq!(align(&stylesheet, &paragraph, &[&div, &blockquote]), None);
+  }
+
+  #[test]
+  fn at_rules_and_comments_are_skipped() {
+    let stylesheet = Stylesheet::parse(
+      "@charset \"utf-8\";
+      @media print { p { text-align: right } }
+      /* p { text-align: center } */
+      p { text-align: left; margin: 0 2em 0 1em }",
+    );
+
+    assert_eq!(
+      stylesheet.declarations(&element("p", &[]), &[]),
+      [
+        Declaration::TextAlign(TextAlign::Left),
+        Declaration::MarginLeft(1.0),
+        Declaration::MarginRight(2.0),
+      ]
+    );
+  }
+
+  #[test]
+  fn values_are_parsed() {
+    assert_eq!(parse_length("24px"), Some(1.5));
+    assert_eq!(parse_length("10%"), Some(4.0));
+    assert_eq!(parse_length("3furlongs"), None);
+
+    assert_eq!(parse_font_size("150%"), Some(FontSize::Scale(1.5)));
+    assert_eq!(parse_font_size("12pt"), Some(FontSize::Points(16.0)));
+    assert_eq!(parse_font_size("large"), Some(FontSize::Scale(1.2)));
+    assert_eq!(parse_font_size("0em"), None);
+
+    assert_eq!(parse_color("#f80"), Some(Color32::from_rgb(255, 136, 0)));
+    assert_eq!(parse_color("#102030"), Some(Color32::from_rgb(16, 32, 48)));
+    assert_eq!(
+      parse_color("rgb(100%, 0, 50.2)"),
+      Some(Color32::from_rgb(255, 0, 50))
+    );
+    assert_eq!(parse_color("#12"), None);
+    assert_eq!(parse_color("chartreuse"), None);
+  }
+
+  #[test]
+  fn font_faces_are_found() {
+    let faces = font_faces(
+      "@font-face { font-family: \"Body Serif\"; font-weight: 700;
+        src: url('../fonts/serif-bold.otf') format('opentype') }
+      @font-face { font-family: Inline; src: url(data:font/ttf;base64,AA) }
+      @FONT-FACE { font-style: italic; src: url(fonts/italic.ttf) }
+      p { font-family: 'Body Serif' }",
+    );
+
+    assert_eq!(
+      faces,
+      [FontFace {
+        family: "body serif".to_string(),
+        src: "../fonts/serif-bold.otf".to_string(),
+        bold: true,
+        italic: false,
+      }]
+    );
+  }
+}
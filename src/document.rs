@@ -0,0 +1,1039 @@
+//! Structured representation of a chapter (spine item) of a book.
+//!
+//! A chapter's XHTML is parsed into a tree by [`crate::xhtml`], which is then
+//! flattened into a list of blocks (paragraphs, headings, list items, ...),
+//! each of which holds runs of inline text.
+//!
+//! The publisher's stylesheets are applied as the tree is walked, but kept
+//! apart from the formatting given by the markup ([`BlockCss`] & [`SpanCss`]),
+//! so that the reader can choose to ignore them. The reader's own stylesheets
+//! are applied twice: over the publisher's, and on their own, for when the
+//! publisher's are ignored. Blocks hidden by CSS are still part of the
+//! document, so that positions within it don't depend on which styles are used
+
+use crate::{
+  css::{Declaration, FontSize, Stylesheet, TextAlign},
+  xhtml::{self, Element, Node},
+};
+use egui::Color32;
+
+/// Formatting applied to a run of inline text. Styles can be freely combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SpanStyle {
+  pub bold: bool,
+  pub italic: bool,
+  pub underline: bool,
+  pub strikethrough: bool,
+  pub small_caps: bool,
+  pub superscript: bool,
+  pub subscript: bool,
+  pub code: bool,
+}
+
+/// Formatting of a run of text set by stylesheets, which overrides that of
+/// its [`SpanStyle`] (and the reader's font & colors) where set
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SpanCss {
+  pub bold: Option<bool>,
+  pub italic: Option<bool>,
+  pub small_caps: Option<bool>,
+  /// Font families in order of preference, of which the first that is
+  /// available is used
+  pub font_family: Option<Vec<String>>,
+  pub color: Option<Color32>,
+}
+
+/// Formatting of a block set by stylesheets
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BlockCss {
+  pub align: Option<TextAlign>,
+  /// Indent of the first line, in ems
+  pub indent: f32,
+  /// Space on either side of the block (added up from the elements it is
+  /// in), in ems
+  pub margin_left: f32,
+  pub margin_right: f32,
+  /// Whether the block is inside of an element with `display: none`
+  pub hidden: bool,
+  /// Size of the block's text in place of the reader's font size. Headings
+  /// are still scaled up from it
+  pub font_size: Option<FontSize>,
+}
+
+/// A run of text that shares the same formatting
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+  pub text: String,
+  pub style: SpanStyle,
+  /// Formatting from the publisher's stylesheets, with the reader's over them
+  pub css: SpanCss,
+  /// Formatting from the reader's stylesheets alone
+  pub user_css: SpanCss,
+  /// `href` of the link this text is part of, if any
+  pub link: Option<String>,
+  /// Whether the link is a reference to a footnote / endnote
+  pub is_noteref: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockKind {
+  Paragraph,
+  /// Level of the heading (1 through 6)
+  Heading(u8),
+  /// `marker` is only present for the first block of a list item
+  ListItem {
+    depth: usize,
+    marker: Option<String>,
+  },
+  Quote,
+  Preformatted,
+  Image {
+    src: String,
+    alt: String,
+  },
+  Rule,
+}
+
+/// A single block level piece of a document, such as a paragraph
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+  pub kind: BlockKind,
+  pub spans: Vec<Span>,
+  /// `id`s of the elements that begin within this block, used as link targets
+  pub anchors: Vec<String>,
+  /// `id` of the footnote / endnote this block is part of, if any
+  pub note: Option<String>,
+  /// Whether the block is part of a footnote (as opposed to an endnote),
+  /// which can be hidden from the main text
+  pub is_footnote: bool,
+  /// Path of the innermost block level element the block is in, written like
+  /// the element steps of an EPUB CFI (`/2/4/6` is the 3rd child element of
+  /// the 2nd child element of the 1st element of the document)
+  pub path: String,
+  /// Number of characters before this block in the blocks that are directly
+  /// in the element at `path`. Text of block level elements nested within
+  /// that element isn't counted, as their blocks have paths of their own.
+  /// Blocks without text (images, rules) count as a single character
+  pub path_offset: usize,
+  /// Formatting from the publisher's stylesheets, with the reader's over them
+  pub css: BlockCss,
+  /// Formatting from the reader's stylesheets alone
+  pub user_css: BlockCss,
+  /// Language of the block's text (see [`language_tag`]), from the `xml:lang`
+  /// or `lang` of the elements it is in
+  pub language: Option<String>,
+}
+
+impl Block {
+  /// The plain text of all of the block's spans
+  pub fn text(&self) -> String {
+    self.spans.iter().map(|span| span.text.as_str()).collect()
+  }
+
+  /// Length of the block's text in characters
+  pub fn char_count(&self) -> usize {
+    self
+      .spans
+      .iter()
+      .map(|span| span.text.chars().count())
+      .sum()
+  }
+
+  /// Finds the span containing the character at the given index
+  pub fn span_at(&self, char_index: usize) -> Option<&Span> {
+    let mut offset = 0;
+
+    for span in &self.spans {
+      offset += span.text.chars().count();
+      if char_index < offset {
+        return Some(span);
+      }
+    }
+
+    None
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Document {
+  /// Contents of the `<title>` element, if any
+  pub title: Option<String>,
+  pub blocks: Vec<Block>,
+  /// Page color set for the `<html>` or `<body>` element by the reader's
+  /// stylesheets
+  pub background: Option<Color32>,
+}
+
+impl Document {
+  /// Parses the XHTML of a single spine item, without any stylesheets it
+  /// links to
+  pub fn from_xhtml(input: &str) -> Self {
+    Self::from_xhtml_styled(input, |_| None, &Stylesheet::default())
+  }
+
+  /// Parses the XHTML of a single spine item, applying its stylesheets and
+  /// then the reader's (`user_stylesheet`). `load_stylesheet` gets the
+  /// contents of a linked stylesheet from its `href`
+  pub fn from_xhtml_styled<F>(
+    input: &str,
+    mut load_stylesheet: F,
+    user_stylesheet: &Stylesheet,
+  ) -> Self
+  where
+    F: FnMut(&str) -> Option<String>,
+  {
+    let root = xhtml::parse(input);
+
+    // Linked & embedded stylesheets, in the order they appear
+    let mut stylesheet = Stylesheet::default();
+    if let Some(head) = root.find("head") {
+      for element in head.child_elements() {
+        match element.name.as_str() {
+          "link" => {
+            let is_stylesheet = element
+              .attr("rel")
+              .unwrap_or_default()
+              .split_ascii_whitespace()
+              .any(|rel| rel.eq_ignore_ascii_case("stylesheet"));
+
+            if let Some(css) = element
+              .attr("href")
+              .filter(|_| is_stylesheet)
+              .and_then(&mut load_stylesheet)
+            {
+              stylesheet.extend(&css);
+            }
+          }
+          "style" => stylesheet.extend(&element.text()),
+          _ => {}
+        }
+      }
+    }
+    stylesheet.append(user_stylesheet);
+
+    let title = root
+      .find("title")
+      .map(|title| collapse_whitespace(&title.text()))
+      .filter(|title| !title.is_empty());
+
+    // The whole document is walked (rather than just the `<body>`) so that
+    // element paths start from the root. The `<head>` is skipped regardless
+    let mut builder = Builder {
+      stylesheet: Some(&stylesheet),
+      user_stylesheet: Some(user_stylesheet),
+      ..Default::default()
+    };
+    builder.containers.push((String::new(), 0));
+    builder.walk(&root);
+    builder.flush();
+
+    // Anchors after the last block still need to be reachable
+    let leftover_anchors = std::mem::take(&mut builder.pending_anchors);
+    if let Some(last) = builder.blocks.last_mut() {
+      last.anchors.extend(leftover_anchors);
+    }
+
+    Self {
+      title,
+      blocks: builder.blocks,
+      background: builder.background,
+    }
+  }
+
+  /// The blocks making up the note with the given `id`. If the `id` is not
+  /// that of a note, the block containing it is used instead
+  pub fn note_blocks(&self, id: &str) -> Vec<&Block> {
+    let blocks: Vec<&Block> = self
+      .blocks
+      .iter()
+      .filter(|block| block.note.as_deref() == Some(id))
+      .collect();
+
+    if blocks.is_empty() {
+      self
+        .anchor_block(id)
+        .map(|index| vec![&self.blocks[index]])
+        .unwrap_or_default()
+    } else {
+      blocks
+    }
+  }
+
+  /// Number of characters of text before a character within a block
+  pub fn char_offset(&self, block: usize, char_index: usize) -> usize {
+    self
+      .blocks
+      .iter()
+      .take(block)
+      .map(Block::char_count)
+      .sum::<usize>()
+      + char_index
+  }
+
+  /// The (block, character within it) a number of characters into the text.
+  /// Offsets past the end are clamped to the end of the last block
+  pub fn position_at(&self, mut offset: usize) -> (usize, usize) {
+    for (index, block) in self.blocks.iter().enumerate() {
+      if offset < block.char_count() {
+        return (index, offset);
+      }
+      offset -= block.char_count();
+    }
+
+    let last = self.blocks.len().saturating_sub(1);
+    (last, self.blocks.get(last).map_or(0, Block::char_count))
+  }
+
+  /// Finds the index of the block containing the element with the given `id`
+  pub fn anchor_block(&self, anchor: &str) -> Option<usize> {
+    self
+      .blocks
+      .iter()
+      .position(|block| block.anchors.iter().any(|a| a == anchor))
+  }
+}
+
+/// Elements whose contents are never displayed
+const HIDDEN_ELEMENTS: [&str; 5] =
+  ["head", "script", "style", "title", "template"];
+
+/// Elements that start a new block when encountered
+const BLOCK_ELEMENTS: [&str; 26] = [
+  "address",
+  "article",
+  "aside",
+  "blockquote",
+  "caption",
+  "dd",
+  "div",
+  "dl",
+  "dt",
+  "figcaption",
+  "figure",
+  "footer",
+  "h1",
+  "h2",
+  "h3",
+  "h4",
+  "h5",
+  "h6",
+  "header",
+  "li",
+  "nav",
+  "ol",
+  "p",
+  "pre",
+  "section",
+  "ul",
+];
+
+/// Keeps track of where in the tree the walk currently is, and collects the
+/// blocks produced so far
+#[derive(Default)]
+struct Builder<'a> {
+  /// The publisher's stylesheets followed by the reader's
+  stylesheet: Option<&'a Stylesheet>,
+  user_stylesheet: Option<&'a Stylesheet>,
+  /// Elements from the root to the parent of the one being walked, which
+  /// selectors are matched against
+  ancestors: Vec<&'a Element>,
+  blocks: Vec<Block>,
+  spans: Vec<Span>,
+  style: SpanStyle,
+  span_css: SpanCss,
+  block_css: BlockCss,
+  user_span_css: SpanCss,
+  user_block_css: BlockCss,
+  background: Option<Color32>,
+  language: Option<String>,
+  link: Option<String>,
+  is_noteref: bool,
+  /// (`id`, is a footnote) of the note currently being walked through
+  note: Option<(Option<String>, bool)>,
+  heading: Option<u8>,
+  quote_depth: usize,
+  preformatted_depth: usize,
+  /// (Is ordered, number of items so far)
+  lists: Vec<(bool, usize)>,
+  in_list_item: bool,
+  pending_marker: Option<String>,
+  /// `id`s seen since the last block was created
+  pending_anchors: Vec<String>,
+  /// Element steps from the root to the element currently being walked
+  path: Vec<usize>,
+  /// (Path, number of characters so far) of the block level elements
+  /// currently being walked through
+  containers: Vec<(String, usize)>,
+}
+
+impl<'a> Builder<'a> {
+  fn walk(&mut self, element: &'a Element) {
+    let mut element_count = 0;
+
+    for child in &element.children {
+      match child {
+        Node::Text(text) => self.push_text(text),
+        Node::Element(child) => {
+          // Elements have even steps in a CFI, starting at 2
+          element_count += 1;
+          self.path.push(element_count * 2);
+          self.walk_element(child);
+          self.path.pop();
+        }
+      }
+    }
+  }
+
+  fn walk_element(&mut self, element: &'a Element) {
+    let name = element.name.as_str();
+
+    if HIDDEN_ELEMENTS.contains(&name) {
+      return;
+    }
+
+    let is_block = BLOCK_ELEMENTS.contains(&name);
+    let is_void = matches!(name, "br" | "hr" | "img" | "image");
+    if is_block || (is_void && name != "br") {
+      self.flush();
+    }
+    if is_block {
+      self.containers.push((path_string(&self.path), 0));
+    }
+
+    // Footnotes & endnotes
+    let previous_note = self.note.clone();
+    let is_footnote = has_semantic(element, "footnote");
+    let is_note = is_footnote
+      || ["endnote", "rearnote", "note"]
+        .iter()
+        .any(|kind| has_semantic(element, kind));
+    if is_note && !is_void {
+      self.flush();
+      self.note = Some((element.attr("id").map(str::to_string), is_footnote));
+    }
+
+    if let Some(id) = element.attr("id") {
+      self.pending_anchors.push(id.to_string());
+    }
+
+    // Styles of blocks come from the elements they are in
+    let declarations = self
+      .stylesheet
+      .map(|stylesheet| stylesheet.declarations(element, &self.ancestors))
+      .unwrap_or_default();
+    let user_declarations = self
+      .user_stylesheet
+      .map(|stylesheet| stylesheet.declarations(element, &self.ancestors))
+      .unwrap_or_default();
+    let previous_block_css = self.block_css;
+    let previous_user_block_css = self.user_block_css;
+    let has_margins = is_block || is_void;
+    apply_block_declarations(&mut self.block_css, &declarations, has_margins);
+    apply_block_declarations(
+      &mut self.user_block_css,
+      &user_declarations,
+      has_margins,
+    );
+
+    if matches!(name, "html" | "body") {
+      for declaration in &user_declarations {
+        if let Declaration::BackgroundColor(color) = declaration {
+          self.background = Some(*color);
+        }
+      }
+    }
+
+    match name {
+      "br" => {
+        self.push_raw("\n");
+        self.block_css = previous_block_css;
+        self.user_block_css = previous_user_block_css;
+        return;
+      }
+      "hr" => {
+        self.push_block(BlockKind::Rule, Vec::new());
+        self.block_css = previous_block_css;
+        self.user_block_css = previous_user_block_css;
+        return;
+      }
+      "img" | "image" => {
+        if let Some(src) = element.attr("src").or_else(|| element.attr("href"))
+        {
+          self.push_block(
+            BlockKind::Image {
+              src: src.to_string(),
+              alt: element.attr("alt").unwrap_or_default().to_string(),
+            },
+            Vec::new(),
+          );
+        }
+        self.block_css = previous_block_css;
+        self.user_block_css = previous_user_block_css;
+        return;
+      }
+      _ => {}
+    }
+
+    // Blocks are in the language of the element they end in, so that of an
+    // inline element only applies to blocks within it. An empty `lang` means
+    // the language is unknown
+    let previous_language = self.language.clone();
+    if let Some(language) = element.attr("lang") {
+      self.language = language_tag(language);
+    }
+
+    // Save the state that this element may modify so it can be restored
+    let previous_style = self.style;
+    let previous_span_css = self.span_css.clone();
+    let previous_user_span_css = self.user_span_css.clone();
+    let previous_link = self.link.clone();
+    let previous_is_noteref = self.is_noteref;
+    let previous_heading = self.heading;
+    let previous_in_list_item = self.in_list_item;
+
+    // Small caps are commonly applied through classes
+    if element
+      .attr("class")
+      .unwrap_or_default()
+      .split_ascii_whitespace()
+      .any(|class| matches!(class, "smallcaps" | "small-caps" | "sc"))
+    {
+      self.style.small_caps = true;
+    }
+
+    match name {
+      "a" => {
+        if let Some(href) = element.attr("href") {
+          self.link = Some(href.to_string());
+          self.is_noteref = has_semantic(element, "noteref");
+        }
+      }
+      "b" | "strong" => self.style.bold = true,
+      "i" | "em" | "cite" | "dfn" | "var" => self.style.italic = true,
+      "u" | "ins" => self.style.underline = true,
+      "s" | "strike" | "del" => self.style.strikethrough = true,
+      "sup" => self.style.superscript = true,
+      "sub" => self.style.subscript = true,
+      "code" | "kbd" | "samp" | "tt" => self.style.code = true,
+      "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+        self.heading = name[1..].parse().ok();
+        // Headings are bold unless their own styles say otherwise
+        self.span_css.bold = None;
+        self.user_span_css.bold = None;
+      }
+      "blockquote" => self.quote_depth += 1,
+      "pre" => self.preformatted_depth += 1,
+      "ul" => self.lists.push((false, 0)),
+      "ol" => {
+        let start = element
+          .attr("start")
+          .and_then(|start| start.parse::<usize>().ok())
+          .unwrap_or(1);
+        self.lists.push((true, start.saturating_sub(1)));
+      }
+      "li" => {
+        self.in_list_item = true;
+        self.pending_marker = Some(match self.lists.last_mut() {
+          Some((true, count)) => {
+            *count += 1;
+            format!("{}.", count)
+          }
+          _ => "\u{2022}".to_string(),
+        });
+      }
+      _ => {}
+    }
+
+    // Formatting from the markup takes precedence over that inherited from
+    // styles, but not over the element's own styles
+    for span_css in [&mut self.span_css, &mut self.user_span_css] {
+      if self.style.bold != previous_style.bold {
+        span_css.bold = None;
+      }
+      if self.style.italic != previous_style.italic {
+        span_css.italic = None;
+      }
+      if self.style.small_caps != previous_style.small_caps {
+        span_css.small_caps = None;
+      }
+    }
+    apply_span_declarations(&mut self.span_css, &declarations);
+    apply_span_declarations(&mut self.user_span_css, &user_declarations);
+
+    self.ancestors.push(element);
+    self.walk(element);
+    self.ancestors.pop();
+
+    if is_block {
+      self.flush();
+      self.containers.pop();
+    }
+
+    match name {
+      "blockquote" => self.quote_depth -= 1,
+      "pre" => self.preformatted_depth -= 1,
+      "ul" | "ol" => {
+        self.lists.pop();
+      }
+      "li" => self.pending_marker = None,
+      _ => {}
+    }
+
+    self.style = previous_style;
+    self.link = previous_link;
+    self.is_noteref = previous_is_noteref;
+    if self.note != previous_note {
+      self.flush();
+      self.note = previous_note;
+    }
+    self.heading = previous_heading;
+    self.in_list_item = previous_in_list_item;
+    self.language = previous_language;
+    self.span_css = previous_span_css;
+    self.block_css = previous_block_css;
+    self.user_span_css = previous_user_span_css;
+    self.user_block_css = previous_user_block_css;
+  }
+
+  /// Adds text as it appears in the source, collapsing whitespace unless
+  /// inside of a `<pre>` element
+  fn push_text(&mut self, text: &str) {
+    if self.preformatted_depth > 0 {
+      self.push_raw(text);
+      return;
+    }
+
+    let at_line_start = match self.spans.last() {
+      Some(span) => span.text.ends_with([' ', '\n']),
+      None => true,
+    };
+
+    let words = collapse_whitespace(text);
+    let mut collapsed = String::new();
+
+    if text.starts_with(|c: char| c.is_ascii_whitespace()) && !at_line_start {
+      collapsed.push(' ');
+    }
+    collapsed.push_str(&words);
+    if text.ends_with(|c: char| c.is_ascii_whitespace()) && !words.is_empty() {
+      collapsed.push(' ');
+    }
+
+    self.push_raw(&collapsed);
+  }
+
+  /// Adds text without any whitespace processing
+  fn push_raw(&mut self, text: &str) {
+    if text.is_empty() {
+      return;
+    }
+
+    match self.spans.last_mut() {
+      Some(span)
+        if span.style == self.style
+          && span.css == self.span_css
+          && span.user_css == self.user_span_css
+          && span.link == self.link
+          && span.is_noteref == self.is_noteref =>
+      {
+        span.text.push_str(text);
+      }
+      _ => self.spans.push(Span {
+        text: text.to_string(),
+        style: self.style,
+        css: self.span_css.clone(),
+        user_css: self.user_span_css.clone(),
+        link: self.link.clone(),
+        is_noteref: self.is_noteref,
+      }),
+    }
+  }
+
+  /// Turns the spans collected so far into a block
+  fn flush(&mut self) {
+    let mut spans = std::mem::take(&mut self.spans);
+
+    if self.preformatted_depth == 0 {
+      // Trim whitespace from either end of the block
+      if let Some(first) = spans.first_mut() {
+        first.text = first
+          .text
+          .trim_start_matches(|c: char| c.is_ascii_whitespace())
+          .to_string();
+      }
+      if let Some(last) = spans.last_mut() {
+        last.text = last
+          .text
+          .trim_end_matches(|c: char| c.is_ascii_whitespace())
+          .to_string();
+      }
+      spans.retain(|span| !span.text.is_empty());
+    }
+
+    if spans.iter().all(|span| span.text.trim().is_empty()) {
+      return;
+    }
+
+    let kind = if let Some(level) = self.heading {
+      BlockKind::Heading(level)
+    } else if self.preformatted_depth > 0 {
+      BlockKind::Preformatted
+    } else if self.in_list_item {
+      BlockKind::ListItem {
+        depth: self.lists.len().saturating_sub(1),
+        marker: self.pending_marker.take(),
+      }
+    } else if self.quote_depth > 0 {
+      BlockKind::Quote
+    } else {
+      BlockKind::Paragraph
+    };
+
+    self.push_block(kind, spans);
+  }
+
+  fn push_block(&mut self, kind: BlockKind, spans: Vec<Span>) {
+    let (note, is_footnote) = match &self.note {
+      Some((id, is_footnote)) => (id.clone(), *is_footnote),
+      None => (None, false),
+    };
+
+    let length = spans
+      .iter()
+      .map(|span| span.text.chars().count())
+      .sum::<usize>()
+      .max(1);
+    let (path, path_offset) = match self.containers.last_mut() {
+      Some((path, count)) => {
+        *count += length;
+        (path.clone(), *count - length)
+      }
+      None => (String::new(), 0),
+    };
+
+    self.blocks.push(Block {
+      kind,
+      spans,
+      anchors: std::mem::take(&mut self.pending_anchors),
+      note,
+      is_footnote,
+      path,
+      path_offset,
+      css: self.block_css,
+      user_css: self.user_block_css,
+      language: self.language.clone(),
+    });
+  }
+}
+
+/// Normalises a language tag (from `xml:lang`, `lang` or `dc:language`) to
+/// lowercase subtags separated by `-`, e.g. `zh-hant-tw`. Returns `None` for
+/// an empty tag
+pub fn language_tag(tag: &str) -> Option<String> {
+  let tag = tag.trim().replace('_', "-").to_lowercase();
+  (!tag.is_empty()).then_some(tag)
+}
+
+/// Applies the declarations for an element to the styles of the blocks within
+/// it. Margins are only used from block level elements (and images)
+fn apply_block_declarations(
+  css: &mut BlockCss,
+  declarations: &[Declaration],
+  has_margins: bool,
+) {
+  // Only the last `display` declared for the element counts, so that a later
+  // (or the reader's) stylesheet can show what an earlier one hid
+  let mut hidden = false;
+
+  for declaration in declarations {
+    match *declaration {
+      Declaration::TextAlign(align) => css.align = Some(align),
+      Declaration::TextIndent(indent) => css.indent = indent,
+      Declaration::MarginLeft(margin) if has_margins => {
+        css.margin_left += margin;
+      }
+      Declaration::MarginRight(margin) if has_margins => {
+        css.margin_right += margin;
+      }
+      Declaration::Hidden(is_hidden) => hidden = is_hidden,
+      Declaration::FontSize(size) => {
+        css.font_size = Some(FontSize::nest(css.font_size, size));
+      }
+      _ => {}
+    }
+  }
+
+  // Nothing within a hidden element can be shown
+  css.hidden |= hidden;
+}
+
+/// Applies the declarations for an element to the styles of the text within
+/// it
+fn apply_span_declarations(css: &mut SpanCss, declarations: &[Declaration]) {
+  for declaration in declarations {
+    match declaration {
+      Declaration::Bold(bold) => css.bold = Some(*bold),
+      Declaration::Italic(italic) => css.italic = Some(*italic),
+      Declaration::SmallCaps(small_caps) => css.small_caps = Some(*small_caps),
+      Declaration::FontFamily(families) => {
+        css.font_family = Some(families.clone());
+      }
+      Declaration::Color(color) => css.color = Some(*color),
+      _ => {}
+    }
+  }
+}
+
+/// Writes element steps as a path (e.g. `/2/4`)
+fn path_string(steps: &[usize]) -> String {
+  steps.iter().map(|step| format!("/{}", step)).collect()
+}
+
+/// Whether an element has the given semantic type, through either
+/// `epub:type` or the equivalent DPUB-ARIA `role` (`doc-...`)
+fn has_semantic(element: &Element, semantic: &str) -> bool {
+  element
+    .attr("epub:type")
+    .unwrap_or_default()
+    .split_ascii_whitespace()
+    .any(|t| t == semantic)
+    || element
+      .attr("role")
+      .unwrap_or_default()
+      .split_ascii_whitespace()
+      .any(|role| role.strip_prefix("doc-") == Some(semantic))
+}
+
+/// Replaces all runs of whitespace with single spaces, and trims the ends.
+///
+/// Only ASCII whitespace is considered, so non-breaking spaces are kept
+fn collapse_whitespace(text: &str) -> String {
+  text.split_ascii_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn texts(document: &Document) -> Vec<String> {
+    document.blocks.iter().map(Block::text).collect()
+  }
+
+  fn kinds(document: &Document) -> Vec<BlockKind> {
+    document
+      .blocks
+      .iter()
+      .map(|block| block.kind.clone())
+      .collect()
+  }
+
+  #[test]
+  fn elements_are_flattened_into_blocks() {
+    let document = Document::from_xhtml(
+      "<html><head><title> The  Title </title><style>p {}</style></head>
+      <body><div>Before <p>Inside</p> after</div><hr/>
+      <img src=\"a.png\" alt=\"A\"/><script>ignored()</script>
+      <blockquote><p>Quoted</p></blockquote></body></html>",
+    );
+
+    assert_eq!(document.title.as_deref(), Some("The Title"));
+    assert_eq!(
+      texts(&document),
+      ["Before", "Inside", "after", "", "", "Quoted"]
+    );
+    assert_eq!(
+      kinds(&document),
+      [
+        BlockKind::Paragraph,
+        BlockKind::Paragraph,
+        BlockKind::Paragraph,
+        BlockKind::Rule,
+        BlockKind::Image {
+          src: "a.png".to_string(),
+          alt: "A".to_string(),
+        },
+        BlockKind::Quote,
+      ]
+    );
+  }
+
+  #[test]
+  fn headings_keep_their_level_and_formatting() {
+    let document = Document::from_xhtml(
+      "<body><h1>Title</h1><h3>Sub <b>bold</b> <i>and</i></h3></body>",
+    );
+
+    assert_eq!(
+      kinds(&document),
+      [BlockKind::Heading(1), BlockKind::Heading(3)]
+    );
+    let spans: Vec<(&str, bool, bool)> = document.blocks[1]
+      .spans
+      .iter()
+      .map(|span| (span.text.as_str(), span.style.bold, span.style.italic))
+      .collect();
+    assert_eq!(
+      spans,
+      [
+        ("Sub ", false, false),
+        ("bold", true, false),
+        (" ", false, false),
+        ("and", false, true)
+      ]
+    );
+  }
+
+  #[test]
+  fn list_items_are_numbered_and_nested() {
+    let document = Document::from_xhtml(
+      "<body><ol start=\"3\"><li>Three</li><li><p>Four</p><p>More</p>
+      <ul><li>Nested</li></ul></li></ol><ul><li>Bullet</li></ul></body>",
+    );
+    let item = |depth, marker: Option<&str>| BlockKind::ListItem {
+      depth,
+      marker: marker.map(str::to_string),
+    };
+
+    assert_eq!(
+      texts(&document),
+      ["Three", "Four", "More", "Nested", "Bullet"]
+    );
+    assert_eq!(
+      kinds(&document),
+      [
+        item(0, Some("3.")),
+        item(0, Some("4.")),
+        item(0, None),
+        item(1, Some("\u{2022}")),
+        item(0, Some("\u{2022}")),
+      ]
+    );
+  }
+
+  #[test]
+  fn whitespace_is_collapsed_outside_of_pre() {
+    let document = Document::from_xhtml(
+      "<body><p>  Some\n   spaced\ttext <i> italic </i> end </p>
+      <p>a&#160; b<br/>next line</p><pre>  keep\n  this </pre></body>",
+    );
+
+    assert_eq!(
+      texts(&document),
+      [
+        "Some spaced text italic end",
+        "a\u{a0} b\nnext line",
+        "  keep\n  this "
+      ]
+    );
+    assert_eq!(document.blocks[2].kind, BlockKind::Preformatted);
+  }
+
+  #[test]
+  fn anchors_belong_to_the_block_they_begin_in() {
+    let document = Document::from_xhtml(
+      "<body><section id=\"chapter\"><h1 id=\"title\">Title</h1>
+      <p>Text with <a id=\"inline\">an anchor</a></p></section>
+      <div id=\"end\"></div></body>",
+    );
+
+    assert_eq!(document.blocks[0].anchors, ["chapter", "title"]);
+    assert_eq!(document.blocks[1].anchors, ["inline", "end"]);
+    assert_eq!(document.anchor_block("end"), Some(1));
+    assert_eq!(document.anchor_block("missing"), None);
+  }
+
+  #[test]
+  fn blocks_know_where_they_are_in_the_element_tree() {
+    let document = Document::from_xhtml(
+      "<html><head><title>T</title></head><body><p>One</p>\
+      <div>Before<p>Inside</p>After<img src=\"a.png\"/>Tail</div>\
+      </body></html>",
+    );
+    let places: Vec<(&str, usize)> = document
+      .blocks
+      .iter()
+      .map(|block| (block.path.as_str(), block.path_offset))
+      .collect();
+
+    assert_eq!(
+      texts(&document),
+      ["One", "Before", "Inside", "After", "", "Tail"]
+    );
+    assert_eq!(
+      places,
+      [
+        ("/2/4/2", 0),
+        ("/2/4/4", 0),
+        ("/2/4/4/2", 0),
+        ("/2/4/4", 6),
+        ("/2/4/4", 11),
+        ("/2/4/4", 12),
+      ]
+    );
+  }
+
+  #[test]
+  fn note_references_are_kept_in_their_own_spans() {
+    let document = Document::from_xhtml(
+      "<body><p><a href=\"#n1\">See note</a><a epub:type=\"noteref\" \
+      href=\"#n1\">1</a></p></body>",
+    );
+    let spans: Vec<(&str, bool)> = document.blocks[0]
+      .spans
+      .iter()
+      .map(|span| (span.text.as_str(), span.is_noteref))
+      .collect();
+
+    assert_eq!(spans, [("See note", false), ("1", true)]);
+  }
+
+  fn styled(publisher_css: &str, user_css: &str, body: &str) -> Document {
+    let mut user_stylesheet = Stylesheet::default();
+    user_stylesheet.extend_user(user_css);
+
+    Document::from_xhtml_styled(
+      &format!(
+        "<html><head><style>{}</style></head><body>{}</body></html>",
+        publisher_css, body
+      ),
+      |_| None,
+      &user_stylesheet,
+    )
+  }
+
+  #[test]
+  fn reader_stylesheet_can_show_hidden_elements() {
+    let document = styled(
+      ".aside { display: none }",
+      ".aside { display: block }",
+      "<p>Shown</p><p class=\"aside\">Unhidden</p>",
+    );
+
+    assert_eq!(document.blocks.len(), 2);
+    assert!(!document.blocks[1].css.hidden);
+    assert!(!document.blocks[1].user_css.hidden);
+  }
+
+  #[test]
+  fn last_display_declaration_wins() {
+    let document = styled(
+      "p { display: block } .aside { display: none }",
+      "",
+      "<p>Shown</p><p class=\"aside\">Hidden</p>",
+    );
+
+    assert!(!document.blocks[0].css.hidden);
+    assert!(document.blocks[1].css.hidden);
+  }
+
+  #[test]
+  fn children_of_hidden_elements_stay_hidden() {
+    let document = styled(
+      ".aside { display: none }",
+      "p { display: block }",
+      "<div class=\"aside\"><p>Hidden</p></div>",
+    );
+
+    assert!(document.blocks[0].css.hidden);
+  }
+}
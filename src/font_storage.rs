@@ -0,0 +1,229 @@
+//! Storage for the fonts added by the user on the web.
+//!
+//! Fonts are kept in the browser's IndexedDB (under their name), apart from
+//! the rest of the app's state, which is saved to local storage: that only
+//! has room for a few megabytes, less than many fonts (CJK ones especially)
+//! take up on their own. Only the names of the fonts are saved with the app's
+//! state.
+//!
+//! IndexedDB can only be used asynchronously, so fonts are read in the
+//! background at startup, and handed over through [`StoredFonts`] as they
+//! arrive. Fonts that can't be read (including when the database can't be
+//! opened at all, e.g. in some private browsing modes) are handed over
+//! without data, so that the app forgets them rather than waiting for them.
+//! Failing to save or remove a font is ignored, as it only means that it has
+//! to be added (or removed) again.
+//!
+//! The database is opened once, the first time it is needed, and the
+//! connection is kept for the rest of the session.
+
+use std::{cell::RefCell, rc::Rc};
+
+use eframe::wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use egui::Context;
+use js_sys::Uint8Array;
+use web_sys::{Event, IdbDatabase, IdbObjectStore, IdbTransactionMode};
+
+const DATABASE_NAME: &str = "pend";
+const DATABASE_VERSION: u32 = 1;
+const FONT_STORE: &str = "fonts";
+
+/// Fonts that have been read from storage, but not yet taken by the app, as
+/// (name, data). Fonts that couldn't be found (or read) have no data
+pub type StoredFonts = Rc<RefCell<Vec<(String, Option<Vec<u8>>)>>>;
+
+/// Something to do with the database once it is open, which is given `None`
+/// if it couldn't be
+type DatabaseTask = Box<dyn FnOnce(Option<&IdbDatabase>)>;
+
+/// The connection to the database
+enum Connection {
+  Closed,
+  /// Being opened, with the tasks waiting for it
+  Opening(Vec<DatabaseTask>),
+  Open(IdbDatabase),
+  Failed,
+}
+
+thread_local! {
+  static CONNECTION: RefCell<Connection> = RefCell::new(Connection::Closed);
+}
+
+/// Hands the database to `then`, opening it (and creating it, if this is the
+/// first time) if it isn't yet. `then` is given `None` if the database can't
+/// be opened
+fn with_database(then: impl FnOnce(Option<&IdbDatabase>) + 'static) {
+  let mut task: Option<DatabaseTask> = Some(Box::new(then));
+
+  let (database, is_closed) = CONNECTION.with(|connection| {
+    let mut connection = connection.borrow_mut();
+    match &mut *connection {
+      Connection::Closed => {
+        *connection = Connection::Opening(task.take().into_iter().collect());
+        (None, true)
+      }
+      Connection::Opening(tasks) => {
+        tasks.extend(task.take());
+        (None, false)
+      }
+      Connection::Open(database) => (Some(database.clone()), false),
+      Connection::Failed => (None, false),
+    }
+  });
+
+  // Tasks are run without the connection borrowed, so they can use it too
+  if let Some(task) = task {
+    task(database.as_ref());
+  }
+  if is_closed && !open_database() {
+    finish_opening(None);
+  }
+}
+
+/// Starts opening the database, returning whether it could be started
+fn open_database() -> bool {
+  let request = match web_sys::window()
+    .and_then(|window| window.indexed_db().ok().flatten())
+    .and_then(|factory| {
+      factory.open_with_u32(DATABASE_NAME, DATABASE_VERSION).ok()
+    }) {
+    Some(request) => request,
+    None => return false,
+  };
+
+  let upgraded = request.clone();
+  let on_upgrade_needed = Closure::once_into_js(move |_: Event| {
+    if let Ok(database) = upgraded.result() {
+      database
+        .unchecked_into::<IdbDatabase>()
+        .create_object_store(FONT_STORE)
+        .ok();
+    }
+  });
+  request.set_onupgradeneeded(Some(on_upgrade_needed.unchecked_ref()));
+
+  let opened = request.clone();
+  let on_success = Closure::once_into_js(move |_: Event| {
+    finish_opening(
+      opened
+        .result()
+        .ok()
+        .map(|database| database.unchecked_into()),
+    );
+  });
+  request.set_onsuccess(Some(on_success.unchecked_ref()));
+
+  let on_error = Closure::once_into_js(move |_: Event| finish_opening(None));
+  request.set_onerror(Some(on_error.unchecked_ref()));
+
+  true
+}
+
+/// Keeps the database once it has been opened (or the failure to open it),
+/// and runs the tasks that were waiting for it
+fn finish_opening(database: Option<IdbDatabase>) {
+  let tasks = CONNECTION.with(|connection| {
+    let state = match &database {
+      Some(database) => Connection::Open(database.clone()),
+      None => Connection::Failed,
+    };
+    match std::mem::replace(&mut *connection.borrow_mut(), state) {
+      Connection::Opening(tasks) => tasks,
+      _ => Vec::new(),
+    }
+  });
+
+  for task in tasks {
+    task(database.as_ref());
+  }
+}
+
+/// The store of fonts within the database, for a new transaction
+fn font_store(
+  database: &IdbDatabase,
+  mode: IdbTransactionMode,
+) -> Option<IdbObjectStore> {
+  database
+    .transaction_with_str_and_mode(FONT_STORE, mode)
+    .ok()?
+    .object_store(FONT_STORE)
+    .ok()
+}
+
+/// Saves a font, replacing any of the same name
+pub fn save(name: &str, data: &[u8]) {
+  let name = JsValue::from_str(name);
+  let data = Uint8Array::from(data);
+
+  with_database(move |database| {
+    if let Some(store) =
+      database.and_then(|db| font_store(db, IdbTransactionMode::Readwrite))
+    {
+      store.put_with_key(&data, &name).ok();
+    }
+  });
+}
+
+/// Removes a font from storage
+pub fn remove(name: &str) {
+  let name = JsValue::from_str(name);
+
+  with_database(move |database| {
+    if let Some(store) =
+      database.and_then(|db| font_store(db, IdbTransactionMode::Readwrite))
+    {
+      store.delete(&name).ok();
+    }
+  });
+}
+
+/// Starts reading fonts from storage. Each font is added to the returned list
+/// once it has been read (or couldn't be), and a repaint is requested so that
+/// it is taken straight away
+pub fn load(ctx: &Context, names: Vec<String>) -> StoredFonts {
+  let stored = StoredFonts::default();
+  let (ctx, loaded) = (ctx.clone(), stored.clone());
+
+  with_database(move |database| {
+    let store =
+      database.and_then(|db| font_store(db, IdbTransactionMode::Readonly));
+
+    for name in names {
+      let request = match store
+        .as_ref()
+        .map(|store| store.get(&JsValue::from_str(&name)))
+      {
+        Some(Ok(request)) => request,
+        _ => {
+          loaded.borrow_mut().push((name, None));
+          ctx.request_repaint();
+          continue;
+        }
+      };
+
+      // Only one of these is called, but both need the font's name
+      let (on_success_name, on_error_name) = (name.clone(), name);
+      let (ctx_success, loaded_success, read) =
+        (ctx.clone(), loaded.clone(), request.clone());
+      let on_success = Closure::once_into_js(move |_: Event| {
+        let data = read
+          .result()
+          .ok()
+          .and_then(|value| value.dyn_into::<Uint8Array>().ok())
+          .map(|array| array.to_vec());
+        loaded_success.borrow_mut().push((on_success_name, data));
+        ctx_success.request_repaint();
+      });
+      request.set_onsuccess(Some(on_success.unchecked_ref()));
+
+      let (ctx_error, loaded_error) = (ctx.clone(), loaded.clone());
+      let on_error = Closure::once_into_js(move |_: Event| {
+        loaded_error.borrow_mut().push((on_error_name, None));
+        ctx_error.request_repaint();
+      });
+      request.set_onerror(Some(on_error.unchecked_ref()));
+    }
+  });
+
+  stored
+}
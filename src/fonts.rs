@@ -0,0 +1,858 @@
+//! Fonts for the reader: those compiled into Pend, those added by the user,
+//! and those embedded in the book that is open.
+//!
+//! Fonts added by the user are read from a directory at startup on native,
+//! and kept in the browser's storage on the web (see `font_storage`), from
+//! which they are read in the background. They can be used for the body text,
+//! or named in custom CSS by their file name.
+//!
+//! Embedded fonts are found through the `@font-face` rules of a book's
+//! stylesheets, and registered with egui under the name of their family (see
+//! [`css_font_family`]) while the book is selected. Fonts obfuscated with the
+//! IDPF or Adobe algorithms (listed in `META-INF/encryption.xml`) are
+//! restored first; fonts that are actually encrypted, or in formats egui can't
+//! read (such as WOFF), are skipped.
+//!
+//! Characters a family's fonts don't have are drawn with the fonts of the
+//! user's [`FallbackChain`]s, and then egui's own (which include emoji).
+//! Chains for a language are registered as a family of their own for each
+//! family (see [`language_font_family`]), which the reader uses for text in
+//! that language, so that e.g. Japanese and Chinese text can prefer different
+//! fonts for the characters they share.
+
+use std::{
+  collections::{BTreeSet, HashMap},
+  io::Cursor,
+  path::Path,
+  sync::Arc,
+};
+
+use egui::{Context, DroppedFile, FontData, FontDefinitions, FontFamily};
+use epub::doc::EpubDoc;
+use glob::glob;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  backend::resolve_href,
+  css::font_faces,
+  document::language_tag,
+  ui::{BookTextStyle, GotoTarget},
+  xhtml::{self, Element},
+  Pend,
+};
+
+const IDPF_OBFUSCATION: &str = "http://www.idpf.org/2008/embedding";
+const ADOBE_OBFUSCATION: &str = "http://ns.adobe.com/pdf/enc#RC";
+
+/// A font embedded in a book
+pub struct EmbeddedFont {
+  /// Name of the font's family in the book's CSS, lowercased
+  pub family: String,
+  pub data: Vec<u8>,
+}
+
+/// A font added by the user
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UserFont {
+  /// Name of the font, from its file name
+  pub name: String,
+  /// Contents of the font file, which is empty while the font is being read
+  /// from the browser's storage. Not saved with the app's state (older
+  /// versions did so on the web, from which it is still read)
+  #[serde(skip_serializing)]
+  #[serde(default)]
+  pub data: Vec<u8>,
+}
+
+impl UserFont {
+  /// Makes a font from a file, if it is a TrueType or OpenType font egui can
+  /// read
+  pub fn from_file(file_name: &str, data: Vec<u8>) -> Option<Self> {
+    let (name, extension) = file_name.rsplit_once('.')?;
+
+    if !matches!(extension.to_lowercase().as_str(), "ttf" | "otf")
+      || name.trim().is_empty()
+      || !is_font_file(&data)
+    {
+      return None;
+    }
+
+    Some(Self {
+      name: name.trim().to_string(),
+      data,
+    })
+  }
+
+  /// The egui font family the font is registered as, the same as a family of
+  /// its name in CSS
+  pub fn family(&self) -> FontFamily {
+    css_font_family(&self.name.to_lowercase())
+  }
+}
+
+/// Fonts tried in turn for characters that a family's own fonts don't have,
+/// such as those of CJK scripts
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct FallbackChain {
+  /// Name of the family the chain is for, as shown in the font picker (see
+  /// [`family_name`]), or empty for every family
+  pub family: String,
+  /// Language tag of the text the chain is for, e.g. `ja` or `zh-hant` (which
+  /// also covers `zh-hant-tw`), or empty for text in any language
+  pub language: String,
+  /// Names of the user's fonts, in the order they're tried
+  pub fonts: Vec<String>,
+}
+
+impl FallbackChain {
+  /// Whether the chain is used for a family (by its name, if it has one) in
+  /// a language (or for text in any language, if empty)
+  fn applies_to(&self, family: Option<&str>, language: &str) -> bool {
+    (self.family.is_empty() || Some(self.family.as_str()) == family)
+      && language_tag(&self.language).unwrap_or_default() == language
+  }
+}
+
+/// Which book's fonts are currently registered with egui
+#[derive(Default)]
+pub struct RegisteredFonts {
+  pub uuid: Option<String>,
+  /// Families of the fonts registered for the book
+  pub families: Vec<String>,
+  /// Whether the user's fonts or fallback chains have changed since they
+  /// were registered
+  pub user_fonts_changed: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Obfuscation {
+  Idpf,
+  Adobe,
+}
+
+/// The egui font family a font family named in CSS is registered as
+pub fn css_font_family(name: &str) -> FontFamily {
+  FontFamily::Name(Arc::from(format!("css:{}", name)))
+}
+
+/// The family registered for text in a language (a tag from
+/// [`language_tag`]), which has the fallback chains for that language before
+/// those for any language
+pub fn language_font_family(family: &FontFamily, language: &str) -> FontFamily {
+  let name = match family {
+    FontFamily::Proportional => "proportional",
+    FontFamily::Monospace => "monospace",
+    FontFamily::Name(name) => name,
+  };
+  FontFamily::Name(Arc::from(format!("{}@{}", name, language)))
+}
+
+/// The family to draw text in a language with: the most specific one
+/// registered for it (`zh-hant` before `zh` for `zh-hant-tw`) out of those
+/// egui has, otherwise the family itself
+pub fn family_for_language(
+  family: FontFamily,
+  language: Option<&str>,
+  fonts: &[FontFamily],
+) -> FontFamily {
+  let mut language = match language {
+    Some(language) => language,
+    None => return family,
+  };
+
+  loop {
+    let candidate = language_font_family(&family, language);
+    if fonts.contains(&candidate) {
+      return candidate;
+    }
+    match language.rsplit_once('-') {
+      Some((broader, _)) => language = broader,
+      None => return family,
+    }
+  }
+}
+
+/// The name of a family as shown in the font picker, if it is one of Pend's
+/// or the user's
+pub fn family_name(
+  family: &FontFamily,
+  user_fonts: &[UserFont],
+) -> Option<String> {
+  match family {
+    FontFamily::Proportional => Some("Work Sans".to_string()),
+    FontFamily::Monospace => Some("Monospace".to_string()),
+    FontFamily::Name(name) if &**name == "Merriweather" => {
+      Some("Merriweather".to_string())
+    }
+    family => user_fonts
+      .iter()
+      .find(|font| &font.family() == family)
+      .map(|font| font.name.clone()),
+  }
+}
+
+/// The fonts compiled into Pend, along with those added by the user and any
+/// embedded in the open book. Fonts of the book take the place of the user's
+/// where their families have the same name. Each family falls back on the
+/// fonts of the chains that apply to it
+pub fn font_definitions(
+  user_fonts: &[UserFont],
+  embedded: &[EmbeddedFont],
+  fallback_chains: &[FallbackChain],
+) -> FontDefinitions {
+  let mut fonts = FontDefinitions::default();
+
+  // egui's own fonts are tried last, for every family
+  let proportional_defaults = fonts.families[&FontFamily::Proportional].clone();
+  let monospace_defaults = fonts.families[&FontFamily::Monospace].clone();
+
+  fonts.font_data.insert(
+    "work_sans_medium".to_string(),
+    FontData::from_static(include_bytes!(
+      "../compiletime_resources/WorkSans-Medium.ttf"
+    )),
+  );
+
+  fonts.font_data.insert(
+    "merriweather_regular".to_string(),
+    FontData::from_static(include_bytes!(
+      "../compiletime_resources/Merriweather-Regular.ttf"
+    )),
+  );
+
+  fonts.font_data.insert(
+    "noto_mono_regular".to_string(),
+    FontData::from_static(include_bytes!(
+      "../compiletime_resources/NotoSansMono-Regular.ttf"
+    )),
+  );
+
+  // (Family, its own fonts, the fonts it falls back on after the chains)
+  let mut families = vec![
+    (
+      FontFamily::Proportional,
+      "work_sans_medium".to_string(),
+      &proportional_defaults,
+    ),
+    (
+      FontFamily::Name(Arc::from("Merriweather")),
+      "merriweather_regular".to_string(),
+      &proportional_defaults,
+    ),
+    (
+      FontFamily::Monospace,
+      "noto_mono_regular".to_string(),
+      &monospace_defaults,
+    ),
+  ];
+
+  for font in user_fonts {
+    let family = font.name.to_lowercase();
+    // Fonts that haven't been read yet are drawn with the default font until
+    // they are, so that they can stay selected
+    let name = if font.data.is_empty() {
+      "work_sans_medium".to_string()
+    } else {
+      let name = format!("user:{}", family);
+      fonts
+        .font_data
+        .insert(name.clone(), FontData::from_owned(font.data.clone()));
+      name
+    };
+
+    if !embedded.iter().any(|font| font.family == family) {
+      families.push((css_font_family(&family), name, &proportional_defaults));
+    }
+  }
+  for font in embedded {
+    let name = format!("book:{}", font.family);
+    fonts
+      .font_data
+      .insert(name.clone(), FontData::from_owned(font.data.clone()));
+    families.push((
+      css_font_family(&font.family),
+      name,
+      &proportional_defaults,
+    ));
+  }
+
+  let languages: BTreeSet<String> = fallback_chains
+    .iter()
+    .filter_map(|chain| language_tag(&chain.language))
+    .collect();
+
+  for (family, own_font, defaults) in families {
+    let name = family_name(&family, user_fonts);
+    let chain_fonts = |language: &str| -> Vec<String> {
+      fallback_chains
+        .iter()
+        .filter(|chain| chain.applies_to(name.as_deref(), language))
+        .flat_map(|chain| &chain.fonts)
+        .map(|font| format!("user:{}", font.to_lowercase()))
+        .filter(|font| fonts.font_data.contains_key(font))
+        .collect()
+    };
+    let any_language = chain_fonts("");
+
+    for language in &languages {
+      let list = fallback_list(
+        &own_font,
+        chain_fonts(language).iter().chain(&any_language),
+        defaults,
+      );
+      fonts
+        .families
+        .insert(language_font_family(&family, language), list);
+    }
+
+    let list = fallback_list(&own_font, any_language.iter(), defaults);
+    fonts.families.insert(family, list);
+  }
+
+  fonts
+}
+
+/// A family's font followed by those it falls back on, each only once
+fn fallback_list<'a>(
+  own_font: &str,
+  chain_fonts: impl Iterator<Item = &'a String>,
+  defaults: &'a [String],
+) -> Vec<String> {
+  let mut list = vec![own_font.to_string()];
+  for font in chain_fonts.chain(defaults) {
+    if !list.contains(font) {
+      list.push(font.clone());
+    }
+  }
+  list
+}
+
+/// Reads the fonts (TTF & OTF files) in a directory and its subfolders
+pub fn load_font_directory<P: AsRef<Path>>(directory: P) -> Vec<UserFont> {
+  let pattern = format!("{}/**/*", directory.as_ref().display());
+  let mut fonts: Vec<UserFont> = match glob(&pattern) {
+    Ok(paths) => paths
+      .flatten()
+      .filter_map(|path| {
+        let file_name = path.file_name()?.to_string_lossy().to_string();
+        UserFont::from_file(&file_name, std::fs::read(&path).ok()?)
+      })
+      .collect(),
+    Err(_) => Vec::new(),
+  };
+
+  fonts.sort_by(|a, b| a.name.cmp(&b.name));
+  fonts.dedup_by(|a, b| a.name.eq_ignore_ascii_case(&b.name));
+  fonts
+}
+
+/// Adds a font that was dropped onto the window, replacing any of the same
+/// name. The file is also copied into the fonts directory on native (or the
+/// browser's storage on the web), so that it is loaded again on startup.
+/// Files that aren't fonts are ignored
+pub fn add_dropped_font(state: &mut Pend, file: &DroppedFile) {
+  let (file_name, data) = match (&file.bytes, &file.path) {
+    (Some(bytes), _) => (file.name.clone(), bytes.to_vec()),
+    (None, Some(path)) => match (path.file_name(), std::fs::read(path)) {
+      (Some(name), Ok(data)) => (name.to_string_lossy().to_string(), data),
+      _ => return,
+    },
+    (None, None) => return,
+  };
+
+  let font = match UserFont::from_file(&file_name, data) {
+    Some(font) => font,
+    None => return,
+  };
+
+  // Failing to copy the font only means it has to be added again next time
+  #[cfg(not(target_arch = "wasm32"))]
+  if std::fs::create_dir_all(&state.fonts_path).is_ok() {
+    std::fs::write(Path::new(&state.fonts_path).join(&file_name), &font.data)
+      .ok();
+  }
+  #[cfg(target_arch = "wasm32")]
+  crate::font_storage::save(&font.name, &font.data);
+
+  state
+    .user_fonts
+    .retain(|existing| !existing.name.eq_ignore_ascii_case(&font.name));
+  state.user_fonts.push(font);
+  state.user_fonts.sort_by(|a, b| a.name.cmp(&b.name));
+  state.registered_fonts.user_fonts_changed = true;
+}
+
+/// Hands a set of fonts to egui, to be used from the next frame on. The body
+/// font goes back to the default if it is no longer among them
+pub fn set_fonts(ctx: &Context, state: &mut Pend, embedded: &[EmbeddedFont]) {
+  let definitions =
+    font_definitions(&state.user_fonts, embedded, &state.font_fallbacks);
+
+  if !definitions
+    .families
+    .contains_key(&state.book_style.font_family)
+  {
+    state.book_style.font_family = BookTextStyle::default().font_family;
+  }
+
+  ctx.set_fonts(definitions);
+  state.registered_fonts.user_fonts_changed = false;
+}
+
+/// Fills in the data of the user's fonts as it is read from the browser's
+/// storage. Fonts that aren't in storage (or can't be read from it) are
+/// forgotten, rather than being drawn with the default font for good
+#[cfg(target_arch = "wasm32")]
+fn receive_stored_fonts(state: &mut Pend) {
+  let received: Vec<_> = state.stored_fonts.borrow_mut().drain(..).collect();
+
+  for (name, data) in received {
+    match data {
+      Some(data) => {
+        if let Some(font) =
+          state.user_fonts.iter_mut().find(|font| font.name == name)
+        {
+          font.data = data;
+        }
+      }
+      None => state.user_fonts.retain(|font| font.name != name),
+    }
+    state.registered_fonts.user_fonts_changed = true;
+  }
+}
+
+/// Registers the fonts of the selected book with egui (and unregisters those
+/// of the book selected before it), if it or the user's fonts have changed
+/// since the last call.
+///
+/// The new fonts are used from the next frame on. As text laid out with the
+/// old fonts can't be drawn with the new ones, the cached chapters are
+/// cleared, and the reader returns to its position once they're laid out again
+pub fn register_fonts(ctx: &Context, state: &mut Pend) {
+  #[cfg(target_arch = "wasm32")]
+  receive_stored_fonts(state);
+
+  let user_fonts_changed = state.registered_fonts.user_fonts_changed;
+  if state.registered_fonts.uuid == state.selected_book_uuid
+    && !user_fonts_changed
+  {
+    return;
+  }
+
+  let fonts = match &state.selected_book_uuid {
+    Some(uuid) => match state.epub_cache.get_mut(uuid) {
+      Some(book) => load_book_fonts(book),
+      // Tried again once the book is loaded
+      None => return,
+    },
+    None => Vec::new(),
+  };
+  state.registered_fonts.uuid = state.selected_book_uuid.clone();
+
+  let families: Vec<String> =
+    fonts.iter().map(|font| font.family.clone()).collect();
+  if families.is_empty()
+    && state.registered_fonts.families.is_empty()
+    && !user_fonts_changed
+  {
+    return;
+  }
+  state.registered_fonts.families = families;
+
+  set_fonts(ctx, state, &fonts);
+  state.chapter_cache.clear();
+  if let Some(position) = state
+    .selected_book_uuid
+    .as_ref()
+    .and_then(|uuid| state.book_userdata.get(uuid))
+    .and_then(|book_info| book_info.position.clone())
+  {
+    state.goto_target = Some(GotoTarget::position(position));
+  }
+}
+
+/// Reads the fonts declared by a book's stylesheets. Where a family has more
+/// than one font, the regular (not bold or italic) one is preferred, as the
+/// reader draws bold & italic text itself
+pub fn load_book_fonts(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+) -> Vec<EmbeddedFont> {
+  let obfuscated = obfuscated_resources(book);
+
+  let stylesheets: Vec<String> = book
+    .resources
+    .values()
+    .filter(|(_, mime)| mime == "text/css")
+    .map(|(path, _)| path.to_string_lossy().replace('\\', "/"))
+    .collect();
+
+  // Family -> (is a regular font, path within the epub)
+  let mut sources: HashMap<String, (bool, String)> = HashMap::new();
+  for stylesheet_path in stylesheets {
+    let css = match book.get_resource_str_by_path(&stylesheet_path) {
+      Ok(css) => css,
+      Err(_) => continue,
+    };
+
+    for face in font_faces(&css) {
+      let is_regular = !face.bold && !face.italic;
+      let path = resolve_href(&stylesheet_path, &face.src);
+
+      match sources.get(&face.family) {
+        Some((true, _)) => {}
+        Some((false, _)) if !is_regular => {}
+        _ => {
+          sources.insert(face.family, (is_regular, path));
+        }
+      }
+    }
+  }
+
+  let mut fonts = Vec::new();
+  for (family, (_, path)) in sources {
+    let mut data = match book.get_resource_by_path(&path) {
+      Ok(data) => data,
+      Err(_) => continue,
+    };
+
+    match obfuscated.get(&path) {
+      Some(Some(obfuscation)) => deobfuscate(book, &mut data, *obfuscation),
+      // Encrypted with something other than obfuscation (e.g. DRM)
+      Some(None) => continue,
+      None => {}
+    }
+
+    if is_font_file(&data) {
+      fonts.push(EmbeddedFont { family, data });
+    }
+  }
+
+  // Registered in a stable order, so the font definitions only differ when
+  // the fonts do
+  fonts.sort_by(|a, b| a.family.cmp(&b.family));
+  fonts
+}
+
+/// Paths of the files listed in a book's `META-INF/encryption.xml`, with the
+/// obfuscation used on each (or `None` if it isn't one that can be undone)
+fn obfuscated_resources(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+) -> HashMap<String, Option<Obfuscation>> {
+  let mut resources = HashMap::new();
+
+  let bytes = match book.get_resource_by_path("META-INF/encryption.xml") {
+    Ok(bytes) => bytes,
+    Err(_) => return resources,
+  };
+  let root = xhtml::parse(&String::from_utf8_lossy(&bytes));
+
+  let mut encrypted = Vec::new();
+  collect_elements(&root, "encrypteddata", &mut encrypted);
+  for data in encrypted {
+    let obfuscation = match data
+      .find("encryptionmethod")
+      .and_then(|method| method.attr("algorithm"))
+    {
+      Some(IDPF_OBFUSCATION) => Some(Obfuscation::Idpf),
+      Some(ADOBE_OBFUSCATION) => Some(Obfuscation::Adobe),
+      _ => None,
+    };
+
+    if let Some(uri) = data
+      .find("cipherreference")
+      .and_then(|reference| reference.attr("uri"))
+    {
+      // Paths in `encryption.xml` are relative to the root of the epub
+      resources.insert(resolve_href("", uri), obfuscation);
+    }
+  }
+
+  resources
+}
+
+/// Collects every element with a given name within an element
+fn collect_elements<'a>(
+  element: &'a Element,
+  name: &str,
+  found: &mut Vec<&'a Element>,
+) {
+  for child in element.child_elements() {
+    if child.name == name {
+      found.push(child);
+    }
+    collect_elements(child, name, found);
+  }
+}
+
+/// Undoes the obfuscation of a font, which XORs the start of the file with a
+/// key derived from the book's identifier
+fn deobfuscate(
+  book: &EpubDoc<Cursor<Vec<u8>>>,
+  data: &mut [u8],
+  obfuscation: Obfuscation,
+) {
+  let identifier = book.unique_identifier.clone().unwrap_or_default();
+  let uuid = book
+    .metadata
+    .get("identifier")
+    .and_then(|identifiers| {
+      identifiers
+        .iter()
+        .find(|identifier| identifier.starts_with("urn:uuid:"))
+    })
+    .unwrap_or(&identifier);
+
+  if let Some((key, length)) = obfuscation_key(obfuscation, &identifier, uuid) {
+    apply_key(data, &key, length);
+  }
+}
+
+/// XORs the first `length` bytes of data with a key, which both obfuscates
+/// and deobfuscates them
+fn apply_key(data: &mut [u8], key: &[u8], length: usize) {
+  for (index, byte) in data.iter_mut().take(length).enumerate() {
+    *byte ^= key[index % key.len()];
+  }
+}
+
+/// The key a font is obfuscated with, given the book's unique identifier and
+/// UUID, along with the number of bytes at the start of the file it is
+/// applied to
+fn obfuscation_key(
+  obfuscation: Obfuscation,
+  identifier: &str,
+  uuid: &str,
+) -> Option<(Vec<u8>, usize)> {
+  match obfuscation {
+    // SHA-1 of the identifier without any whitespace, over 1040 bytes
+    Obfuscation::Idpf => {
+      let identifier: String = identifier
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n'))
+        .collect();
+      Some((sha1(identifier.as_bytes()).to_vec(), 1040))
+    }
+    // The bytes of the book's UUID, over 1024 bytes
+    Obfuscation::Adobe => Some((uuid_bytes(uuid)?.to_vec(), 1024)),
+  }
+}
+
+/// The 16 bytes of a UUID, written with or without `urn:uuid:` and dashes
+fn uuid_bytes(uuid: &str) -> Option<[u8; 16]> {
+  let hex: Vec<u8> = uuid
+    .trim()
+    .trim_start_matches("urn:uuid:")
+    .chars()
+    .filter(|c| *c != '-')
+    .map(|c| c.to_digit(16).map(|digit| digit as u8))
+    .collect::<Option<_>>()?;
+  if hex.len() != 32 {
+    return None;
+  }
+
+  let mut bytes = [0; 16];
+  for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+    *byte = pair[0] * 16 + pair[1];
+  }
+  Some(bytes)
+}
+
+/// Whether data looks like a TrueType or OpenType font whose tables are all
+/// within the file, as egui panics on fonts it can't read
+fn is_font_file(data: &[u8]) -> bool {
+  let read_u16 = |at: usize| -> Option<usize> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?) as usize)
+  };
+  let read_u32 = |at: usize| -> Option<usize> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?) as usize)
+  };
+
+  if !matches!(data.get(..4), Some([0, 1, 0, 0] | b"OTTO" | b"true")) {
+    return false;
+  }
+
+  let tables = match read_u16(4) {
+    Some(tables) if tables > 0 => tables,
+    _ => return false,
+  };
+  (0..tables).all(|table| {
+    let record = 12 + table * 16;
+    // Offset + length can overflow where `usize` is 32 bits (WASM)
+    let end = read_u32(record + 8)
+      .zip(read_u32(record + 12))
+      .and_then(|(offset, length)| offset.checked_add(length));
+    matches!(end, Some(end) if end <= data.len())
+  })
+}
+
+/// The SHA-1 hash of some data, used for the IDPF font obfuscation key
+fn sha1(data: &[u8]) -> [u8; 20] {
+  let mut state: [u32; 5] = [
+    0x6745_2301,
+    0xEFCD_AB89,
+    0x98BA_DCFE,
+    0x1032_5476,
+    0xC3D2_E1F0,
+  ];
+
+  let mut message = data.to_vec();
+  message.push(0x80);
+  while message.len() % 64 != 56 {
+    message.push(0);
+  }
+  message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+  for block in message.chunks(64) {
+    let mut words = [0u32; 80];
+    for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+      *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    for index in 16..80 {
+      words[index] = (words[index - 3]
+        ^ words[index - 8]
+        ^ words[index - 14]
+        ^ words[index - 16])
+        .rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = state;
+    for (index, word) in words.iter().enumerate() {
+      let (f, k) = match index {
+        0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+        20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+        40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+        _ => (b ^ c ^ d, 0xCA62_C1D6),
+      };
+      let next = a
+        .rotate_left(5)
+        .wrapping_add(f)
+        .wrapping_add(e)
+        .wrapping_add(k)
+        .wrapping_add(*word);
+      e = d;
+      d = c;
+      c = b.rotate_left(30);
+      b = a;
+      a = next;
+    }
+
+    for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+      *value = value.wrapping_add(add);
+    }
+  }
+
+  let mut hash = [0; 20];
+  for (bytes, value) in hash.chunks_mut(4).zip(state) {
+    bytes.copy_from_slice(&value.to_be_bytes());
+  }
+  hash
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+  }
+
+  /// A font file with a single table of `length` bytes
+  fn font_file(offset: u32, length: u32) -> Vec<u8> {
+    let mut data = vec![0, 1, 0, 0, 0, 1, 0, 16, 0, 0, 0, 0];
+    data.extend_from_slice(b"glyf");
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&offset.to_be_bytes());
+    data.extend_from_slice(&length.to_be_bytes());
+    data.extend((0..length.min(2000)).map(|index| index as u8));
+    data
+  }
+
+  #[test]
+  fn sha1_matches_known_hashes() {
+    assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(
+      hex(&sha1(b"abc")),
+      "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    // Long enough that the padding takes another block
+    assert_eq!(
+      hex(&sha1(
+        b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+      )),
+      "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+    );
+  }
+
+  #[test]
+  fn uuids_are_read_with_or_without_prefix() {
+    let bytes = [
+      0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x01, 0x23, 0x45, 0x67,
+      0x89, 0xab, 0xcd, 0xef,
+    ];
+
+    assert_eq!(
+      uuid_bytes("urn:uuid:12345678-9abc-def0-0123-456789abcdef"),
+      Some(bytes)
+    );
+    assert_eq!(
+      uuid_bytes(" 123456789ABCDEF00123456789ABCDEF "),
+      Some(bytes)
+    );
+    assert_eq!(uuid_bytes("urn:isbn:9780000000000"), None);
+    assert_eq!(uuid_bytes("12345678-9abc"), None);
+  }
+
+  #[test]
+  fn idpf_key_is_the_hash_of_the_identifier_without_whitespace() {
+    let (key, length) = obfuscation_key(
+      Obfuscation::Idpf,
+      " urn:uuid:0000\n0000 ",
+      "urn:uuid:0000",
+    )
+    .unwrap();
+
+    assert_eq!(key, sha1(b"urn:uuid:00000000"));
+    assert_eq!(length, 1040);
+  }
+
+  #[test]
+  fn adobe_key_is_the_bytes_of_the_uuid() {
+    let uuid = "urn:uuid:12345678-9abc-def0-0123-456789abcdef";
+    let (key, length) =
+      obfuscation_key(Obfuscation::Adobe, "isbn", uuid).unwrap();
+
+    assert_eq!(key, uuid_bytes(uuid).unwrap());
+    assert_eq!(length, 1024);
+    assert_eq!(obfuscation_key(Obfuscation::Adobe, "isbn", "isbn"), None);
+  }
+
+  #[test]
+  fn deobfuscating_restores_the_font() {
+    let font = font_file(28, 1500);
+    let uuid = "urn:uuid:12345678-9abc-def0-0123-456789abcdef";
+
+    for obfuscation in [Obfuscation::Idpf, Obfuscation::Adobe] {
+      let (key, length) = obfuscation_key(obfuscation, uuid, uuid).unwrap();
+
+      let mut data = font.clone();
+      apply_key(&mut data, &key, length);
+      assert!(!is_font_file(&data));
+      // Only the start of the file is obfuscated
+      assert_eq!(data[length..], font[length..]);
+
+      apply_key(&mut data, &key, length);
+      assert_eq!(data, font);
+      assert!(is_font_file(&data));
+    }
+  }
+
+  #[test]
+  fn font_files_need_their_tables_within_them() {
+    assert!(is_font_file(&font_file(28, 4)));
+    assert!(!is_font_file(&font_file(28, 4)[..31]));
+    assert!(!is_font_file(&font_file(u32::MAX, 4)));
+    assert!(!is_font_file(&font_file(u32::MAX, u32::MAX)[..32]));
+    assert!(!is_font_file(b"wOFF\0\0\0\0"));
+  }
+}
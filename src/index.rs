@@ -0,0 +1,743 @@
+//! A persistent full-text index of every book in the library, used to search
+//! through the text of all books at once.
+//!
+//! The index is stored as a plain text file within the library directory.
+//! Books are queued for indexing as they are loaded (whether from the library
+//! directory, or dropped onto the app), and indexed a chapter per frame so
+//! that the app stays usable meanwhile. Books from the library directory are
+//! only (re)indexed when their file is new or has changed since it was last
+//! indexed. Books loaded some other way (e.g. on the web) are only indexed in
+//! memory
+
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  fs,
+  io::Cursor,
+  ops::Range,
+  path::{Path, PathBuf},
+  time::UNIX_EPOCH,
+};
+
+use epub::doc::EpubDoc;
+
+use crate::{
+  backend::chapter_document, document::Document, location::TextPosition,
+  search::match_context,
+};
+
+/// Name of the index file within the library directory
+pub const INDEX_FILE_NAME: &str = ".pend-index";
+
+/// First line of the index file, changed whenever the format (or the way text
+/// is extracted from books) changes so that old indexes are rebuilt
+const INDEX_HEADER: &str = "pend-index 2";
+
+/// Maximum number of results returned by a query
+const MAX_RESULTS: usize = 50;
+
+/// A book file that has been indexed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedBook {
+  pub uuid: String,
+  pub path: String,
+  /// Modification time (in seconds since the unix epoch) of the file
+  pub modified: u64,
+  /// Size of the file in bytes
+  pub length: u64,
+  /// Number of blocks of text within the book
+  pub blocks: u64,
+}
+
+/// An occurrence of a term within a block of a book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Posting {
+  /// Key of the book within [`LibraryIndex::books`]
+  pub book: u32,
+  pub chapter: u32,
+  pub block: u32,
+  /// How many times the term appears in the block
+  pub count: u32,
+}
+
+/// A book waiting to be indexed
+#[derive(Debug)]
+struct PendingBook {
+  uuid: String,
+  /// Path of the book's file, for books loaded from one
+  path: Option<PathBuf>,
+  /// (Key the book will have within [`LibraryIndex::books`], next chapter to
+  /// index) once indexing has started
+  progress: Option<(u32, usize)>,
+  /// Number of blocks of text indexed so far
+  blocks: u64,
+}
+
+/// Maps each term to the blocks of the books that contain it
+#[derive(Debug, Default)]
+pub struct LibraryIndex {
+  pub books: HashMap<u32, IndexedBook>,
+  pub terms: HashMap<String, Vec<Posting>>,
+  /// Path the index is saved to, if it belongs to a library directory
+  file: Option<String>,
+  /// Books still to be indexed, in order. Their postings are added as they
+  /// go, but they only match queries once they are in `books`
+  pending: VecDeque<PendingBook>,
+  /// Whether the index has changed since it was last saved
+  changed: bool,
+}
+
+/// A block of a book matching a query
+#[derive(Debug, Clone, PartialEq)]
+pub struct LibraryHit {
+  pub uuid: String,
+  pub chapter: usize,
+  pub position: TextPosition,
+  pub score: f32,
+  /// Some of the text of the block, around the first matching term
+  pub snippet: String,
+  /// Byte range of the matching term within `snippet`
+  pub snippet_match: Range<usize>,
+}
+
+impl LibraryIndex {
+  /// Reads the index from a file. Missing, outdated, or broken indexes are
+  /// treated as empty so that they get rebuilt
+  pub fn load(path: &str) -> Self {
+    let index = fs::read_to_string(path)
+      .ok()
+      .and_then(|contents| Self::parse(&contents))
+      .unwrap_or_default();
+
+    Self {
+      file: Some(path.to_string()),
+      ..index
+    }
+  }
+
+  fn parse(contents: &str) -> Option<Self> {
+    let mut lines = contents.lines();
+    if lines.next()? != INDEX_HEADER {
+      return None;
+    }
+
+    let mut index = Self::default();
+
+    for line in lines {
+      let mut fields = line.splitn(7, '\t');
+
+      match fields.next()? {
+        // Book: id, uuid, modified, length, blocks, path
+        "B" => {
+          let id = fields.next()?.parse().ok()?;
+          let book = IndexedBook {
+            uuid: fields.next()?.to_string(),
+            modified: fields.next()?.parse().ok()?,
+            length: fields.next()?.parse().ok()?,
+            blocks: fields.next()?.parse().ok()?,
+            path: fields.next()?.to_string(),
+          };
+          index.books.insert(id, book);
+        }
+        // Term: the term, then book,chapter,block,count for each posting
+        "T" => {
+          let term = fields.next()?.to_string();
+          let postings = fields
+            .next()?
+            .split(';')
+            .map(|posting| {
+              let mut numbers = posting.split(',').map(str::parse::<u32>);
+              Some(Posting {
+                book: numbers.next()?.ok()?,
+                chapter: numbers.next()?.ok()?,
+                block: numbers.next()?.ok()?,
+                count: numbers.next()?.ok()?,
+              })
+            })
+            .collect::<Option<Vec<_>>>()?;
+          index.terms.insert(term, postings);
+        }
+        _ => return None,
+      }
+    }
+
+    Some(index)
+  }
+
+  pub fn save(&self, path: &str) -> std::io::Result<()> {
+    let mut contents = String::from(INDEX_HEADER);
+    contents.push('\n');
+
+    for (id, book) in &self.books {
+      contents.push_str(&format!(
+        "B\t{}\t{}\t{}\t{}\t{}\t{}\n",
+        id, book.uuid, book.modified, book.length, book.blocks, book.path
+      ));
+    }
+    for (term, postings) in &self.terms {
+      let postings: Vec<String> = postings
+        .iter()
+        .map(|p| format!("{},{},{},{}", p.book, p.chapter, p.block, p.count))
+        .collect();
+      contents.push_str(&format!("T\t{}\t{}\n", term, postings.join(";")));
+    }
+
+    fs::write(path, contents)
+  }
+
+  /// Whether the file at a path has already been indexed in its current state
+  pub fn is_current(&self, path: &Path) -> bool {
+    let (modified, length) = file_info(path);
+    let path = path.to_string_lossy();
+
+    self.books.values().any(|book| {
+      book.path == path && book.modified == modified && book.length == length
+    })
+  }
+
+  /// Queues a book to be indexed by [`Self::index_pending`], unless it
+  /// already has been. Books loaded from a file are indexed by their file,
+  /// so that copies of a book are kept apart. Other books are only indexed if
+  /// no copy of them is
+  pub fn queue_book(&mut self, uuid: &str, path: Option<&Path>) {
+    let is_known = match path {
+      Some(path) => {
+        self.is_current(path)
+          || self
+            .pending
+            .iter()
+            .any(|pending| pending.path.as_deref() == Some(path))
+      }
+      None => {
+        self.books.values().any(|book| book.uuid == uuid)
+          || self.pending.iter().any(|pending| pending.uuid == uuid)
+      }
+    };
+
+    if !is_known {
+      self.pending.push_back(PendingBook {
+        uuid: uuid.to_string(),
+        path: path.map(Path::to_path_buf),
+        progress: None,
+        blocks: 0,
+      });
+    }
+  }
+
+  /// Indexes the next chapter of the books waiting to be indexed (taking the
+  /// books from the cache of loaded ones), and saves the index once there
+  /// are none left. Returns whether there are any left
+  pub fn index_pending(
+    &mut self,
+    books: &mut HashMap<String, EpubDoc<Cursor<Vec<u8>>>>,
+  ) -> bool {
+    let mut pending = match self.pending.pop_front() {
+      Some(pending) => pending,
+      None => {
+        if self.changed {
+          // The index can always be rebuilt, so failing to save it isn't
+          // fatal
+          if let Some(file) = &self.file {
+            self.save(file).ok();
+          }
+          self.changed = false;
+        }
+        return false;
+      }
+    };
+
+    let (id, chapter) = match pending.progress {
+      Some(progress) => progress,
+      None => {
+        // Older versions of the file are replaced. Other copies of the same
+        // book are kept, as they are files of their own
+        if let Some(path) = &pending.path {
+          let path = path.to_string_lossy();
+          self.remove_books(|book| book.path == path);
+        }
+        // Only one book is indexed at a time, so the first free key is
+        // free until it is done
+        let id = (0..).find(|id| !self.books.contains_key(id)).unwrap_or(0);
+        (id, 0)
+      }
+    };
+
+    let book = match books.get_mut(&pending.uuid) {
+      Some(book) => book,
+      // The book was removed before it could be indexed
+      None => {
+        self.remove_postings(&[id].into());
+        return true;
+      }
+    };
+
+    if chapter < book.spine.len() {
+      if let Some(document) = chapter_document(book, chapter) {
+        self.add_chapter(id, chapter, &document);
+        pending.blocks += document.blocks.len() as u64;
+      }
+      pending.progress = Some((id, chapter + 1));
+      self.pending.push_front(pending);
+    } else {
+      let (modified, length) =
+        pending.path.as_deref().map_or((0, 0), file_info);
+      self.books.insert(
+        id,
+        IndexedBook {
+          uuid: pending.uuid,
+          path: pending
+            .path
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_default(),
+          modified,
+          length,
+          blocks: pending.blocks,
+        },
+      );
+      self.changed = true;
+    }
+
+    true
+  }
+
+  /// Adds the text of a chapter of a book to the index
+  fn add_chapter(&mut self, id: u32, chapter: usize, document: &Document) {
+    for (index, block) in document.blocks.iter().enumerate() {
+      let mut counts: HashMap<String, u32> = HashMap::new();
+      for term in tokenize(&block.text()) {
+        *counts.entry(term).or_default() += 1;
+      }
+
+      for (term, count) in counts {
+        self.terms.entry(term).or_default().push(Posting {
+          book: id,
+          chapter: chapter as u32,
+          block: index as u32,
+          count,
+        });
+      }
+    }
+  }
+
+  /// Removes books whose files are no longer present. Returns whether any
+  /// books were removed
+  pub fn retain_paths(&mut self, paths: &HashSet<String>) -> bool {
+    let removed = self.remove_books(|book| !paths.contains(&book.path));
+    self.changed |= removed;
+    removed
+  }
+
+  /// Removes the books matching a predicate, along with all of their postings.
+  /// Returns whether any books were removed
+  fn remove_books(&mut self, predicate: impl Fn(&IndexedBook) -> bool) -> bool {
+    let removed: HashSet<u32> = self
+      .books
+      .iter()
+      .filter(|(_, book)| predicate(book))
+      .map(|(id, _)| *id)
+      .collect();
+
+    if removed.is_empty() {
+      return false;
+    }
+
+    self.books.retain(|id, _| !removed.contains(id));
+    self.remove_postings(&removed);
+
+    true
+  }
+
+  /// Removes all postings of the books with the given keys
+  fn remove_postings(&mut self, books: &HashSet<u32>) {
+    for postings in self.terms.values_mut() {
+      postings.retain(|posting| !books.contains(&posting.book));
+    }
+    self.terms.retain(|_, postings| !postings.is_empty());
+  }
+
+  /// Finds the blocks containing every term of a query, ranked by how often
+  /// the terms appear in them (rarer terms counting for more). Results are
+  /// (book uuid, chapter, block, score)
+  pub fn query(&self, query: &str) -> Vec<(String, usize, usize, f32)> {
+    let terms: HashSet<String> = tokenize(query).collect();
+    if terms.is_empty() {
+      return Vec::new();
+    }
+
+    let total_blocks: u64 = self.books.values().map(|book| book.blocks).sum();
+    let mut scores: HashMap<(u32, u32, u32), (usize, f32)> = HashMap::new();
+
+    for term in &terms {
+      let postings = match self.terms.get(term) {
+        Some(postings) => postings,
+        None => return Vec::new(),
+      };
+      let rarity = (1.0 + total_blocks as f32 / postings.len() as f32).ln();
+
+      for posting in postings {
+        let (matched_terms, score) = scores
+          .entry((posting.book, posting.chapter, posting.block))
+          .or_default();
+        *matched_terms += 1;
+        *score += (1.0 + (posting.count as f32).ln()) * rarity;
+      }
+    }
+
+    let mut results: Vec<(String, usize, usize, f32)> = scores
+      .into_iter()
+      .filter(|(_, (matched_terms, _))| *matched_terms == terms.len())
+      .filter_map(|((book, chapter, block), (_, score))| {
+        let uuid = self.books.get(&book)?.uuid.clone();
+        Some((uuid, chapter as usize, block as usize, score))
+      })
+      .collect();
+
+    results.sort_by(|a, b| b.3.total_cmp(&a.3));
+    // Copies of the same book match in the same places, so only one is kept
+    let mut seen = HashSet::new();
+    results.retain(|(uuid, chapter, block, _)| {
+      seen.insert((uuid.clone(), *chapter, *block))
+    });
+    results.truncate(MAX_RESULTS);
+    results
+  }
+}
+
+/// Runs a query against the index, and cuts snippets for the results out of
+/// the books
+pub fn search_library(
+  index: &LibraryIndex,
+  books: &mut HashMap<String, EpubDoc<Cursor<Vec<u8>>>>,
+  query: &str,
+) -> Vec<LibraryHit> {
+  let terms: Vec<String> = tokenize(query).collect();
+  let mut documents: HashMap<(String, usize), Option<Document>> =
+    HashMap::new();
+
+  index
+    .query(query)
+    .into_iter()
+    .filter_map(|(uuid, chapter, block, score)| {
+      let book = books.get_mut(&uuid)?;
+      let document = documents
+        .entry((uuid.clone(), chapter))
+        .or_insert_with(|| chapter_document(book, chapter))
+        .as_ref()?;
+      let text = document.blocks.get(block)?.text();
+
+      // The snippet is centred on the first term of the query found
+      let lowercase = text.to_lowercase();
+      let found = terms
+        .iter()
+        .filter_map(|term| {
+          lowercase
+            .find(term.as_str())
+            .map(|start| start..start + term.len())
+        })
+        .min_by_key(|range| range.start)
+        // Lowercasing may change the length of the text
+        .filter(|range| {
+          text.is_char_boundary(range.start) && text.is_char_boundary(range.end)
+        })
+        .unwrap_or(0..0);
+      let (snippet, snippet_match) = match_context(&text, found.clone());
+
+      let start = text[..found.start].chars().count();
+
+      Some(LibraryHit {
+        position: TextPosition::new(chapter, document, block, start),
+        uuid,
+        chapter,
+        score,
+        snippet,
+        snippet_match,
+      })
+    })
+    .collect()
+}
+
+/// Splits text into lowercase words. Chinese & Japanese aren't written with
+/// spaces between words (and finding them needs a dictionary), so each of
+/// their ideographs & kana is taken as a word of its own: a query for a word
+/// in them matches the blocks containing all of its characters
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+  text
+    .split(|c: char| !c.is_alphanumeric())
+    .flat_map(split_ideographs)
+    .filter(|word| !word.is_empty())
+    .map(str::to_lowercase)
+}
+
+/// Splits each ideograph & kana off from the rest of a word
+fn split_ideographs(word: &str) -> Vec<&str> {
+  let mut parts = Vec::new();
+  let mut start = 0;
+
+  for (index, character) in word.char_indices() {
+    if is_ideograph(character) {
+      let end = index + character.len_utf8();
+      parts.push(&word[start..index]);
+      parts.push(&word[index..end]);
+      start = end;
+    }
+  }
+  parts.push(&word[start..]);
+
+  parts
+}
+
+/// Whether a character is a Han ideograph, or Hiragana / Katakana
+fn is_ideograph(character: char) -> bool {
+  matches!(
+    character,
+    '\u{3040}'..='\u{30FF}'
+      | '\u{31F0}'..='\u{31FF}'
+      | '\u{3400}'..='\u{4DBF}'
+      | '\u{4E00}'..='\u{9FFF}'
+      | '\u{F900}'..='\u{FAFF}'
+      | '\u{FF66}'..='\u{FF9F}'
+      | '\u{20000}'..='\u{3FFFD}'
+  )
+}
+
+/// (Modification time, size) of a file, or zeros if it can't be read
+fn file_info(path: &Path) -> (u64, u64) {
+  fs::metadata(path)
+    .map(|metadata| {
+      let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs());
+
+      (modified, metadata.len())
+    })
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn indexed_book(uuid: &str, path: &str, blocks: u64) -> IndexedBook {
+    IndexedBook {
+      uuid: uuid.to_string(),
+      path: path.to_string(),
+      modified: 1_650_000_000,
+      length: 4096,
+      blocks,
+    }
+  }
+
+  fn posting(book: u32, block: u32, count: u32) -> Posting {
+    Posting {
+      book,
+      chapter: 2,
+      block,
+      count,
+    }
+  }
+
+  /// Two copies of one book (`a`), and another book (`b`)
+  fn library() -> LibraryIndex {
+    let mut index = LibraryIndex::default();
+    index
+      .books
+      .insert(0, indexed_book("a", "library/a.epub", 10));
+    index
+      .books
+      .insert(1, indexed_book("a", "library/copy/a.epub", 10));
+    index
+      .books
+      .insert(2, indexed_book("b", "library/b\tc.epub", 5));
+
+    index.terms.insert(
+      "whale".to_string(),
+      vec![posting(0, 3, 1), posting(1, 3, 1), posting(2, 0, 4)],
+    );
+    index.terms.insert(
+      "white".to_string(),
+      vec![posting(0, 3, 2), posting(1, 3, 2), posting(2, 1, 1)],
+    );
+    index
+  }
+
+  #[test]
+  fn index_files_are_read_back_as_written() {
+    let path = std::env::temp_dir()
+      .join(format!("pend-index-test-{}", std::process::id()));
+    let path = path.to_string_lossy();
+
+    let index = library();
+    index.save(&path).unwrap();
+    let loaded = LibraryIndex::load(&path);
+    fs::remove_file(&*path).ok();
+
+    assert_eq!(loaded.books, index.books);
+    assert_eq!(loaded.terms, index.terms);
+  }
+
+  #[test]
+  fn outdated_or_broken_index_files_are_rebuilt() {
+    let book = "B\t0\ta\t1\t2\t3\tbook.epub";
+
+    assert!(
+      LibraryIndex::parse(&format!("{}\n{}", INDEX_HEADER, book)).is_some()
+    );
+    assert!(LibraryIndex::parse(&format!("pend-index 0\n{}", book)).is_none());
+    assert!(
+      LibraryIndex::parse(&format!("{}\nB\t0\ta", INDEX_HEADER)).is_none()
+    );
+    assert!(LibraryIndex::parse(&format!(
+      "{}\nT\tword\t0,1,x,1",
+      INDEX_HEADER
+    ))
+    .is_none());
+    assert!(LibraryIndex::load("/nonexistent/.pend-index")
+      .books
+      .is_empty());
+  }
+
+  #[test]
+  fn queries_need_every_term() {
+    let index = library();
+
+    assert_eq!(
+      index
+        .query("White WHALE!")
+        .into_iter()
+        .map(|(uuid, chapter, block, _)| (uuid, chapter, block))
+        .collect::<Vec<_>>(),
+      [("a".to_string(), 2, 3)]
+    );
+    assert_eq!(index.query("whale").len(), 2);
+    assert!(index.query("white shark").is_empty());
+    assert!(index.query("  ").is_empty());
+  }
+
+  #[test]
+  fn copies_of_a_book_match_once() {
+    let hits = library().query("whale");
+
+    assert_eq!(hits.len(), 2);
+    assert_eq!(hits.iter().filter(|(uuid, ..)| uuid == "a").count(), 1);
+  }
+
+  #[test]
+  fn books_are_queued_unless_already_indexed() {
+    let mut index = library();
+    let path = Path::new("library/c.epub");
+
+    index.queue_book("a", None);
+    index.queue_book("c", None);
+    index.queue_book("c", None);
+    index.queue_book("c", Some(path));
+    index.queue_book("c", Some(path));
+    assert_eq!(index.pending.len(), 2);
+
+    // Books that are no longer loaded are skipped
+    let mut books = HashMap::new();
+    assert!(index.index_pending(&mut books));
+    assert!(index.index_pending(&mut books));
+    assert!(!index.index_pending(&mut books));
+    assert_eq!(index.books.len(), 3);
+  }
+
+  #[test]
+  fn books_only_match_once_fully_indexed() {
+    let mut index = library();
+    let document = Document::from_xhtml("<body><p>A white whale</p></body>");
+
+    index.add_chapter(3, 0, &document);
+    assert_eq!(index.query("white whale").len(), 1);
+    index.books.insert(3, indexed_book("c", "", 1));
+    assert_eq!(index.query("white whale").len(), 2);
+  }
+
+  #[test]
+  fn removed_files_take_their_postings_with_them() {
+    let mut index = library();
+    let paths: HashSet<String> = ["library/a.epub".to_string()].into();
+
+    assert!(index.retain_paths(&paths));
+    assert!(!index.retain_paths(&paths));
+    assert_eq!(index.books.len(), 1);
+    assert!(index
+      .terms
+      .values()
+      .flatten()
+      .all(|posting| posting.book == 0));
+  }
+
+  #[test]
+  fn changed_files_are_no_longer_current() {
+    let path = std::env::temp_dir()
+      .join(format!("pend-index-book-{}.epub", std::process::id()));
+    fs::write(&path, b"book").unwrap();
+
+    let mut index = LibraryIndex::default();
+    let (modified, length) = file_info(&path);
+    index.books.insert(
+      0,
+      IndexedBook {
+        modified,
+        length,
+        ..indexed_book("a", &path.to_string_lossy(), 1)
+      },
+    );
+    let was_current = index.is_current(&path);
+
+    fs::write(&path, b"longer book").unwrap();
+    let is_current = index.is_current(&path);
+    fs::remove_file(&path).ok();
+
+    assert!(was_current);
+    assert!(!is_current);
+  }
+
+  #[test]
+  fn words_are_split_on_anything_but_letters_and_digits() {
+    assert_eq!(
+      tokenize("Call me Ishmael—some years ago (1851)").collect::<Vec<_>>(),
+      ["call", "me", "ishmael", "some", "years", "ago", "1851"]
+    );
+    assert_eq!(
+      tokenize("Ünïcödé, ΚΑΛΗ").collect::<Vec<_>>(),
+      ["ünïcödé", "καλη"]
+    );
+  }
+
+  #[test]
+  fn ideographs_and_kana_are_words_of_their_own() {
+    assert_eq!(
+      tokenize("日本語のテキスト, mixed漢字text").collect::<Vec<_>>(),
+      [
+        "日", "本", "語", "の", "テ", "キ", "ス", "ト", "mixed", "漢", "字",
+        "text"
+      ]
+    );
+    assert_eq!(
+      tokenize("한국어 텍스트").collect::<Vec<_>>(),
+      ["한국어", "텍스트"]
+    );
+  }
+
+  #[test]
+  fn chinese_and_japanese_words_are_found_by_their_characters() {
+    let mut index = LibraryIndex::default();
+    index.add_chapter(
+      0,
+      0,
+      &Document::from_xhtml(
+        "<body><p>吾輩は猫である。</p><p>名前はまだ無い。</p></body>",
+      ),
+    );
+    index
+      .books
+      .insert(0, indexed_book("a", "library/a.epub", 2));
+
+    assert_eq!(index.query("猫").len(), 1);
+    assert_eq!(index.query("名前").len(), 1);
+    assert_eq!(index.query("は").len(), 2);
+    assert!(index.query("犬").is_empty());
+  }
+}
@@ -3,8 +3,23 @@ use eframe::wasm_bindgen::{self, prelude::*};
 
 pub mod app;
 pub mod backend;
+pub mod css;
+pub mod document;
+#[cfg(target_arch = "wasm32")]
+pub mod font_storage;
+pub mod fonts;
+pub mod index;
+pub mod linebreak;
+pub mod location;
+pub mod pages;
 pub mod panels;
+pub mod progress;
+pub mod search;
+#[cfg(target_arch = "wasm32")]
+pub mod side_buttons;
+pub mod toc;
 pub mod ui;
+pub mod xhtml;
 use app::Pend;
 
 /// WASM entry point
@@ -0,0 +1,579 @@
+//! Where lines of text may be broken, following the Unicode line breaking
+//! algorithm ([UAX #14](https://www.unicode.org/reports/tr14/)).
+//!
+//! egui only wraps text at spaces (and between Chinese ideographs), so text
+//! in scripts that aren't written with spaces between words, such as Japanese,
+//! would overflow its page. The reader finds the places a line can be broken
+//! with [`break_opportunities`] and wraps text at them itself.
+//!
+//! Characters are sorted into line breaking classes by a table of ranges that
+//! covers the scripts books are commonly written in, rather than the full
+//! Unicode database. The rules are those of the algorithm, except that:
+//! - Conditionally non-starting characters (e.g. small kana) never start a
+//!   line, as with CSS `line-break: strict`
+//! - Scripts that need a dictionary to find word boundaries (Thai, Lao,
+//!   Khmer, Myanmar) are treated like alphabetic text
+//! - Emoji modifiers are treated as combining marks, so they stay with the
+//!   emoji before them
+
+/// Line breaking classes of characters, as described in UAX #14
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Class {
+  /// Mandatory break
+  Bk,
+  /// Carriage return
+  Cr,
+  /// Line feed
+  Lf,
+  /// Next line
+  Nl,
+  /// Space
+  Sp,
+  /// Zero width space
+  Zw,
+  /// Zero width joiner
+  Zwj,
+  /// Word joiner
+  Wj,
+  /// Non-breaking ("glue")
+  Gl,
+  /// Combining mark
+  Cm,
+  /// Opening punctuation
+  Op,
+  /// Closing punctuation
+  Cl,
+  /// Closing parenthesis
+  Cp,
+  /// Ambiguous quotation
+  Qu,
+  /// Exclamation / interrogation
+  Ex,
+  /// Infix numeric separator
+  Is,
+  /// Symbols allowing a break after
+  Sy,
+  /// Non-starter
+  Ns,
+  /// Hyphen
+  Hy,
+  /// Break after
+  Ba,
+  /// Break before
+  Bb,
+  /// Break opportunity before & after
+  B2,
+  /// Inseparable
+  In,
+  /// Ideographic
+  Id,
+  /// Alphabetic
+  Al,
+  /// Numeric
+  Nu,
+  /// Prefix numeric
+  Pr,
+  /// Postfix numeric
+  Po,
+  /// Regional indicator
+  Ri,
+  /// Hangul LV syllable
+  H2,
+  /// Hangul LVT syllable
+  H3,
+  /// Hangul leading jamo
+  Jl,
+  /// Hangul vowel jamo
+  Jv,
+  /// Hangul trailing jamo
+  Jt,
+}
+
+use Class::*;
+
+/// Indices of the characters of some text that a line may be broken before.
+/// Newlines aren't included, as lines always end at them anyway
+pub fn break_opportunities(text: &str) -> Vec<usize> {
+  let mut breaks = Vec::new();
+
+  // The class of the character before the current one, where combining
+  // marks take the class of the character they're attached to
+  let mut before: Option<Class> = None;
+  // The class of the last character that isn't a space
+  let mut before_spaces: Option<Class> = None;
+  let mut after_zwj = false;
+  let mut regional_indicators = 0;
+
+  for (index, character) in text.chars().enumerate() {
+    let mut class = line_break_class(character);
+
+    let previous = match before {
+      Some(previous) => previous,
+      // Never at the start of the text
+      None => {
+        class = resolve_combining(class);
+        before = Some(class);
+        before_spaces = Some(class).filter(|class| *class != Sp);
+        after_zwj = character == '\u{200D}';
+        regional_indicators = usize::from(class == Ri);
+        continue;
+      }
+    };
+
+    // Combining marks & joiners stay with what they're attached to
+    let is_attached = matches!(class, Cm | Zwj)
+      && !matches!(previous, Bk | Cr | Lf | Nl | Sp | Zw);
+    if !is_attached {
+      class = resolve_combining(class);
+    }
+
+    let can_break = !is_attached
+      && !after_zwj
+      && can_break_between(
+        previous,
+        before_spaces,
+        class,
+        is_wide(character),
+        regional_indicators,
+      );
+    if can_break && !matches!(previous, Bk | Cr | Lf | Nl) {
+      breaks.push(index);
+    }
+
+    after_zwj = character == '\u{200D}';
+    if !is_attached {
+      regional_indicators = if class == Ri {
+        regional_indicators + 1
+      } else {
+        0
+      };
+      before = Some(class);
+      if class != Sp {
+        before_spaces = Some(class);
+      }
+    }
+  }
+
+  breaks
+}
+
+/// Combining marks that aren't attached to anything are treated as
+/// alphabetic
+fn resolve_combining(class: Class) -> Class {
+  match class {
+    Cm | Zwj => Al,
+    class => class,
+  }
+}
+
+/// Whether a line can be broken between a character of class `before` and
+/// one of class `after`. `before_spaces` is the class of the last character
+/// that isn't a space, and `regional_indicators` the number of regional
+/// indicators in a row that end at `before`
+fn can_break_between(
+  before: Class,
+  before_spaces: Option<Class>,
+  after: Class,
+  after_is_wide: bool,
+  regional_indicators: usize,
+) -> bool {
+  // Mandatory breaks, which egui handles itself
+  if matches!(before, Bk | Lf | Nl) || (before == Cr && after != Lf) {
+    return true;
+  }
+  if matches!(after, Bk | Cr | Lf | Nl | Sp | Zw) {
+    return false;
+  }
+  if before_spaces == Some(Zw) {
+    return true;
+  }
+  if before == Wj || after == Wj || before == Gl {
+    return false;
+  }
+  if after == Gl && !matches!(before, Sp | Ba | Hy) {
+    return false;
+  }
+  if matches!(after, Cl | Cp | Ex | Is | Sy) {
+    return false;
+  }
+
+  // Rules that apply across spaces
+  match (before_spaces, after) {
+    (Some(Op), _) | (Some(Qu), Op) | (Some(Cl | Cp), Ns) | (Some(B2), B2) => {
+      return false
+    }
+    _ => {}
+  }
+  if before == Sp {
+    return true;
+  }
+
+  if before == Qu || after == Qu {
+    return false;
+  }
+  if matches!(after, Ba | Hy | Ns | In) || before == Bb {
+    return false;
+  }
+
+  let joined = matches!(
+    (before, after),
+    // Letters & numbers
+    (Al, Nu | Al | Pr | Po)
+      | (Nu, Al | Nu | Po | Pr)
+      | (Is, Al | Nu)
+      | (Sy | Hy, Nu)
+      // Prefixes & postfixes of numbers and ideographs
+      | (Pr, Id | Al | Nu | Op | Jl | Jv | Jt | H2 | H3)
+      | (Po, Al | Nu | Op)
+      | (Id, Po)
+      | (Cl | Cp, Po | Pr)
+      // Hangul syllables
+      | (Jl, Jl | Jv | H2 | H3)
+      | (Jv | H2, Jv | Jt)
+      | (Jt | H3, Jt)
+      | (Jl | Jv | Jt | H2 | H3, Po)
+      // Parentheses around letters & numbers
+      | (Cp, Al | Nu)
+  );
+  if joined {
+    return false;
+  }
+  // Only opening punctuation that isn't as wide as an ideograph (e.g. `(`
+  // but not `「`) stays with the letters before it
+  if matches!(before, Al | Nu) && after == Op && !after_is_wide {
+    return false;
+  }
+
+  // Flags are pairs of regional indicators
+  if before == Ri && after == Ri && regional_indicators % 2 == 1 {
+    return false;
+  }
+
+  true
+}
+
+/// Whether a character is as wide as an ideograph, going by the blocks of
+/// CJK characters & fullwidth forms
+fn is_wide(character: char) -> bool {
+  matches!(character, '\u{2E80}'..='\u{A4CF}' | '\u{F900}'..='\u{FFEF}')
+}
+
+/// The line breaking class of a character
+fn line_break_class(character: char) -> Class {
+  let code = character as u32;
+
+  match character {
+    '\n' => Lf,
+    '\r' => Cr,
+    '\u{0B}' | '\u{0C}' | '\u{2028}' | '\u{2029}' => Bk,
+    '\u{85}' => Nl,
+    ' ' => Sp,
+    '\u{200B}' => Zw,
+    '\u{200D}' => Zwj,
+    '\u{2060}' | '\u{FEFF}' => Wj,
+    '\u{A0}' | '\u{202F}' | '\u{2007}' | '\u{2011}' | '\u{0F0C}' => Gl,
+    '\t'
+    | '|'
+    | '\u{AD}'
+    | '\u{058A}'
+    | '\u{1680}'
+    | '\u{2000}'..='\u{2006}'
+    | '\u{2008}'..='\u{200A}'
+    | '\u{2010}'
+    | '\u{2012}'
+    | '\u{2013}'
+    | '\u{2027}'
+    | '\u{205F}'
+    | '\u{3000}'
+    | '\u{0F0B}' => Ba,
+    '\u{2014}' | '\u{2E3A}' | '\u{2E3B}' => B2,
+    '\u{B4}' | '\u{02C8}' | '\u{02CC}' | '\u{02DF}' => Bb,
+    '-' => Hy,
+    '\u{2024}'..='\u{2026}' | '\u{22EF}' | '\u{FE19}' => In,
+
+    '(' | '[' | '{' | '\u{A1}' | '\u{BF}' | '\u{201A}' | '\u{201E}'
+    | '\u{2045}' | '\u{207D}' | '\u{208D}' | '\u{2329}' | '\u{2768}'
+    | '\u{276A}' | '\u{276C}' | '\u{276E}' | '\u{2770}' | '\u{2772}'
+    | '\u{2774}' | '\u{3008}' | '\u{300A}' | '\u{300C}' | '\u{300E}'
+    | '\u{3010}' | '\u{3014}' | '\u{3016}' | '\u{3018}' | '\u{301A}'
+    | '\u{301D}' | '\u{FE59}' | '\u{FE5B}' | '\u{FE5D}' | '\u{FF08}'
+    | '\u{FF3B}' | '\u{FF5B}' | '\u{FF5F}' | '\u{FF62}' => Op,
+    ')' | ']' | '\u{FF09}' | '\u{FF3D}' => Cp,
+    '}' | '\u{2046}' | '\u{207E}' | '\u{208E}' | '\u{232A}' | '\u{2769}'
+    | '\u{276B}' | '\u{276D}' | '\u{276F}' | '\u{2771}' | '\u{2773}'
+    | '\u{2775}' | '\u{3001}' | '\u{3002}' | '\u{3009}' | '\u{300B}'
+    | '\u{300D}' | '\u{300F}' | '\u{3011}' | '\u{3015}' | '\u{3017}'
+    | '\u{3019}' | '\u{301B}' | '\u{301E}' | '\u{301F}' | '\u{FE50}'
+    | '\u{FE52}' | '\u{FE5A}' | '\u{FE5C}' | '\u{FE5E}' | '\u{FF0C}'
+    | '\u{FF0E}' | '\u{FF5D}' | '\u{FF60}' | '\u{FF61}' | '\u{FF63}'
+    | '\u{FF64}' => Cl,
+    '"'
+    | '\''
+    | '\u{AB}'
+    | '\u{BB}'
+    | '\u{2018}'
+    | '\u{2019}'
+    | '\u{201B}'..='\u{201D}'
+    | '\u{201F}'
+    | '\u{2039}'
+    | '\u{203A}'
+    | '\u{275B}'..='\u{2760}'
+    | '\u{2E00}'..='\u{2E0D}' => Qu,
+    '!'
+    | '?'
+    | '\u{05C6}'
+    | '\u{061B}'
+    | '\u{061E}'
+    | '\u{061F}'
+    | '\u{06D4}'
+    | '\u{07F9}'
+    | '\u{0F0D}'..='\u{0F11}'
+    | '\u{1802}'
+    | '\u{1803}'
+    | '\u{1808}'
+    | '\u{1809}'
+    | '\u{2762}'
+    | '\u{2763}'
+    | '\u{FE15}'
+    | '\u{FE16}'
+    | '\u{FE56}'
+    | '\u{FE57}'
+    | '\u{FF01}'
+    | '\u{FF1F}' => Ex,
+    ',' | '.' | ':' | ';' | '\u{037E}' | '\u{0589}' | '\u{060C}'
+    | '\u{060D}' | '\u{07F8}' | '\u{2044}' | '\u{FE10}' | '\u{FE13}'
+    | '\u{FE14}' => Is,
+    '/' => Sy,
+    '\u{17D6}'
+    | '\u{203C}'
+    | '\u{203D}'
+    | '\u{2047}'..='\u{2049}'
+    | '\u{3005}'
+    | '\u{301C}'
+    | '\u{303B}'
+    | '\u{303C}'
+    | '\u{309B}'..='\u{309E}'
+    | '\u{30A0}'
+    | '\u{30FB}'
+    | '\u{30FD}'
+    | '\u{30FE}'
+    | '\u{A015}'
+    | '\u{FE54}'
+    | '\u{FE55}'
+    | '\u{FF1A}'
+    | '\u{FF1B}'
+    | '\u{FF65}'
+    | '\u{FF9E}'
+    | '\u{FF9F}' => Ns,
+    // Small kana & the prolonged sound mark
+    '\u{3041}'
+    | '\u{3043}'
+    | '\u{3045}'
+    | '\u{3047}'
+    | '\u{3049}'
+    | '\u{3063}'
+    | '\u{3083}'
+    | '\u{3085}'
+    | '\u{3087}'
+    | '\u{308E}'
+    | '\u{3095}'
+    | '\u{3096}'
+    | '\u{30A1}'
+    | '\u{30A3}'
+    | '\u{30A5}'
+    | '\u{30A7}'
+    | '\u{30A9}'
+    | '\u{30C3}'
+    | '\u{30E3}'
+    | '\u{30E5}'
+    | '\u{30E7}'
+    | '\u{30EE}'
+    | '\u{30F5}'
+    | '\u{30F6}'
+    | '\u{30FC}'
+    | '\u{31F0}'..='\u{31FF}'
+    | '\u{FF67}'..='\u{FF70}' => Ns,
+    '$'
+    | '+'
+    | '\\'
+    | '\u{A3}'
+    | '\u{A5}'
+    | '\u{B1}'
+    | '\u{20A0}'..='\u{20CF}'
+    | '\u{2116}'
+    | '\u{2212}'
+    | '\u{2213}'
+    | '\u{FE69}'
+    | '\u{FF04}'
+    | '\u{FFE1}'
+    | '\u{FFE5}'
+    | '\u{FFE6}' => Pr,
+    '%'
+    | '\u{A2}'
+    | '\u{B0}'
+    | '\u{060B}'
+    | '\u{066A}'
+    | '\u{2030}'..='\u{2037}'
+    | '\u{2103}'
+    | '\u{2109}'
+    | '\u{FE6A}'
+    | '\u{FF05}'
+    | '\u{FFE0}' => Po,
+    '0'..='9' => Nu,
+
+    // Controls & combining marks
+    '\u{00}'..='\u{1F}' | '\u{7F}'..='\u{9F}' => Cm,
+    '\u{0300}'..='\u{036F}'
+    | '\u{0483}'..='\u{0489}'
+    | '\u{0591}'..='\u{05BD}'
+    | '\u{05BF}'
+    | '\u{05C1}'
+    | '\u{05C2}'
+    | '\u{05C4}'
+    | '\u{05C5}'
+    | '\u{05C7}'
+    | '\u{0610}'..='\u{061A}'
+    | '\u{064B}'..='\u{065F}'
+    | '\u{0670}'
+    | '\u{06D6}'..='\u{06DC}'
+    | '\u{06DF}'..='\u{06E4}'
+    | '\u{06E7}'
+    | '\u{06E8}'
+    | '\u{06EA}'..='\u{06ED}'
+    | '\u{0E31}'
+    | '\u{0E34}'..='\u{0E3A}'
+    | '\u{0E47}'..='\u{0E4E}'
+    | '\u{1AB0}'..='\u{1AFF}'
+    | '\u{1DC0}'..='\u{1DFF}'
+    | '\u{20D0}'..='\u{20FF}'
+    | '\u{302A}'..='\u{302F}'
+    | '\u{3099}'
+    | '\u{309A}'
+    | '\u{FE00}'..='\u{FE0F}'
+    | '\u{FE20}'..='\u{FE2F}'
+    | '\u{1F3FB}'..='\u{1F3FF}'
+    | '\u{E0020}'..='\u{E007F}'
+    | '\u{E0100}'..='\u{E01EF}' => Cm,
+    // Brahmic scripts (Devanagari to Malayalam) share a layout, with their
+    // vowel signs & other marks at the same offsets within each block
+    '\u{0900}'..='\u{0D7F}' => match code % 0x80 {
+      0x00..=0x03 | 0x3C | 0x3E..=0x4F | 0x51..=0x57 | 0x62 | 0x63 => Cm,
+      0x66..=0x6F => Nu,
+      _ => Al,
+    },
+    '\u{0660}'..='\u{0669}'
+    | '\u{06F0}'..='\u{06F9}'
+    | '\u{0E50}'..='\u{0E59}'
+    | '\u{0ED0}'..='\u{0ED9}'
+    | '\u{0F20}'..='\u{0F29}'
+    | '\u{1040}'..='\u{1049}'
+    | '\u{17E0}'..='\u{17E9}'
+    | '\u{1810}'..='\u{1819}' => Nu,
+
+    // Hangul
+    '\u{1100}'..='\u{115F}' | '\u{A960}'..='\u{A97C}' => Jl,
+    '\u{1160}'..='\u{11A7}' | '\u{D7B0}'..='\u{D7C6}' => Jv,
+    '\u{11A8}'..='\u{11FF}' | '\u{D7CB}'..='\u{D7FB}' => Jt,
+    '\u{AC00}'..='\u{D7A3}' if (code - 0xAC00) % 28 == 0 => H2,
+    '\u{AC00}'..='\u{D7A3}' => H3,
+
+    '\u{1F1E6}'..='\u{1F1FF}' => Ri,
+
+    // Ideographs, kana, fullwidth forms & emoji
+    '\u{231A}'
+    | '\u{231B}'
+    | '\u{23F0}'..='\u{23F3}'
+    | '\u{2600}'..='\u{2767}'
+    | '\u{2794}'..='\u{27BF}'
+    | '\u{2E80}'..='\u{2FFF}'
+    | '\u{3003}'..='\u{3004}'
+    | '\u{3006}'..='\u{3007}'
+    | '\u{3012}'..='\u{3013}'
+    | '\u{3020}'..='\u{3029}'
+    | '\u{3030}'..='\u{303A}'
+    | '\u{303D}'..='\u{303F}'
+    | '\u{3040}'..='\u{309F}'
+    | '\u{30A0}'..='\u{30FF}'
+    | '\u{3100}'..='\u{31EF}'
+    | '\u{3200}'..='\u{4DBF}'
+    | '\u{4E00}'..='\u{9FFF}'
+    | '\u{A000}'..='\u{A4CF}'
+    | '\u{F900}'..='\u{FAFF}'
+    | '\u{FE30}'..='\u{FE4F}'
+    | '\u{FF00}'..='\u{FF5F}'
+    | '\u{FFE0}'..='\u{FFE6}'
+    | '\u{1B000}'..='\u{1B2FF}'
+    | '\u{1F000}'..='\u{1FAFF}'
+    | '\u{20000}'..='\u{3FFFD}' => Id,
+
+    _ => Al,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The text with a `|` at each place a line may be broken
+  fn marked(text: &str) -> String {
+    let breaks = break_opportunities(text);
+    let mut output = String::new();
+
+    for (index, character) in text.chars().enumerate() {
+      if breaks.contains(&index) {
+        output.push('|');
+      }
+      output.push(character);
+    }
+    output
+  }
+
+  #[test]
+  fn ideographs_and_kana_break_between_characters() {
+    assert_eq!(
+      marked("吾輩は猫である。名前はまだ無い。"),
+      "吾|輩|は|猫|で|あ|る。|名|前|は|ま|だ|無|い。"
+    );
+    assert_eq!(
+      marked("中文（测试）文本，很好。"),
+      "中|文|（测|试）|文|本，|很|好。"
+    );
+  }
+
+  #[test]
+  fn small_kana_and_prolonged_sounds_never_start_lines() {
+    assert_eq!(
+      marked("ちょっと待って…キャーッ！"),
+      "ちょっ|と|待っ|て…|キャーッ！"
+    );
+  }
+
+  #[test]
+  fn words_break_after_spaces_and_hyphens() {
+    assert_eq!(
+      marked("Hello world, … $100.50 and 50% \"quotes\"."),
+      "Hello |world, |… |$100.50 |and |50% |\"quotes\"."
+    );
+    assert_eq!(
+      marked("co-operate state\u{2011}of\u{2011}the\u{2011}art"),
+      "co-|operate |state\u{2011}of\u{2011}the\u{2011}art"
+    );
+    assert_eq!(marked("English 混合 text"), "English |混|合 |text");
+  }
+
+  #[test]
+  fn emoji_sequences_stay_together() {
+    assert_eq!(
+      marked("emoji 👍🏽 flags 🇯🇵🇺🇸 ok — dash"),
+      "emoji |👍🏽 |flags |🇯🇵|🇺🇸 |ok |— |dash"
+    );
+  }
+
+  #[test]
+  fn zero_width_characters_and_newlines() {
+    assert_eq!(marked("a\u{200B}b\u{2060}c"), "a\u{200B}|b\u{2060}c");
+    // Lines end at newlines anyway, so they aren't break opportunities
+    assert_eq!(break_opportunities("one\ntwo  three"), [9]);
+    assert!(break_opportunities("").is_empty());
+  }
+}
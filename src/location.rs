@@ -0,0 +1,288 @@
+//! Positions within a book that stay valid when the way chapters are split
+//! into blocks changes.
+//!
+//! A position is stored as the path of the innermost block level element it
+//! is in (written like the element steps of an EPUB CFI, e.g. `/2/4/6`), along
+//! with a character offset within the text directly in that element. See
+//! [`crate::document::Block::path`] & [`crate::document::Block::path_offset`]
+
+use std::{cmp::Ordering, ops::Range};
+
+use serde::{Deserialize, Serialize};
+
+use crate::document::Document;
+
+#[derive(
+  Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, Default,
+)]
+pub struct TextPosition {
+  pub chapter: usize,
+  /// Path of the element containing the position
+  pub path: String,
+  /// Offset in characters within the element's text, not counting that of
+  /// block level elements nested in it
+  pub offset: usize,
+}
+
+impl TextPosition {
+  /// The position of a character within a block of a chapter
+  #[must_use]
+  pub fn new(
+    chapter: usize,
+    document: &Document,
+    block: usize,
+    char_index: usize,
+  ) -> Self {
+    match document.blocks.get(block) {
+      Some(block) => Self {
+        chapter,
+        path: block.path.clone(),
+        offset: block.path_offset + char_index,
+      },
+      None => Self {
+        chapter,
+        ..Default::default()
+      },
+    }
+  }
+
+  /// Finds the (block index, character index within the block) the position
+  /// refers to, in the document of its chapter
+  pub fn resolve(&self, document: &Document) -> Option<(usize, usize)> {
+    let (index, block) =
+      document
+        .blocks
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, block)| {
+          block.path == self.path && block.path_offset <= self.offset
+        })?;
+
+    let char_index = (self.offset - block.path_offset).min(block.char_count());
+
+    Some((index, char_index))
+  }
+
+  /// Like [`Self::resolve`], for the (exclusive) end of a range: a position
+  /// at the end of one block that is followed by more text directly in the
+  /// same element is the end of that block, rather than the start of the
+  /// next one (which would take in any nested blocks between them)
+  pub fn resolve_end(&self, document: &Document) -> Option<(usize, usize)> {
+    document
+      .blocks
+      .iter()
+      .enumerate()
+      .find(|(_, block)| {
+        block.path == self.path
+          && (block.path_offset..=block.path_offset + block.char_count())
+            .contains(&self.offset)
+      })
+      .map(|(index, block)| (index, self.offset - block.path_offset))
+      .or_else(|| self.resolve(document))
+  }
+
+  /// The steps of the path as numbers, used for ordering
+  fn steps(&self) -> Vec<usize> {
+    self
+      .path
+      .split('/')
+      .filter_map(|step| step.parse().ok())
+      .collect()
+  }
+}
+
+impl PartialOrd for TextPosition {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+/// Orders positions by chapter, then path, then offset. This is close to the
+/// reading order, though not exact for text that follows a nested element;
+/// use [`TextPosition::resolve`] where the exact order matters
+impl Ord for TextPosition {
+  fn cmp(&self, other: &Self) -> Ordering {
+    self
+      .chapter
+      .cmp(&other.chapter)
+      .then_with(|| self.steps().cmp(&other.steps()))
+      .then_with(|| self.offset.cmp(&other.offset))
+  }
+}
+
+/// A range of text, which may span several blocks (or chapters)
+#[derive(
+  Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, Default,
+)]
+pub struct TextRange {
+  pub start: TextPosition,
+  /// Exclusive
+  pub end: TextPosition,
+}
+
+impl TextRange {
+  /// The range covering the whole of a block
+  #[must_use]
+  pub fn block(chapter: usize, document: &Document, block: usize) -> Self {
+    let length = document.blocks.get(block).map_or(0, |b| b.char_count());
+
+    Self {
+      start: TextPosition::new(chapter, document, block, 0),
+      end: TextPosition::new(chapter, document, block, length),
+    }
+  }
+
+  /// The character ranges of a chapter's blocks covered by this range, as
+  /// (block index, character range within the block)
+  pub fn block_ranges(
+    &self,
+    chapter: usize,
+    document: &Document,
+  ) -> Vec<(usize, Range<usize>)> {
+    let start = match self.start.chapter.cmp(&chapter) {
+      Ordering::Less => (0, 0),
+      Ordering::Equal => match self.start.resolve(document) {
+        Some(start) => start,
+        None => return Vec::new(),
+      },
+      Ordering::Greater => return Vec::new(),
+    };
+    let end = match self.end.chapter.cmp(&chapter) {
+      Ordering::Greater => (usize::MAX, 0),
+      Ordering::Equal => match self.end.resolve_end(document) {
+        Some(end) => end,
+        None => return Vec::new(),
+      },
+      Ordering::Less => return Vec::new(),
+    };
+
+    document
+      .blocks
+      .iter()
+      .enumerate()
+      .filter(|(index, _)| (start.0..=end.0).contains(index))
+      .map(|(index, block)| {
+        let from = if index == start.0 { start.1 } else { 0 };
+        let to = if index == end.0 {
+          end.1
+        } else {
+          block.char_count()
+        };
+
+        (index, from..to.max(from))
+      })
+      .filter(|(_, range)| !range.is_empty())
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Blocks: "First" (`/2/2`), "Outer" (`/2/4`), "Nested" (`/2/4/2`) and
+  /// "tail" (`/2/4`, after "Outer")
+  fn document() -> Document {
+    Document::from_xhtml(
+      "<body><p>First</p><div>Outer<p>Nested</p>tail</div></body>",
+    )
+  }
+
+  fn position(chapter: usize, path: &str, offset: usize) -> TextPosition {
+    TextPosition {
+      chapter,
+      path: path.to_string(),
+      offset,
+    }
+  }
+
+  #[test]
+  fn positions_resolve_to_the_block_they_were_made_in() {
+    let document = document();
+
+    assert_eq!(
+      TextPosition::new(3, &document, 3, 2),
+      position(3, "/2/4", 7)
+    );
+    for (block, char_index) in [(0, 0), (0, 4), (1, 2), (2, 3), (3, 0), (3, 2)]
+    {
+      let position = TextPosition::new(0, &document, block, char_index);
+      assert_eq!(position.resolve(&document), Some((block, char_index)));
+    }
+  }
+
+  #[test]
+  fn positions_that_no_longer_fit_are_clamped_or_dropped() {
+    let document = document();
+
+    assert_eq!(position(0, "/2/2", 50).resolve(&document), Some((0, 5)));
+    assert_eq!(position(0, "/2/8", 0).resolve(&document), None);
+    assert_eq!(position(0, "", 0).resolve(&document), None);
+  }
+
+  #[test]
+  fn ranges_are_split_into_the_blocks_they_cover() {
+    let document = document();
+    let range = TextRange {
+      start: TextPosition::new(0, &document, 0, 2),
+      end: TextPosition::new(0, &document, 2, 3),
+    };
+
+    assert_eq!(
+      range.block_ranges(0, &document),
+      [(0, 2..5), (1, 0..5), (2, 0..3)]
+    );
+    assert_eq!(
+      TextRange::block(0, &document, 2).block_ranges(0, &document),
+      [(2, 0..6)]
+    );
+  }
+
+  #[test]
+  fn ranges_ending_before_a_nested_block_leave_it_out() {
+    let document = document();
+    let range = TextRange {
+      start: TextPosition::new(0, &document, 0, 2),
+      end: TextPosition::new(0, &document, 1, 5),
+    };
+
+    assert_eq!(range.end.resolve_end(&document), Some((1, 5)));
+    assert_eq!(range.end.resolve(&document), Some((3, 0)));
+    assert_eq!(range.block_ranges(0, &document), [(0, 2..5), (1, 0..5)]);
+    assert_eq!(
+      TextRange::block(0, &document, 1).block_ranges(0, &document),
+      [(1, 0..5)]
+    );
+  }
+
+  #[test]
+  fn ranges_can_cross_chapters() {
+    let document = document();
+    let range = TextRange {
+      start: TextPosition::new(0, &document, 3, 1),
+      end: TextPosition::new(2, &document, 0, 3),
+    };
+
+    assert_eq!(range.block_ranges(0, &document), [(3, 1..4)]);
+    assert_eq!(
+      range.block_ranges(1, &document),
+      [(0, 0..5), (1, 0..5), (2, 0..6), (3, 0..4)]
+    );
+    assert_eq!(range.block_ranges(2, &document), [(0, 0..3)]);
+    assert!(range.block_ranges(3, &document).is_empty());
+  }
+
+  #[test]
+  fn ranges_with_ends_that_dont_resolve_cover_nothing() {
+    let document = document();
+    let range = TextRange {
+      start: position(0, "/2/8", 0),
+      end: position(2, "/2/2", 1),
+    };
+
+    assert!(range.block_ranges(0, &document).is_empty());
+    assert_eq!(range.block_ranges(1, &document).len(), 4);
+    assert_eq!(range.block_ranges(2, &document), [(0, 0..1)]);
+  }
+}
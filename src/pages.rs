@@ -0,0 +1,220 @@
+//! Page numbers that don't depend on how the text is laid out, so they can be
+//! cited: either the print page numbers listed by the book, or synthetic ones
+//! counted every [`SYNTHETIC_PAGE_LENGTH`] characters
+
+use std::{collections::HashMap, io::Cursor, ops::Range};
+
+use epub::doc::EpubDoc;
+
+use crate::{
+  backend::{cached_chapter, chapter_document, CachedChapter},
+  document::Document,
+  location::TextPosition,
+  progress::book_offset,
+  toc::load_page_list,
+};
+
+/// Characters of text in a synthetic page
+pub const SYNTHETIC_PAGE_LENGTH: usize = 1500;
+
+/// The page numbers of a book
+#[derive(Debug, Clone)]
+pub enum PageNumbers {
+  /// Pages from the book's page list, as (label, characters into the book the
+  /// page starts at) in reading order
+  Print(Vec<(String, usize)>),
+  /// A page every [`SYNTHETIC_PAGE_LENGTH`] characters of a book with this
+  /// many characters in total
+  Synthetic(usize),
+}
+
+impl PageNumbers {
+  /// Reads the page list of a book, falling back to synthetic pages if it
+  /// doesn't have one (or none of its pages can be found in the text)
+  pub fn load(book: &mut EpubDoc<Cursor<Vec<u8>>>, lengths: &[usize]) -> Self {
+    let mut documents: HashMap<usize, Option<Document>> = HashMap::new();
+
+    let mut pages: Vec<(String, usize)> = load_page_list(book)
+      .into_iter()
+      .filter_map(|entry| {
+        let chapter = entry.chapter?;
+        let document = documents
+          .entry(chapter)
+          .or_insert_with(|| chapter_document(book, chapter))
+          .as_ref()?;
+        let block = match &entry.anchor {
+          Some(anchor) => document.anchor_block(anchor)?,
+          None => 0,
+        };
+
+        Some((
+          entry.label,
+          book_offset(lengths, chapter, document.char_offset(block, 0)),
+        ))
+      })
+      .collect();
+
+    if pages.is_empty() {
+      Self::Synthetic(lengths.iter().sum())
+    } else {
+      pages.sort_by_key(|(_, start)| *start);
+      Self::Print(pages)
+    }
+  }
+
+  /// Label of the page a number of characters into the book is on. Text
+  /// before the first print page (e.g. the cover) is on no page
+  pub fn label_at(&self, offset: usize) -> Option<String> {
+    match self {
+      Self::Print(pages) => pages
+        .iter()
+        .rev()
+        .find(|(_, start)| *start <= offset)
+        .map(|(label, _)| label.clone()),
+      Self::Synthetic(_) => {
+        Some((offset / SYNTHETIC_PAGE_LENGTH + 1).to_string())
+      }
+    }
+  }
+
+  /// The pages starting within a range of characters into the book, as
+  /// (characters into the book, label)
+  pub fn starts_in(&self, range: Range<usize>) -> Vec<(usize, String)> {
+    match self {
+      Self::Print(pages) => pages
+        .iter()
+        .filter(|(_, start)| range.contains(start))
+        .map(|(label, start)| (*start, label.clone()))
+        .collect(),
+      Self::Synthetic(_) => {
+        let first_page =
+          (range.start + SYNTHETIC_PAGE_LENGTH - 1) / SYNTHETIC_PAGE_LENGTH;
+
+        (first_page..)
+          .map(|page| page * SYNTHETIC_PAGE_LENGTH)
+          .take_while(|start| *start < range.end)
+          .map(|start| (start, (start / SYNTHETIC_PAGE_LENGTH + 1).to_string()))
+          .collect()
+      }
+    }
+  }
+
+  /// Number of characters into the book the page with a label starts at
+  pub fn find(&self, label: &str) -> Option<usize> {
+    let label = label.trim();
+
+    match self {
+      Self::Print(pages) => pages
+        .iter()
+        .find(|(page, _)| page.eq_ignore_ascii_case(label))
+        .map(|(_, start)| *start),
+      Self::Synthetic(total) => label
+        .parse::<usize>()
+        .ok()
+        .filter(|page| *page > 0)
+        .map(|page| (page - 1) * SYNTHETIC_PAGE_LENGTH)
+        .filter(|start| start < total),
+    }
+  }
+
+  /// Whether the pages are the book's own print page numbers
+  pub fn is_print(&self) -> bool {
+    matches!(self, Self::Print(_))
+  }
+}
+
+/// Label of the page a position within a book is on. `user_css` is the
+/// reader's CSS for the book, which its chapters are cached with
+pub fn position_page(
+  position: &TextPosition,
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  chapters: &mut HashMap<usize, CachedChapter>,
+  user_css: &str,
+  lengths: &[usize],
+  pages: &PageNumbers,
+) -> Option<String> {
+  let document =
+    &cached_chapter(chapters, book, position.chapter, user_css)?.document;
+  let (block, char_index) = position.resolve(document)?;
+
+  pages.label_at(book_offset(
+    lengths,
+    position.chapter,
+    document.char_offset(block, char_index),
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn print() -> PageNumbers {
+    PageNumbers::Print(vec![
+      ("i".to_string(), 100),
+      ("1".to_string(), 2000),
+      ("2".to_string(), 3500),
+    ])
+  }
+
+  #[test]
+  fn synthetic_pages_are_counted_from_the_start_of_the_book() {
+    let pages = PageNumbers::Synthetic(4000);
+
+    assert_eq!(pages.label_at(0).as_deref(), Some("1"));
+    assert_eq!(pages.label_at(1499).as_deref(), Some("1"));
+    assert_eq!(pages.label_at(1500).as_deref(), Some("2"));
+    assert_eq!(pages.label_at(3999).as_deref(), Some("3"));
+    assert!(!pages.is_print());
+  }
+
+  #[test]
+  fn synthetic_pages_start_every_page_length() {
+    let pages = PageNumbers::Synthetic(4000);
+    let starts = |range| pages.starts_in(range);
+
+    assert_eq!(starts(0..1), [(0, "1".to_string())]);
+    assert!(starts(1..1500).is_empty());
+    assert_eq!(
+      starts(1..3001),
+      [(1500, "2".to_string()), (3000, "3".to_string())]
+    );
+    assert_eq!(starts(1500..1501), [(1500, "2".to_string())]);
+    assert!(starts(10..10).is_empty());
+  }
+
+  #[test]
+  fn synthetic_pages_are_found_by_number() {
+    let pages = PageNumbers::Synthetic(4000);
+
+    assert_eq!(pages.find("1"), Some(0));
+    assert_eq!(pages.find(" 3 "), Some(3000));
+    assert_eq!(pages.find("4"), None);
+    assert_eq!(pages.find("0"), None);
+    assert_eq!(pages.find("iv"), None);
+  }
+
+  #[test]
+  fn print_pages_come_from_the_page_list() {
+    let pages = print();
+
+    assert_eq!(pages.label_at(0), None);
+    assert_eq!(pages.label_at(100).as_deref(), Some("i"));
+    assert_eq!(pages.label_at(3499).as_deref(), Some("1"));
+    assert_eq!(pages.label_at(10_000).as_deref(), Some("2"));
+    assert_eq!(
+      pages.starts_in(0..2001),
+      [(100, "i".to_string()), (2000, "1".to_string())]
+    );
+    assert!(pages.starts_in(101..2000).is_empty());
+    assert!(pages.is_print());
+  }
+
+  #[test]
+  fn print_pages_are_found_by_label() {
+    let pages = print();
+
+    assert_eq!(pages.find("I"), Some(100));
+    assert_eq!(pages.find(" 2"), Some(3500));
+    assert_eq!(pages.find("3"), None);
+  }
+}
@@ -0,0 +1,105 @@
+use egui::{ScrollArea, TextEdit};
+
+use crate::{
+  backend::chapter_lengths,
+  pages::{position_page, PageNumbers},
+  ui::GotoTarget,
+};
+
+pub fn ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
+  if let Some(uuid) = &state.selected_book_uuid {
+    if let Some(book_info) = state.book_userdata.get_mut(uuid) {
+      let user_css = state.book_style.for_book(book_info).user_css;
+      let bookmarks = &mut book_info.bookmarks;
+
+      if bookmarks.is_empty() {
+        ui.label("No bookmarks. Add one with the Bookmark button or Ctrl+D.");
+        return;
+      }
+
+      bookmarks.sort_by(|a, b| a.position.cmp(&b.position));
+
+      // Page each bookmark is on
+      let pages: Vec<Option<String>> = match state.epub_cache.get_mut(uuid) {
+        Some(book) => {
+          let lengths = state
+            .chapter_lengths
+            .entry(uuid.clone())
+            .or_insert_with(|| chapter_lengths(book));
+          let page_numbers = state
+            .page_numbers
+            .entry(uuid.clone())
+            .or_insert_with(|| PageNumbers::load(book, lengths));
+          let chapters = state.chapter_cache.entry(uuid.clone()).or_default();
+
+          bookmarks
+            .iter()
+            .map(|bookmark| {
+              position_page(
+                &bookmark.position,
+                book,
+                chapters,
+                &user_css,
+                lengths,
+                page_numbers,
+              )
+            })
+            .collect()
+        }
+        None => Vec::new(),
+      };
+
+      let mut to_delete = None;
+
+      ScrollArea::vertical()
+        .auto_shrink([false, false])
+        .show(ui, |ui| {
+          for (index, bookmark) in bookmarks.iter_mut().enumerate() {
+            let place = match pages.get(index) {
+              Some(Some(page)) => format!(
+                "Ch. {}, p. {}, {:.0}%",
+                bookmark.position.chapter,
+                page,
+                bookmark.progress * 100.0
+              ),
+              _ => format!(
+                "Ch. {}, {:.0}%",
+                bookmark.position.chapter,
+                bookmark.progress * 100.0
+              ),
+            };
+
+            ui.horizontal(|ui| {
+              TextEdit::singleline(&mut bookmark.label)
+                .hint_text(&place)
+                .desired_width(ui.available_width() / 2.0)
+                .show(ui);
+
+              if ui.button("Go to").clicked() {
+                state.goto_target =
+                  Some(GotoTarget::position(bookmark.position.clone()));
+              }
+              if ui.button("Remove").clicked() {
+                to_delete = Some(index);
+              }
+            });
+            if !bookmark.label.is_empty() {
+              ui.weak(&place);
+            }
+            if !bookmark.excerpt.is_empty() {
+              ui.label(format!("\"{}...\"", bookmark.excerpt));
+            }
+            ui.separator();
+          }
+        });
+
+      if let Some(index) = to_delete {
+        bookmarks.remove(index);
+      }
+    } else {
+      ui.label("No bookmarks detected.");
+    }
+  } else {
+    ui.label("No Book Selected");
+  }
+}
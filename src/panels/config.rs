@@ -1,13 +1,21 @@
-use std::sync::Arc;
-
-use egui::{ComboBox, FontFamily, TextEdit};
+use egui::{ComboBox, FontFamily, RichText, TextEdit};
 
 use crate::{
   backend::load_directory,
-  ui::{BookTextStyle, DocumentColors},
+  fonts::{add_dropped_font, family_name, FallbackChain, UserFont},
+  pages::SYNTHETIC_PAGE_LENGTH,
+  ui::{BookTextStyle, DocumentColors, ReadingMode},
 };
 
+#[cfg(not(target_arch = "wasm32"))]
+use crate::fonts::load_font_directory;
+
 pub fn ui(state: &mut crate::app::Pend, ui: &mut egui::Ui) {
+  // Fonts dropped onto the panel are added to those that can be used
+  for file in &ui.ctx().input().raw.dropped_files {
+    add_dropped_font(state, file);
+  }
+
   ui.collapsing("Program", |ui| {
     // Path to directory containing books
     #[cfg(not(target_arch = "wasm32"))]
@@ -28,6 +36,12 @@ pub fn ui(state: &mut crate::app::Pend, ui: &mut egui::Ui) {
     if ui.button("Force Clear Library").clicked() {
       state.shelves.clear();
       state.book_covers.clear();
+      state.book_images.clear();
+      state.chapter_cache.clear();
+      state.chapter_lengths.clear();
+      state.book_tocs.clear();
+      state.page_numbers.clear();
+      state.library_hits.clear();
       state.selected_book_uuid = None;
     }
 
@@ -43,26 +57,201 @@ pub fn ui(state: &mut crate::app::Pend, ui: &mut egui::Ui) {
   });
 
   ui.collapsing("Document", |ui| {
+    // Fonts are only previewed once egui has them, which is a frame after
+    // they're added
+    let available_fonts = ui.fonts().families();
+
     ComboBox::from_label("Font")
-      .selected_text(match &state.book_style.font_family {
-        f if f == &FontFamily::Proportional => "Work Sans",
-        f if f == &FontFamily::Name(Arc::from("Merriweather")) => {
-          "Merriweather"
-        }
-        _ => "Unrecognized Font",
-      })
+      .selected_text(
+        family_name(&state.book_style.font_family, &state.user_fonts)
+          .unwrap_or_else(|| "Unrecognized Font".to_string()),
+      )
       .show_ui(ui, |ui| {
         ui.selectable_value(
           &mut state.book_style.font_family,
           FontFamily::Proportional,
-          "Work Sans",
+          RichText::new("Work Sans").family(FontFamily::Proportional),
         );
         ui.selectable_value(
           &mut state.book_style.font_family,
           FontFamily::Name("Merriweather".into()),
-          "Merriweather",
+          RichText::new("Merriweather")
+            .family(FontFamily::Name("Merriweather".into())),
         );
+
+        for font in &state.user_fonts {
+          let family = font.family();
+          if available_fonts.contains(&family) {
+            ui.selectable_value(
+              &mut state.book_style.font_family,
+              family.clone(),
+              RichText::new(&font.name).family(family),
+            );
+          }
+        }
+      });
+
+    ui.collapsing("Added Fonts", |ui| {
+      #[cfg(not(target_arch = "wasm32"))]
+      ui.horizontal(|ui| {
+        ui.label("Fonts Path:");
+        TextEdit::singleline(&mut state.fonts_path)
+          .hint_text("e.g. ./fonts")
+          .show(ui)
+          .response
+          .on_hover_text_at_pointer(
+            "TTF & OTF files in this folder are loaded on startup. Remove a \
+            font by deleting it from the folder.",
+          );
       });
+      #[cfg(not(target_arch = "wasm32"))]
+      if ui.button("Reload Fonts").clicked() {
+        state.user_fonts = load_font_directory(&state.fonts_path);
+        state.registered_fonts.user_fonts_changed = true;
+      }
+
+      ui.label("Drop TTF or OTF files here to add them.");
+
+      #[cfg(target_arch = "wasm32")]
+      let mut to_remove = None;
+      for font in &state.user_fonts {
+        ui.horizontal(|ui| {
+          let family = font.family();
+          if available_fonts.contains(&family) {
+            ui.label(RichText::new(&font.name).family(family));
+          } else {
+            ui.label(&font.name);
+          }
+
+          #[cfg(target_arch = "wasm32")]
+          if ui.button("Remove").clicked() {
+            to_remove = Some(font.name.clone());
+          }
+        });
+      }
+      #[cfg(target_arch = "wasm32")]
+      if let Some(name) = to_remove {
+        crate::font_storage::remove(&name);
+        state.user_fonts.retain(|font| font.name != name);
+        state.registered_fonts.user_fonts_changed = true;
+      }
+    });
+
+    ui.collapsing("Fallback Fonts", |ui| {
+      ui.label(
+        "Added fonts to draw the characters a font doesn't have with, such \
+        as those of Chinese, Japanese & Korean text.",
+      );
+
+      let user_fonts = &state.user_fonts;
+      let mut changed = false;
+      let mut chain_to_remove = None;
+
+      for (index, chain) in state.font_fallbacks.iter_mut().enumerate() {
+        ui.separator();
+        ui.horizontal(|ui| {
+          ui.label("For:");
+          ComboBox::from_id_source(("fallback_family", index))
+            .selected_text(if chain.family.is_empty() {
+              "Every Font"
+            } else {
+              &chain.family
+            })
+            .show_ui(ui, |ui| {
+              let names = ["", "Work Sans", "Merriweather", "Monospace"]
+                .into_iter()
+                .map(str::to_string)
+                .chain(user_fonts.iter().map(|font| font.name.clone()));
+              for name in names {
+                let label = if name.is_empty() {
+                  "Every Font".to_string()
+                } else {
+                  name.clone()
+                };
+                changed |= ui
+                  .selectable_value(&mut chain.family, name, label)
+                  .changed();
+              }
+            });
+
+          ui.label("Language:");
+          changed |= TextEdit::singleline(&mut chain.language)
+            .hint_text("Any")
+            .desired_width(60.0)
+            .show(ui)
+            .response
+            .on_hover_text(
+              "A language tag, such as ja, zh-Hans or zh-Hant. Text is in \
+                the language its book or chapter says it's in",
+            )
+            .lost_focus();
+
+          if ui.button("Remove").clicked() {
+            chain_to_remove = Some(index);
+          }
+        });
+
+        // Fonts are tried from the top down
+        let mut font_to_raise = None;
+        let mut font_to_remove = None;
+        for (position, name) in chain.fonts.iter().enumerate() {
+          ui.horizontal(|ui| {
+            let family = user_fonts
+              .iter()
+              .find(|font| font.name.eq_ignore_ascii_case(name))
+              .map(UserFont::family);
+            match family {
+              Some(family) if available_fonts.contains(&family) => {
+                ui.label(RichText::new(name).family(family))
+              }
+              Some(_) => ui.label(name),
+              None => ui.label(format!("{} (Not Added)", name)),
+            };
+
+            if position > 0 && ui.button("Move Up").clicked() {
+              font_to_raise = Some(position);
+            }
+            if ui.button("Remove").clicked() {
+              font_to_remove = Some(position);
+            }
+          });
+        }
+        if let Some(position) = font_to_raise {
+          chain.fonts.swap(position - 1, position);
+          changed = true;
+        }
+        if let Some(position) = font_to_remove {
+          chain.fonts.remove(position);
+          changed = true;
+        }
+
+        ComboBox::from_id_source(("fallback_add_font", index))
+          .selected_text("Add Font")
+          .show_ui(ui, |ui| {
+            for font in user_fonts {
+              if !chain.fonts.contains(&font.name)
+                && ui.selectable_label(false, &font.name).clicked()
+              {
+                chain.fonts.push(font.name.clone());
+                changed = true;
+              }
+            }
+          });
+      }
+
+      if let Some(index) = chain_to_remove {
+        state.font_fallbacks.remove(index);
+        changed = true;
+      }
+      ui.separator();
+      if ui.button("Add Fallback Chain").clicked() {
+        state.font_fallbacks.push(FallbackChain::default());
+      }
+
+      if changed {
+        state.registered_fonts.user_fonts_changed = true;
+      }
+    });
 
     ui.horizontal(|ui| {
       ui.label("Text Size: ");
@@ -83,6 +272,65 @@ pub fn ui(state: &mut crate::app::Pend, ui: &mut egui::Ui) {
       );
     });
 
+    ui.checkbox(
+      &mut state.book_style.hide_footnotes,
+      "Hide Footnotes From Text",
+    );
+    ui.checkbox(
+      &mut state.book_style.ignore_publisher_styles,
+      "Ignore Publisher Styles",
+    )
+    .on_hover_text(
+      "Don't use the books' own alignment, indents, margins & font styles. \
+      Custom CSS is still used",
+    );
+
+    ui.checkbox(&mut state.book_style.show_page_numbers, "Show Page Numbers")
+      .on_hover_text(format!(
+        "The book's print page numbers if it lists them, otherwise a page \
+        every {} characters",
+        SYNTHETIC_PAGE_LENGTH
+      ));
+
+    ui.horizontal(|ui| {
+      ui.label("Reading Mode: ");
+      ui.selectable_value(
+        &mut state.book_style.reading_mode,
+        ReadingMode::Scroll,
+        "Scroll",
+      );
+      ui.selectable_value(
+        &mut state.book_style.reading_mode,
+        ReadingMode::Continuous,
+        "Continuous",
+      )
+      .on_hover_text("Scroll from one chapter into the next");
+      ui.selectable_value(
+        &mut state.book_style.reading_mode,
+        ReadingMode::Paginated,
+        "Pages",
+      );
+      ui.selectable_value(
+        &mut state.book_style.reading_mode,
+        ReadingMode::Spread,
+        "Two Pages",
+      );
+    });
+
+    if state.book_style.reading_mode == ReadingMode::Spread {
+      ui.horizontal(|ui| {
+        ui.label("Two Pages From Width: ");
+        ui.add(
+          egui::Slider::new(
+            &mut state.book_style.spread_min_width,
+            600.0..=3000.0,
+          )
+          .step_by(50.0),
+        )
+        .on_hover_text("Narrower windows show a single page");
+      });
+    }
+
     ui.collapsing("Colors", |ui| {
       ui.horizontal(|ui| {
         ui.color_edit_button_srgba(&mut state.theme.highlight_color);
@@ -96,6 +344,18 @@ pub fn ui(state: &mut crate::app::Pend, ui: &mut egui::Ui) {
         ui.color_edit_button_srgba(&mut state.theme.page_color);
         ui.label(": Page Color");
       });
+      ui.horizontal(|ui| {
+        ui.color_edit_button_srgba(&mut state.theme.link_color);
+        ui.label(": Link Color");
+      });
+      ui.horizontal(|ui| {
+        ui.color_edit_button_srgba(&mut state.theme.selection_color);
+        ui.label(": Selection Color");
+      });
+      ui.horizontal(|ui| {
+        ui.color_edit_button_srgba(&mut state.theme.search_color);
+        ui.label(": Search Match Color");
+      });
 
       ui.separator();
 
@@ -104,11 +364,56 @@ pub fn ui(state: &mut crate::app::Pend, ui: &mut egui::Ui) {
       }
     });
 
+    ui.collapsing("Custom CSS", |ui| {
+      ui.label("For every book:").on_hover_text(
+        "Applied over the publisher's styles. Supports text-align, \
+        text-indent, margin, display, font-style, font-weight, font-variant, \
+        font-family (of fonts in the book), font-size, color & \
+        background-color (of html or body)",
+      );
+      if TextEdit::multiline(&mut state.book_style.user_css)
+        .code_editor()
+        .desired_rows(4)
+        .hint_text("p { text-align: left; text-indent: 1em; }")
+        .show(ui)
+        .response
+        .changed()
+      {
+        state.chapter_cache.clear();
+      }
+
+      if let Some(uuid) = &state.selected_book_uuid {
+        if let Some(book_info) = state.book_userdata.get_mut(uuid) {
+          ui.separator();
+
+          ui.label("For the selected book:");
+          if TextEdit::multiline(&mut book_info.user_css)
+            .code_editor()
+            .desired_rows(4)
+            .hint_text("body { color: #222; background-color: #fff; }")
+            .show(ui)
+            .response
+            .changed()
+          {
+            state.chapter_cache.remove(uuid);
+          }
+          ui.checkbox(
+            &mut book_info.ignore_publisher_styles,
+            "Instead of the Publisher's Styles",
+          );
+        }
+      }
+    });
+
     ui.separator();
 
     ui.horizontal(|ui| {
       if ui.button("Reset Style").clicked() {
-        state.book_style = BookTextStyle::default();
+        // Custom CSS is kept, as it can't be set again with a click
+        state.book_style = BookTextStyle {
+          user_css: std::mem::take(&mut state.book_style.user_css),
+          ..BookTextStyle::default()
+        };
       }
       if ui.button("Clear Selected Book Highlights").clicked() {
         if let Some(path) = &state.selected_book_uuid {
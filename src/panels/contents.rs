@@ -0,0 +1,49 @@
+use egui::ScrollArea;
+
+use crate::{
+  toc::{entry_for_chapter, flatten},
+  ui::GotoTarget,
+};
+
+pub fn ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
+  if let Some(uuid) = &state.selected_book_uuid {
+    let entries = state.book_tocs.get(uuid).map_or(&[][..], Vec::as_slice);
+
+    if entries.is_empty() {
+      ui.label("This book has no table of contents.");
+      return;
+    }
+
+    let current_chapter = state
+      .book_userdata
+      .get(uuid)
+      .map_or(0, |book_info| book_info.chapter);
+    let current_entry = entry_for_chapter(entries, current_chapter);
+
+    ScrollArea::vertical()
+      .auto_shrink([false, false])
+      .show(ui, |ui| {
+        for (depth, entry) in flatten(entries) {
+          ui.horizontal(|ui| {
+            ui.add_space(depth as f32 * 20.0);
+            // Entries that don't point anywhere (e.g. section titles) are
+            // still displayed, but can't be clicked
+            ui.set_enabled(entry.chapter.is_some());
+
+            let is_current = current_entry
+              .filter(|current| std::ptr::eq(*current, entry))
+              .is_some();
+
+            if ui.selectable_label(is_current, &entry.label).clicked() {
+              if let Some(chapter) = entry.chapter {
+                state.goto_target =
+                  Some(GotoTarget::anchor(chapter, entry.anchor.clone()));
+              }
+            }
+          });
+        }
+      });
+  } else {
+    ui.label("No Book Selected");
+  }
+}
@@ -1,4 +1,6 @@
+pub mod bookmarks;
 pub mod config;
+pub mod contents;
 pub mod notes;
 pub mod reader;
 pub mod shelf;
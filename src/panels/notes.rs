@@ -1,12 +1,48 @@
 use egui::TextEdit;
 
-use crate::ui::Note;
+use crate::{
+  backend::chapter_lengths,
+  pages::{position_page, PageNumbers},
+  ui::GotoTarget,
+};
 
 pub fn ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
   if let Some(path) = &state.selected_book_uuid {
     if let Some(book_info) = state.book_userdata.get_mut(path) {
+      let user_css = state.book_style.for_book(book_info).user_css;
       let notes = &mut book_info.notes;
 
+      // Page each note starts on
+      let pages: Vec<Option<String>> = match state.epub_cache.get_mut(path) {
+        Some(book) => {
+          let lengths = state
+            .chapter_lengths
+            .entry(path.clone())
+            .or_insert_with(|| chapter_lengths(book));
+          let page_numbers = state
+            .page_numbers
+            .entry(path.clone())
+            .or_insert_with(|| PageNumbers::load(book, lengths));
+          let chapters = state.chapter_cache.entry(path.clone()).or_default();
+
+          notes
+            .iter()
+            .map(|note| {
+              let range = note.range.as_ref()?;
+              position_page(
+                &range.start,
+                book,
+                chapters,
+                &user_css,
+                lengths,
+                page_numbers,
+              )
+            })
+            .collect()
+        }
+        None => Vec::new(),
+      };
+
       ui.horizontal(|ui| {
         if ui.button("Sort Notes").clicked() {
           notes.sort();
@@ -24,15 +60,30 @@ pub fn ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
         let (chapter, line, content) =
           (note.chapter, note.line, &mut note.content);
 
+        // Notes are labelled with the start of the text they're attached to
+        let mut title = if note.excerpt.chars().count() > 32 {
+          let excerpt: String = note.excerpt.chars().take(32).collect();
+          format!("Ch. {}: \"{}...\"", chapter, excerpt.trim_end())
+        } else if note.excerpt.is_empty() {
+          format!("Ch. {}, line: {}", chapter, line)
+        } else {
+          format!("Ch. {}: \"{}\"", chapter, note.excerpt)
+        };
+        if let Some(Some(page)) = pages.get(index) {
+          title = format!("p. {}, {}", page, title);
+        }
+
         ui.horizontal(|ui| {
-          let response =
-            ui.collapsing(format!("Ch. {}, line: {}", chapter, line), |ui| {
-              TextEdit::multiline(content).show(ui);
-            });
+          let response = ui.collapsing(title, |ui| {
+            TextEdit::multiline(content).show(ui);
+          });
 
           if response.body_response.is_none() {
             if ui.button("Go to").clicked() {
-              state.goto_target = Some(Note::new(chapter, line));
+              state.goto_target = Some(match &note.range {
+                Some(range) => GotoTarget::position(range.start.clone()),
+                None => GotoTarget::new(chapter as usize, line as usize),
+              });
             }
             if ui.button("Remove Note").clicked() {
               to_delete = Some(index);
@@ -1,234 +1,3154 @@
-use egui::{Color32, FontId, Label, RichText, ScrollArea, Sense};
+use egui::{
+  epaint::text::cursor::CCursor,
+  output::OpenUrl,
+  pos2,
+  text::{Fonts, LayoutJob},
+  vec2, Align, Align2, Color32, CursorIcon, FontFamily, FontId, Galley, Pos2,
+  Rect, RichText, ScrollArea, Sense, Stroke, TextFormat, Vec2,
+};
+use egui_extras::RetainedImage;
+
+use std::{
+  collections::{hash_map::DefaultHasher, HashMap},
+  hash::{Hash, Hasher},
+  io::Cursor,
+  ops::Range,
+  sync::Arc,
+};
+
+use epub::doc::EpubDoc;
 
 use crate::{
-  backend::{parse_calibre, FormattingInfo},
-  ui::{Note, PanelState},
+  backend::{
+    cached_chapter, chapter_document, chapter_lengths, chapter_path,
+    current_chapter_path, is_external_link, load_chapter_image, percent_decode,
+    percent_encode, resolve_href, spine_index_of, user_styled_chapter_document,
+    BlockLayout, Bookmark, CachedChapter, Highlight, LocalBookInfo, Pagination,
+  },
+  css::{Stylesheet, TextAlign},
+  document::{Block, BlockCss, BlockKind, Document, Span, SpanCss, SpanStyle},
+  fonts::{css_font_family, family_for_language},
+  linebreak::break_opportunities,
+  location::{TextPosition, TextRange},
+  pages::{PageNumbers, SYNTHETIC_PAGE_LENGTH},
+  progress::{
+    book_offset, book_progress, format_duration, locate_offset,
+    locate_progress, ReadingSpeed,
+  },
+  search::{build_pattern, search_book, BookSearch},
+  toc::{entry_for_chapter, TocEntry},
+  ui::{
+    BookTextStyle, DocumentColors, FootnotePopup, GotoTarget, Note, PanelState,
+    ReaderSelection, ReadingMode, UIState,
+  },
   Pend,
 };
 
+/// Height of horizontal rules
+const RULE_HEIGHT: f32 = 6.0;
+
+/// Fraction of the width of the reader on either side of a page that turns
+/// the page when clicked, in the paginated mode
+const PAGE_TURN_ZONE: f32 = 0.08;
+
 pub fn right_panel_reader_ui(state: &mut Pend, ui: &mut egui::Ui) {
   // Displays page(s) of the book
-  if let Some(selected_book_path) = &state.selected_book_uuid {
-    if let Some(book) = &mut state.epub_cache.get_mut(selected_book_path) {
-      // If a book is loaded there will be a path, only panics if
-      // unexpected unloading of that path occurs
-      let selected_book_path = state.selected_book_uuid.as_ref().unwrap();
+  if let Some(uuid) = state.selected_book_uuid.clone() {
+    if state.epub_cache.contains_key(&uuid) {
+      book_ui(state, ui, &uuid);
+    } else {
+      ui.label("No book loaded");
+    }
+  }
 
-      let book_userdata =
-        state.book_userdata.get_mut(selected_book_path).unwrap();
+  show_footnote_popup(state, ui);
+  confirm_external_link_ui(state, ui);
+}
 
-      if let Some(target) = &state.goto_target {
-        book_userdata.chapter = target.chapter as usize;
-      }
+/// How the reader lays out the chapter
+#[derive(Clone, Copy)]
+struct ViewMode {
+  /// Whether the chapter is split into pages rather than scrolled through
+  paginated: bool,
+  /// Whether the chapters before & after are shown around the current one
+  continuous: bool,
+  /// Pages shown side by side in the paginated mode
+  pages_shown: usize,
+}
+
+impl ViewMode {
+  fn new(style: &BookTextStyle, ui: &egui::Ui) -> Self {
+    // Two pages are shown side by side only if the window is wide enough
+    let spread = style.reading_mode == ReadingMode::Spread
+      && ui.ctx().input().screen_rect().width() >= style.spread_min_width;
+
+    Self {
+      paginated: matches!(
+        style.reading_mode,
+        ReadingMode::Paginated | ReadingMode::Spread
+      ),
+      continuous: style.reading_mode == ReadingMode::Continuous,
+      pages_shown: if spread { 2 } else { 1 },
+    }
+  }
+}
+
+/// The open book: its header (navigation, progress, search), and the text of
+/// the chapter being read
+fn book_ui(state: &mut Pend, ui: &mut egui::Ui, uuid: &str) {
+  let mode = ViewMode::new(&state.book_style, ui);
+
+  // Characters in each chapter, which progress through the book is measured
+  // in
+  let book = state.epub_cache.get_mut(uuid).unwrap();
+  let lengths = state
+    .chapter_lengths
+    .entry(uuid.to_string())
+    .or_insert_with(|| chapter_lengths(book));
+  state
+    .page_numbers
+    .entry(uuid.to_string())
+    .or_insert_with(|| PageNumbers::load(book, lengths));
+
+  // Shortcuts are ignored while typing into a text field
+  let is_typing = ui.memory().focus().is_some();
+  let mut history_step = handle_history_input(ui, is_typing);
+  // The mouse's back & forward buttons (only reported on the web)
+  #[cfg(target_arch = "wasm32")]
+  if let Some(forwards) = state.side_button_presses.borrow_mut().pop() {
+    history_step = Some(forwards);
+  }
+  let mut page_turn = handle_page_keys(state, ui, uuid, mode, is_typing);
+
+  let search = &mut state.ui_state.book_search;
+  if !is_typing
+    && ui.input().modifiers.command
+    && ui.input().key_pressed(egui::Key::F)
+  {
+    search.open = true;
+    search.focus = true;
+  }
+  let mut toggle_bookmark = !is_typing
+    && ui.input().modifiers.command
+    && ui.input().key_pressed(egui::Key::D);
+
+  header_ui(
+    state,
+    ui,
+    uuid,
+    mode,
+    &mut history_step,
+    &mut toggle_bookmark,
+  );
+
+  if toggle_bookmark {
+    toggle_shown_bookmark(state, uuid);
+  }
+
+  let search = &mut state.ui_state.book_search;
+  if search.open {
+    ui.separator();
+    search_ui(
+      ui,
+      search,
+      uuid,
+      state.epub_cache.get_mut(uuid).unwrap(),
+      state.book_tocs.get(uuid).map_or(&[][..], Vec::as_slice),
+      &mut state.goto_target,
+    );
+  }
+
+  navigate(state, uuid, mode, history_step);
+
+  ui.separator();
+
+  let page_height = ui.available_height();
 
-      // Key-based page navigation
-      if ui.ctx().input().key_pressed(egui::Key::ArrowLeft)
-        && book.get_current_page() > 1
+  // Display of page (CHAPTER) contents
+  let mut scroll_area = ScrollArea::new([false, true])
+    .always_show_scroll(false)
+    .auto_shrink([false, true]);
+  if let Some(offset) = state.ui_state.reader_scroll.take() {
+    scroll_area = scroll_area.vertical_scroll_offset(offset);
+  }
+  scroll_area.show(ui, |ui| {
+    if state.ui_state.display_raw_text {
+      let book = state.epub_cache.get_mut(uuid).unwrap();
+      ui.label(&book.get_current_str().unwrap());
+    } else {
+      chapter_ui(state, ui, uuid, mode, page_height, &mut page_turn);
+    }
+  });
+
+  if let Some(forwards) = page_turn {
+    let book = &state.epub_cache[uuid];
+    let book_userdata = state.book_userdata.get_mut(uuid).unwrap();
+    let pages = state
+      .chapter_cache
+      .get(uuid)
+      .and_then(|chapters| chapters.get(&book_userdata.chapter))
+      .and_then(|chapter| chapter.pages.as_ref())
+      .map_or(1, |pagination| pagination.starts.len());
+
+    turn_page(
+      &mut state.ui_state.reader_page,
+      &mut book_userdata.chapter,
+      pages,
+      book.get_num_pages(),
+      mode.pages_shown,
+      forwards,
+    );
+    ui.ctx().request_repaint();
+  }
+}
+
+/// With Alt held, the arrow keys move through the navigation history.
+/// Returns the step to take through it: forwards (true) or back (false)
+fn handle_history_input(ui: &egui::Ui, is_typing: bool) -> Option<bool> {
+  let mut history_step = None;
+  let alt = ui.input().modifiers.alt;
+  if !is_typing && alt && ui.input().key_pressed(egui::Key::ArrowLeft) {
+    history_step = Some(false);
+  }
+  if !is_typing && alt && ui.input().key_pressed(egui::Key::ArrowRight) {
+    history_step = Some(true);
+  }
+
+  history_step
+}
+
+/// The arrow keys (without Alt) turn the page in the paginated mode, and
+/// otherwise move between chapters. Returns the page to turn to: the next
+/// (true) or previous (false)
+fn handle_page_keys(
+  state: &mut Pend,
+  ui: &egui::Ui,
+  uuid: &str,
+  mode: ViewMode,
+  is_typing: bool,
+) -> Option<bool> {
+  if is_typing || ui.input().modifiers.alt {
+    return None;
+  }
+
+  let book = &state.epub_cache[uuid];
+  let book_userdata = state.book_userdata.get_mut(uuid).unwrap();
+  let mut page_turn = None;
+  if ui.ctx().input().key_pressed(egui::Key::ArrowLeft) {
+    if mode.paginated {
+      page_turn = Some(false);
+    } else if book.get_current_page() > 1 {
+      book_userdata.chapter -= 1;
+    }
+  }
+  if ui.ctx().input().key_pressed(egui::Key::ArrowRight) {
+    if mode.paginated {
+      page_turn = Some(true);
+    } else if book.get_current_page() < book.get_num_pages() - 1 {
+      book_userdata.chapter += 1;
+    }
+  }
+
+  page_turn
+}
+
+/// Buttons for the history, search, bookmarks & focus mode, followed (unless
+/// focused) by the chapter's title, the progress through the book and a
+/// slider to move through it
+fn header_ui(
+  state: &mut Pend,
+  ui: &mut egui::Ui,
+  uuid: &str,
+  mode: ViewMode,
+  history_step: &mut Option<bool>,
+  toggle_bookmark: &mut bool,
+) {
+  // A bookmark in the text shown is removed by the bookmark button rather
+  // than another being added
+  let shown = state.ui_state.reader_shown.as_ref();
+  let is_bookmarked = state.book_userdata[uuid]
+    .bookmarks
+    .iter()
+    .any(|bookmark| is_shown(shown, bookmark));
+
+  ui.horizontal(|ui| {
+    // Return to where the reader was before jumping somewhere else (also
+    // done with Alt+Left / Alt+Right, and the mouse's side buttons on the
+    // web)
+    let history = state.history.entry(uuid.to_string()).or_default();
+    if ui
+      .add_enabled(!history.back.is_empty(), egui::Button::new("Back"))
+      .on_hover_text("Alt+Left")
+      .clicked()
+    {
+      *history_step = Some(false);
+    }
+    if ui
+      .add_enabled(!history.forward.is_empty(), egui::Button::new("Forward"))
+      .on_hover_text("Alt+Right")
+      .clicked()
+    {
+      *history_step = Some(true);
+    }
+
+    let search = &mut state.ui_state.book_search;
+    if ui.selectable_label(search.open, "Search").clicked() {
+      search.open = !search.open;
+      search.focus = search.open;
+    }
+
+    if ui
+      .selectable_label(is_bookmarked, "Bookmark")
+      .on_hover_text("Ctrl+D")
+      .clicked()
+    {
+      *toggle_bookmark = true;
+    }
+
+    if state.ui_state.reader_focus_mode {
+      // Collapse focus
+      if ui
+        .add(egui::Button::new(RichText::new("Unfocus")))
+        .clicked()
       {
-        book_userdata.chapter -= 1;
+        state.ui_state.reader_focus_mode = false;
       }
-      if ui.ctx().input().key_pressed(egui::Key::ArrowRight)
-        && book.get_current_page() < book.get_num_pages() - 1
-      {
-        book_userdata.chapter += 1;
+    } else {
+      // Expand focus
+      if ui.add(egui::Button::new(RichText::new("Focus"))).clicked() {
+        state.ui_state.reader_focus_mode = true;
       }
 
-      ui.horizontal(|ui| {
-        if state.ui_state.reader_focus_mode {
-          // Collapse focus
-          if ui
-            .add(egui::Button::new(RichText::new("Unfocus")))
-            .clicked()
-          {
-            state.ui_state.reader_focus_mode = false;
-          }
-        } else {
-          // Expand focus
-          if ui.add(egui::Button::new(RichText::new("Focus"))).clicked() {
-            state.ui_state.reader_focus_mode = true;
-          }
+      ui.separator();
 
-          ui.separator();
+      // Prefer the title from the table of contents over the number
+      let chapter = state.book_userdata[uuid].chapter;
+      match state
+        .book_tocs
+        .get(uuid)
+        .and_then(|toc| entry_for_chapter(toc, chapter))
+      {
+        Some(entry) => ui.label(&entry.label),
+        None => ui.label(format!("Chapter: {}", &chapter)),
+      };
+
+      progress_menu_ui(state, ui, uuid);
+      page_slider_ui(state, ui, uuid, mode);
+    }
+  });
+}
 
-          ui.label(format!("Chapter: {}", &book_userdata.chapter));
+/// Progress through the book, opening a menu with how long the rest should
+/// take, and ways to go to a percentage or page of the book
+fn progress_menu_ui(state: &mut Pend, ui: &mut egui::Ui, uuid: &str) {
+  let book = state.epub_cache.get_mut(uuid).unwrap();
+  let book_userdata = &state.book_userdata[uuid];
+  let lengths = &state.chapter_lengths[uuid];
+  let page_numbers = &state.page_numbers[uuid];
 
-          ui.spacing_mut().slider_width = ui.available_width();
-          ui.add(
-            egui::Slider::new(
-              &mut book_userdata.chapter,
-              1..=book.get_num_pages() - 1,
-            )
-            .show_value(false),
-          );
-        }
-      });
+  let total: usize = lengths.iter().sum();
+  let read = (book_userdata.progress * total as f32) as usize;
+  let chapter_end = book_offset(lengths, book_userdata.chapter + 1, 0);
+  let speed = state.reading_speed;
+
+  let progress = match page_numbers.label_at(read) {
+    Some(page) => {
+      format!("{:.0}% · p. {}", book_userdata.progress * 100.0, page)
+    }
+    None => format!("{:.0}%", book_userdata.progress * 100.0),
+  };
+
+  ui.menu_button(progress, |ui| {
+    ui.label(format!(
+      "About {} left in this chapter",
+      format_duration(speed.time_to_read(chapter_end.saturating_sub(read)))
+    ));
+    ui.label(format!(
+      "About {} left in the book",
+      format_duration(speed.time_to_read(total.saturating_sub(read)))
+    ));
 
-      // Apply page / chapter change of needed
-      if book.get_current_page() != book_userdata.chapter {
-        book.set_current_page(book_userdata.chapter).unwrap();
-        state.goto_target = Some(Note::new(book_userdata.chapter as u16, 0));
+    ui.separator();
+
+    ui.horizontal(|ui| {
+      ui.label("Go to");
+      ui.add(
+        egui::DragValue::new(&mut state.ui_state.goto_percent)
+          .clamp_range(0.0..=100.0)
+          .suffix("%"),
+      );
+
+      if ui.button("Go").clicked() {
+        let (chapter, offset) =
+          locate_progress(lengths, state.ui_state.goto_percent / 100.0);
+
+        if let Some(document) = chapter_document(book, chapter) {
+          let (block, char_index) = document.position_at(offset);
+          state.goto_target = Some(GotoTarget::position(TextPosition::new(
+            chapter, &document, block, char_index,
+          )));
+        }
+        ui.close_menu();
       }
+    });
 
-      ui.separator();
+    ui.horizontal(|ui| {
+      ui.label("Go to page");
+      let response = ui.add(
+        egui::TextEdit::singleline(&mut state.ui_state.goto_page)
+          .desired_width(48.0),
+      );
+      let go = ui.button("Go").clicked()
+        || (response.lost_focus() && ui.input().key_pressed(egui::Key::Enter));
 
-      // Display of page (CHAPTER) contents
-      ScrollArea::new([false, true])
-        .always_show_scroll(false)
-        .auto_shrink([false, true])
-        .show(ui, |ui| {
-          if state.ui_state.display_raw_text {
-            ui.label(&book.get_current_str().unwrap());
-          } else {
-            let style = &state.book_style;
-            let theme = &state.theme;
-
-            if let Ok(page_data) = book.get_current_str() {
-              let contents = parse_calibre(
-                &page_data,
-                book.get_current_page(),
-                book_userdata,
-              );
-              let contents = contents.lines();
-
-              // Background
-              ui.painter()
-                .rect_filled(ui.clip_rect(), 0.0, theme.page_color);
-
-              // Actual "stuff"
-              let font_id =
-                FontId::new(style.font_size, style.font_family.clone());
-              let line_spacing =
-                ui.fonts().row_height(&font_id) * style.line_spacing_multiplier;
-
-              ui.style_mut().spacing.item_spacing.y = line_spacing;
-
-              let mut goto_target_response = None;
-
-              for (line_number, line) in contents.into_iter().enumerate() {
-                let line_response = ui.add(
-                  Label::new({
-                    // Creates text with normal / default appearence
-                    // This is how normal body text looks
-                    let mut text = RichText::new(line)
-                      .color(theme.text_color)
-                      .background_color(
-                        book_userdata
-                          .highlights
-                          .get(&(book_userdata.chapter, line_number))
-                          .map_or(Color32::TRANSPARENT, |color| *color),
-                      )
-                      .font(font_id.clone());
-
-                    // Applies special formatting (heading, bold, etc.)
-                    if let Some(info) = book_userdata
-                      .formatting_info
-                      .get(&(book_userdata.chapter, line_number))
-                    {
-                      match info {
-                        FormattingInfo::Title => {
-                          text = text.size(font_id.size * 1.75);
-                        }
-                        FormattingInfo::Heading => {
-                          text = text.size(font_id.size * 1.5);
-                        }
-                        FormattingInfo::Heading2 => {
-                          text = text.size(font_id.size * 1.25);
-                        }
-                        FormattingInfo::Bold => text = text.strong(),
-                        FormattingInfo::Italic => text = text.italics(),
-                      }
-                    }
-
-                    text
-                  })
-                  .sense(Sense::click()),
-                );
-
-                if let Some(target) = &state.goto_target {
-                  if line_number == target.line as usize {
-                    goto_target_response = Some(line_response.clone());
-                  }
-                }
-
-                // Context menu
-                line_response.context_menu(|ui| {
-                  ui.horizontal(|ui| {
-                    for (index, color) in [
-                      state.theme.highlight_color,
-                      Color32::from_rgb(255, 150, 138),
-                      Color32::from_rgb(255, 209, 138),
-                      Color32::from_rgb(138, 255, 150),
-                      Color32::from_rgb(150, 138, 255),
-                    ]
-                    .iter()
-                    .enumerate()
-                    {
-                      // Separator placed after the first option to indicate
-                      // the user's custom selected highlight color
-                      if index == 1 {
-                        ui.separator();
-                        ui.add_space(6.0);
-                      }
-
-                      // Button & logic
-                      if ui
-                        .button(
-                          RichText::new("\u{25CF}").color(*color).size(32.0),
-                        )
-                        .clicked()
-                      {
-                        let coord = (book_userdata.chapter, line_number);
-
-                        if let Some(existing_color) =
-                          book_userdata.highlights.get_mut(&coord)
-                        {
-                          if *existing_color == *color {
-                            book_userdata.highlights.remove(&coord);
-                          } else {
-                            *existing_color = *color;
-                          };
-                        } else {
-                          book_userdata.highlights.insert(coord, *color);
-                        }
-
-                        ui.close_menu();
-                      }
-                    }
-                  });
-
-                  if ui.button("Copy").clicked() {
-                    ui.output().copied_text = line.to_string();
-                    ui.close_menu();
-                  }
-
-                  if ui.button("Add Note").clicked() {
-                    let note = Note::new(
-                      book.get_current_page() as u16,
-                      line_number as u16,
-                    );
-
-                    // Adds the note if one is not already in place for the specified chapter / line combo
-                    if !book_userdata.notes.contains(&note) {
-                      book_userdata.notes.push(note);
-                      state.ui_state.left_panel_state = PanelState::Notes;
-
-                      ui.close_menu();
-                    }
-                  }
-                });
-              }
-
-              if let Some(response) = goto_target_response {
-                response.scroll_to_me(Some(egui::Align::TOP));
-                state.goto_target = None;
-              }
-            } else {
-              ui.label("Unable to load page data");
+      if go {
+        match page_numbers.find(&state.ui_state.goto_page) {
+          Some(start) => {
+            let (chapter, offset) = locate_offset(lengths, start);
+
+            if let Some(document) = chapter_document(book, chapter) {
+              let (block, char_index) = document.position_at(offset);
+              state.goto_target = Some(GotoTarget::position(
+                TextPosition::new(chapter, &document, block, char_index),
+              ));
             }
+            ui.close_menu();
           }
-        });
+          None => state.ui_state.goto_page.clear(),
+        }
+      }
+    });
+    if !page_numbers.is_print() {
+      ui.label(format!(
+        "Pages are every {} characters, as the book has no page list",
+        SYNTHETIC_PAGE_LENGTH
+      ));
+    }
+  });
+}
+
+/// Slider through the pages of the book in the paginated mode (once the
+/// chapter has been split into pages), or else through its chapters, with
+/// the bookmarks marked along it
+fn page_slider_ui(
+  state: &mut Pend,
+  ui: &mut egui::Ui,
+  uuid: &str,
+  mode: ViewMode,
+) {
+  let book = &state.epub_cache[uuid];
+  let book_userdata = state.book_userdata.get_mut(uuid).unwrap();
+  let lengths = &state.chapter_lengths[uuid];
+
+  // Pages in each chapter
+  let page_counts = if mode.paginated {
+    state.chapter_cache.get(uuid).and_then(|chapters| {
+      let key = chapters.get(&book_userdata.chapter)?.pages.as_ref()?.key;
+      Some(chapter_page_counts(lengths, chapters, key))
+    })
+  } else {
+    None
+  };
+
+  if let Some(counts) = &page_counts {
+    let reader_page = &mut state.ui_state.reader_page;
+    let chapter = book_userdata.chapter;
+    let page = if reader_page.0 == chapter {
+      reader_page.1
     } else {
-      ui.label("No book loaded");
+      0
+    };
+    let pages_before: usize = counts.iter().take(chapter).sum();
+    let total: usize = counts.iter().sum();
+    let mut book_page = pages_before + page + 1;
+    let count = counts.get(chapter).copied().unwrap_or(1);
+
+    let shown = if page + 1 < count && mode.pages_shown == 2 {
+      format!("Pages {}-{}", page + 1, page + 2)
+    } else {
+      format!("Page {}", page + 1)
+    };
+    ui.label(format!(
+      "{}/{} ({}/{} in book)",
+      shown, count, book_page, total
+    ));
+
+    // Moves through the pages of the whole book
+    ui.spacing_mut().slider_width = ui.available_width();
+    let response =
+      ui.add(egui::Slider::new(&mut book_page, 1..=total).show_value(false));
+    if response.changed() {
+      *reader_page = locate_page(counts, book_page - 1);
+      book_userdata.chapter = reader_page.0;
+    }
+    slider_markers(
+      ui,
+      response.rect,
+      book_userdata
+        .bookmarks
+        .iter()
+        .map(|bookmark| bookmark.progress),
+    );
+  } else {
+    let last_chapter = book.get_num_pages() - 1;
+    ui.spacing_mut().slider_width = ui.available_width();
+    let response = ui.add(
+      egui::Slider::new(&mut book_userdata.chapter, 1..=last_chapter)
+        .show_value(false),
+    );
+    slider_markers(
+      ui,
+      response.rect,
+      book_userdata.bookmarks.iter().map(|bookmark| {
+        bookmark.position.chapter.saturating_sub(1) as f32
+          / last_chapter.saturating_sub(1).max(1) as f32
+      }),
+    );
+  }
+}
+
+/// Whether a bookmark is in the text shown in the reader (the last time it
+/// was drawn)
+fn is_shown(shown: Option<&TextRange>, bookmark: &Bookmark) -> bool {
+  matches!(shown, Some(range) if range.start <= bookmark.position
+    && bookmark.position < range.end)
+}
+
+/// Removes the bookmarks in the text shown in the reader, or if there are
+/// none, adds one where the reader is
+fn toggle_shown_bookmark(state: &mut Pend, uuid: &str) {
+  let book_userdata = state.book_userdata.get_mut(uuid).unwrap();
+  let user_css = state.book_style.for_book(book_userdata).user_css;
+  let shown = state.ui_state.reader_shown.as_ref();
+
+  let bookmarks = &mut book_userdata.bookmarks;
+  let count = bookmarks.len();
+  bookmarks.retain(|bookmark| !is_shown(shown, bookmark));
+
+  if bookmarks.len() == count {
+    if let Some(position) = book_userdata.position.clone() {
+      let chapters = state.chapter_cache.entry(uuid.to_string()).or_default();
+
+      if let Some(cached) = cached_chapter(
+        chapters,
+        state.epub_cache.get_mut(uuid).unwrap(),
+        position.chapter,
+        &user_css,
+      ) {
+        bookmarks.push(Bookmark::new(
+          position,
+          &cached.document,
+          book_userdata.progress,
+        ));
+      }
+    }
+  }
+}
+
+/// Moves through the navigation history, returns to where the book was left
+/// when it is opened, and opens the chapter of anywhere being gone to. Jumps
+/// are recorded in the history
+fn navigate(
+  state: &mut Pend,
+  uuid: &str,
+  mode: ViewMode,
+  history_step: Option<bool>,
+) {
+  let book = state.epub_cache.get_mut(uuid).unwrap();
+  let book_userdata = state.book_userdata.get_mut(uuid).unwrap();
+  let history = state.history.entry(uuid.to_string()).or_default();
+
+  // Where the reader is now, to come back to
+  let here = book_userdata.position.clone().map_or_else(
+    || GotoTarget::new(book_userdata.chapter, 0),
+    GotoTarget::position,
+  );
+
+  if let Some(forwards) = history_step {
+    let target = if forwards {
+      history.forward(here.clone())
+    } else {
+      history.back(here.clone())
+    };
+    if target.is_some() {
+      state.goto_target = target;
+    }
+  }
+
+  // Return to where the book was left when it is opened
+  let opened = state.ui_state.reader_book.as_deref() != Some(uuid);
+  if opened {
+    state.ui_state.reader_book = Some(uuid.to_string());
+    if state.goto_target.is_none() {
+      state.goto_target =
+        book_userdata.position.clone().map(GotoTarget::position);
+    }
+  }
+
+  if let Some(target) = &state.goto_target {
+    book_userdata.chapter = target.chapter;
+  }
+
+  // Apply page / chapter change of needed
+  if book.get_current_page() != book_userdata.chapter {
+    book.set_current_page(book_userdata.chapter).unwrap();
+
+    // Start at the top of the chapter, unless going somewhere specific
+    // (pages are kept track of separately, and the continuous mode keeps its
+    // place when scrolling into another chapter)
+    if state.goto_target.is_none()
+      && !mode.paginated
+      && state.ui_state.reader_scroll.is_none()
+    {
+      state.goto_target = Some(GotoTarget::new(book_userdata.chapter, 0));
+    }
+  }
+
+  // Jumps (e.g. from the contents, a link, or the slider) are recorded in the
+  // history, but not returning to the same place after the text is laid out
+  // differently
+  if let Some(target) = &state.goto_target {
+    if !opened
+      && history_step.is_none()
+      && target.position.as_ref() != book_userdata.position.as_ref()
+    {
+      history.record(here);
+    }
+  }
+}
+
+/// The text of the book: the current chapter, along with the end of the
+/// previous one and the start of the next in the continuous mode
+fn chapter_ui(
+  state: &mut Pend,
+  ui: &mut egui::Ui,
+  uuid: &str,
+  mode: ViewMode,
+  page_height: f32,
+  page_turn: &mut Option<bool>,
+) {
+  let book_userdata = &state.book_userdata[uuid];
+  let style = state.book_style.for_book(book_userdata);
+
+  // Background, which may be set by the reader's CSS
+  let chapters = state.chapter_cache.entry(uuid.to_string()).or_default();
+  let page_color = cached_chapter(
+    chapters,
+    state.epub_cache.get_mut(uuid).unwrap(),
+    book_userdata.chapter,
+    &style.user_css,
+  )
+  .and_then(|cached| cached.document.background)
+  .unwrap_or(state.theme.page_color);
+  ui.painter().rect_filled(ui.clip_rect(), 0.0, page_color);
+
+  // Actual "stuff"
+  let font_id = FontId::new(style.font_size, style.font_family.clone());
+  let line_spacing =
+    ui.fonts().row_height(&font_id) * style.line_spacing_multiplier;
+
+  ui.style_mut().spacing.item_spacing.y = line_spacing;
+
+  // Page numbers are shown in a margin to the right of the text (in the
+  // paginated mode there is room beside the pages already)
+  if style.show_page_numbers && !mode.paginated {
+    ui.set_max_width(ui.available_width() - style.font_size * 2.0);
+  }
+
+  if mode.continuous {
+    continuous_view(state, ui, uuid, &style, |state, ui| {
+      current_chapter_ui(state, ui, uuid, mode, &style, page_height, page_turn)
+    });
+  } else {
+    current_chapter_ui(state, ui, uuid, mode, &style, page_height, page_turn);
+  }
+}
+
+/// In the continuous mode, the end of the previous chapter is shown above the
+/// current one and the start of the next below it. The chapter in the middle
+/// of the view becomes the current one once it is scrolled into, and the view
+/// is kept in place as the chapters shown around it change
+fn continuous_view(
+  state: &mut Pend,
+  ui: &mut egui::Ui,
+  uuid: &str,
+  style: &BookTextStyle,
+  current_chapter_ui: impl FnOnce(&mut Pend, &mut egui::Ui) -> Option<bool>,
+) {
+  let origin = ui.available_rect_before_wrap().top();
+  let chapter = state.book_userdata[uuid].chapter;
+  let previous = chapter.saturating_sub(1);
+  if previous > 0 {
+    neighbour_ui(state, ui, uuid, previous, style);
+  }
+
+  let current_top = ui.available_rect_before_wrap().top();
+  let moved = match current_chapter_ui(state, ui) {
+    Some(moved) => moved,
+    None => return,
+  };
+  let current_bottom = ui.available_rect_before_wrap().top();
+
+  let next = chapter + 1;
+  let chapter_count = state.epub_cache[uuid].get_num_pages();
+  if next < chapter_count {
+    neighbour_ui(state, ui, uuid, next, style);
+  }
+
+  if moved || state.goto_target.is_some() {
+    return;
+  }
+  let middle = ui.clip_rect().center().y;
+  let offset = ui.clip_rect().top() + ui.visuals().clip_rect_margin - origin;
+
+  if middle >= current_bottom && next < chapter_count {
+    state.ui_state.reader_scroll = Some(offset - (current_top - origin));
+    state.book_userdata.get_mut(uuid).unwrap().chapter = next;
+  } else if middle < current_top && previous > 0 {
+    // The chapter before the previous one will be shown above it
+    let above = match previous - 1 {
+      0 => 0.0,
+      chapter => cached_chapter(
+        state.chapter_cache.entry(uuid.to_string()).or_default(),
+        state.epub_cache.get_mut(uuid).unwrap(),
+        chapter,
+        &style.user_css,
+      )
+      .map_or(0.0, |cached| {
+        let font_id = FontId::new(style.font_size, style.font_family.clone());
+        chapter_height(ui, cached, style, &font_id)
+      }),
+    };
+    state.ui_state.reader_scroll = Some(offset + above);
+    state.book_userdata.get_mut(uuid).unwrap().chapter = previous;
+  }
+}
+
+/// A chapter shown around the current one in the continuous mode
+fn neighbour_ui(
+  state: &mut Pend,
+  ui: &mut egui::Ui,
+  uuid: &str,
+  chapter: usize,
+  style: &BookTextStyle,
+) {
+  let book = state.epub_cache.get_mut(uuid).unwrap();
+  let chapters = state.chapter_cache.entry(uuid.to_string()).or_default();
+
+  if let Some(cached) = cached_chapter(chapters, book, chapter, &style.user_css)
+  {
+    neighbour_chapter_ui(
+      ui,
+      cached,
+      chapter,
+      book,
+      state.book_images.entry(uuid.to_string()).or_default(),
+      style,
+      &state.theme,
+      &state.book_userdata[uuid].highlights,
+    );
+  }
+}
+
+/// The current chapter, along with everything drawing its blocks depends on
+/// for a frame
+struct ChapterView<'a> {
+  chapter: usize,
+  document: Arc<Document>,
+  /// Path of the chapter within the epub, which its images are relative to
+  path: String,
+  mode: ViewMode,
+  style: &'a BookTextStyle,
+  theme: &'a DocumentColors,
+  font_id: FontId,
+  line_spacing: f32,
+  /// Whether publisher styles are applied
+  css: bool,
+  /// Height of the reader below its header
+  page_height: f32,
+  /// Characters in the book before the chapter
+  start: usize,
+  /// (Block, character range), color of each highlighted range: the user's
+  /// highlights, then matches of the search, then the selection
+  highlights: Vec<(usize, Range<usize>, Color32)>,
+  selected_range: Option<TextRange>,
+  selected_text: Option<String>,
+  /// (Block, character) to go to
+  target: Option<(usize, usize)>,
+}
+
+impl ChapterView<'_> {
+  /// Images are kept within a page in the paginated mode
+  fn max_image_height(&self) -> f32 {
+    if self.mode.paginated {
+      self.page_height
+    } else {
+      f32::INFINITY
+    }
+  }
+}
+
+/// What was found while drawing the blocks of the current chapter
+#[derive(Default)]
+struct BlocksShown {
+  /// Where the target being gone to was drawn
+  target_rect: Option<Rect>,
+  /// (Block, character) at the top of the view
+  reading_position: Option<(usize, usize)>,
+  /// (Block, character) at the end of the last block shown
+  shown_end: Option<(usize, usize)>,
+  clicked_link: Option<String>,
+  /// (Link, pointer position, whether it was clicked) of the note reference
+  /// under the pointer
+  hovered_noteref: Option<(String, Pos2, bool)>,
+}
+
+/// The current chapter. Returns `None` if it couldn't be loaded, and
+/// otherwise whether the view was moved to a target in it
+fn current_chapter_ui(
+  state: &mut Pend,
+  ui: &mut egui::Ui,
+  uuid: &str,
+  mode: ViewMode,
+  style: &BookTextStyle,
+  page_height: f32,
+  page_turn: &mut Option<bool>,
+) -> Option<bool> {
+  let book = state.epub_cache.get_mut(uuid).unwrap();
+  let book_userdata = state.book_userdata.get_mut(uuid).unwrap();
+  let chapter = book_userdata.chapter;
+  let chapters = state.chapter_cache.entry(uuid.to_string()).or_default();
+  let cached = match cached_chapter(chapters, book, chapter, &style.user_css) {
+    Some(cached) => cached,
+    None => {
+      ui.label("Unable to load page data");
+      return None;
+    }
+  };
+  let document = Arc::clone(&cached.document);
+  let ui_state = &mut state.ui_state;
+
+  // Selections are dropped when leaving their chapter
+  let selection = &mut ui_state.reader_selection;
+  if matches!(selection, Some(s) if s.chapter != chapter) {
+    *selection = None;
+  }
+  let selected_range = selection
+    .as_ref()
+    .filter(|selection| !selection.is_empty())
+    .map(|selection| selection.range(&document));
+  let selected_blocks = selected_range
+    .as_ref()
+    .map(|range| range.block_ranges(chapter, &document))
+    .unwrap_or_default();
+  let selected_text = selected_range
+    .as_ref()
+    .map(|_| range_text(&document, &selected_blocks));
+
+  let mut highlights =
+    chapter_highlights(&book_userdata.highlights, chapter, &document);
+  highlights.extend(search_highlights(
+    &ui_state.book_search,
+    uuid,
+    chapter,
+    &state.theme,
+  ));
+  // The selection is drawn above highlights
+  highlights.extend(
+    selected_blocks
+      .into_iter()
+      .map(|(block, range)| (block, range, state.theme.selection_color)),
+  );
+
+  let view = ChapterView {
+    chapter,
+    path: current_chapter_path(book),
+    mode,
+    style,
+    theme: &state.theme,
+    font_id: FontId::new(style.font_size, style.font_family.clone()),
+    line_spacing: ui.spacing().item_spacing.y,
+    css: !style.ignore_publisher_styles,
+    page_height,
+    start: book_offset(&state.chapter_lengths[uuid], chapter, 0),
+    highlights,
+    selected_range,
+    selected_text,
+    target: state
+      .goto_target
+      .as_ref()
+      .and_then(|target| target_position(target, &document)),
+    document: Arc::clone(&document),
+  };
+
+  let images = state.book_images.entry(uuid.to_string()).or_default();
+  let mut page_uis = if mode.paginated {
+    paginated_view(
+      ui,
+      &view,
+      cached,
+      book,
+      images,
+      &mut ui_state.reader_page,
+      page_turn,
+    )
+  } else {
+    Vec::new()
+  };
+  let relayout = view_changed(ui, &view, &mut ui_state.reader_view);
+
+  let uis: Vec<&mut egui::Ui> = if mode.paginated {
+    page_uis.iter_mut().collect()
+  } else {
+    vec![&mut *ui]
+  };
+  let mut shown = BlocksShown::default();
+  for ui in uis {
+    blocks_ui(
+      ui,
+      &view,
+      cached,
+      book,
+      images,
+      book_userdata,
+      ui_state,
+      &state.page_numbers[uuid],
+      &mut shown,
+    );
+  }
+
+  end_selection(
+    ui,
+    &mut ui_state.reader_selection,
+    view.selected_text.as_ref(),
+  );
+
+  if let Some(rect) = shown.target_rect.filter(|_| !mode.paginated) {
+    ui.scroll_to_rect(rect, Some(Align::TOP));
+  }
+  // Targets that don't exist in this chapter are dropped as well
+  state.goto_target = None;
+
+  // The reading position is kept track of (unless the view is about to
+  // move), and returned to when the text is laid out differently
+  let moved = view.target.is_some();
+  if relayout {
+    state.goto_target =
+      book_userdata.position.clone().map(GotoTarget::position);
+  } else if let Some(position) = shown.reading_position.filter(|_| !moved) {
+    record_reading_position(
+      state,
+      uuid,
+      &document,
+      position,
+      shown.shown_end,
+      ui.input().time,
+    );
+  }
+
+  let book = state.epub_cache.get_mut(uuid).unwrap();
+  update_footnote_popup(
+    &mut state.ui_state.footnote_popup,
+    shown.hovered_noteref,
+    book,
+    &document,
+    &style.user_css,
+  );
+
+  if let Some(href) = shown.clicked_link {
+    if is_external_link(&href) {
+      state.ui_state.confirm_external_link = Some(href);
+    } else if let Some(target) = link_target(book, &href) {
+      state.goto_target = Some(target);
+    }
+  }
+
+  Some(moved)
+}
+
+/// (Block, character range), color of each of the user's highlights within a
+/// chapter
+fn chapter_highlights(
+  highlights: &[Highlight],
+  chapter: usize,
+  document: &Document,
+) -> Vec<(usize, Range<usize>, Color32)> {
+  highlights
+    .iter()
+    .flat_map(|highlight| {
+      highlight
+        .range
+        .block_ranges(chapter, document)
+        .into_iter()
+        .map(|(block, range)| (block, range, highlight.color))
+    })
+    .collect()
+}
+
+/// (Block, character range), color of each match of the search within a
+/// chapter, while the search is open and up to date
+fn search_highlights(
+  search: &BookSearch,
+  uuid: &str,
+  chapter: usize,
+  theme: &DocumentColors,
+) -> Vec<(usize, Range<usize>, Color32)> {
+  if !search.open || search.is_stale(uuid) {
+    return Vec::new();
+  }
+
+  search
+    .hits
+    .iter()
+    .enumerate()
+    .filter(|(_, hit)| hit.chapter == chapter)
+    .map(|(index, hit)| {
+      let color = if search.current == Some(index) {
+        theme.selection_color
+      } else {
+        theme.search_color
+      };
+      (hit.block, hit.range.clone(), color)
+    })
+    .collect()
+}
+
+/// (Block, character) of a chapter that a target leads to
+fn target_position(
+  target: &GotoTarget,
+  document: &Document,
+) -> Option<(usize, usize)> {
+  match (&target.position, &target.anchor) {
+    (Some(position), _) => position.resolve(document),
+    (None, Some(anchor)) => {
+      document.anchor_block(anchor).map(|block| (block, 0))
+    }
+    (None, None) => Some((target.line, 0)),
+  }
+}
+
+/// In the paginated mode the chapter is drawn shifted up to the start of each
+/// page shown, and cut off at its end. Returns a ui for each page shown, and
+/// turns to the page of the target being gone to
+fn paginated_view(
+  ui: &mut egui::Ui,
+  view: &ChapterView,
+  cached: &mut CachedChapter,
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  images: &mut HashMap<String, Option<RetainedImage>>,
+  reader_page: &mut (usize, usize),
+  page_turn: &mut Option<bool>,
+) -> Vec<egui::Ui> {
+  let pages_shown = view.mode.pages_shown;
+  let zone_width = ui.available_width() * PAGE_TURN_ZONE;
+  let (rect, _) = ui.allocate_exact_size(
+    vec2(ui.available_width(), view.page_height),
+    Sense::hover(),
+  );
+  let text_rect = rect.shrink2(vec2(zone_width, 0.0));
+  // Pages shown side by side are separated by the same space as is left on
+  // either side of them
+  let page_size = vec2(
+    (text_rect.width() - zone_width * (pages_shown - 1) as f32)
+      / pages_shown as f32,
+    text_rect.height(),
+  );
+
+  let key =
+    pagination_key(&view.font_id, page_size, view.line_spacing, view.style);
+  let pagination = match &mut cached.pages {
+    Some(pagination) if pagination.key == key => pagination,
+    pages => {
+      // Every block has to be measured to know where the pages break
+      let measures =
+        measure_blocks(ui, view, &mut cached.layouts, book, images, page_size);
+      pages.insert(paginate(key, &measures, view.line_spacing, page_size.y))
+    }
+  };
+
+  if reader_page.0 != view.chapter {
+    *reader_page = (view.chapter, 0);
+  }
+  if let Some((block, char_index)) = view.target {
+    if let Some(Some(top)) = pagination.block_tops.get(block) {
+      let offset = cached
+        .layouts
+        .get(&block)
+        .map_or(0.0, |layout| char_top(layout, char_index));
+      reader_page.1 = pagination.page_at(top + offset);
+    }
+  }
+  // Pages are turned together, starting from the first
+  reader_page.1 = reader_page.1.min(pagination.starts.len() - 1);
+  reader_page.1 -= reader_page.1 % pages_shown;
+
+  page_turn_zones(ui, rect, text_rect, page_turn);
+
+  (0..pages_shown)
+    .map(|side| {
+      let page_rect = Rect::from_min_size(
+        text_rect.min + vec2((page_size.x + zone_width) * side as f32, 0.0),
+        page_size,
+      );
+      let shown = pagination.page_range(reader_page.1 + side);
+
+      let mut child = ui.child_ui_with_id_source(
+        Rect::from_min_size(
+          page_rect.min - vec2(0.0, shown.start),
+          vec2(page_size.x, f32::INFINITY),
+        ),
+        *ui.layout(),
+        ("page", side),
+      );
+      child.set_clip_rect(
+        Rect::from_min_size(
+          page_rect.min,
+          vec2(page_size.x, shown.end - shown.start),
+        )
+        .expand2(vec2(zone_width / 2.0, 0.0))
+        .intersect(ui.clip_rect()),
+      );
+      child
+    })
+    .collect()
+}
+
+/// Height of each block of the current chapter on pages of a size, and the
+/// tops of its lines (which pages can break between), or `None` for blocks
+/// that are hidden
+fn measure_blocks(
+  ui: &egui::Ui,
+  view: &ChapterView,
+  layouts: &mut HashMap<usize, BlockLayout>,
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  images: &mut HashMap<String, Option<RetainedImage>>,
+  page_size: Vec2,
+) -> Vec<Option<(f32, Vec<f32>)>> {
+  let mut measures = Vec::new();
+  for (index, block) in view.document.blocks.iter().enumerate() {
+    if is_hidden(block, view.style) {
+      measures.push(None);
+      continue;
+    }
+
+    let image = match &block.kind {
+      BlockKind::Image { src, .. } => {
+        load_chapter_image(images, book, &view.path, src)
+      }
+      _ => None,
+    };
+    measures.push(Some(match (&block.kind, image) {
+      (BlockKind::Rule, _) => (RULE_HEIGHT, vec![0.0]),
+      (_, Some(image)) => {
+        (image_size(image, page_size.x, page_size.y).y, vec![0.0])
+      }
+      _ => {
+        let (left, right) = block_margins(block, &view.font_id, view.css);
+        let layout = block_layout(
+          ui,
+          layouts,
+          index,
+          block,
+          &view.font_id,
+          view.theme,
+          wrap_width(page_size.x, left, right, &view.font_id),
+          &block_highlights(&view.highlights, index),
+          view.css,
+        );
+        let rows = layout.galley.rows.iter();
+
+        (
+          layout.galley.size().y,
+          rows.map(|row| row.rect.min.y).collect(),
+        )
+      }
+    }));
+  }
+
+  measures
+}
+
+/// Clicking either side of the page turns it
+fn page_turn_zones(
+  ui: &egui::Ui,
+  rect: Rect,
+  text_rect: Rect,
+  page_turn: &mut Option<bool>,
+) {
+  for (zone, forwards) in [
+    (Rect::from_min_max(rect.min, text_rect.left_bottom()), false),
+    (Rect::from_min_max(text_rect.right_top(), rect.max), true),
+  ] {
+    let response =
+      ui.interact(zone, ui.id().with(("page turn", forwards)), Sense::click());
+    if response.hovered() {
+      ui.output().cursor_icon = CursorIcon::PointingHand;
+    }
+    if response.clicked() {
+      *page_turn = Some(forwards);
+    }
+  }
+}
+
+/// Whether the text is laid out differently than the last time it was drawn
+/// (e.g. the window was resized), keeping track of how it is now
+fn view_changed(
+  ui: &egui::Ui,
+  view: &ChapterView,
+  last_view: &mut Option<u64>,
+) -> bool {
+  let view_key = {
+    let mut hasher = DefaultHasher::new();
+    pagination_key(
+      &view.font_id,
+      vec2(ui.available_width(), view.page_height),
+      view.line_spacing,
+      view.style,
+    )
+    .hash(&mut hasher);
+    view.style.reading_mode.hash(&mut hasher);
+    hasher.finish()
+  };
+  let changed = matches!(last_view, Some(key) if *key != view_key);
+  *last_view = Some(view_key);
+
+  changed
+}
+
+/// The blocks of the current chapter drawn in a ui (the whole view, or one
+/// page in the paginated mode)
+#[allow(clippy::too_many_arguments)]
+fn blocks_ui(
+  ui: &mut egui::Ui,
+  view: &ChapterView,
+  cached: &mut CachedChapter,
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  images: &mut HashMap<String, Option<RetainedImage>>,
+  book_userdata: &mut LocalBookInfo,
+  ui_state: &mut UIState,
+  page_numbers: &PageNumbers,
+  shown: &mut BlocksShown,
+) {
+  // Only blocks within (or near) the visible area are laid out and painted;
+  // the rest are replaced by empty space of their last measured (or else
+  // estimated) height
+  let visible = ui.clip_rect().expand(ui.clip_rect().height());
+  let reading_line = ui.clip_rect().top();
+  let size_key = size_key(&view.font_id, ui.available_width(), view.css);
+  let target_block = view.target.map(|(block, _)| block);
+  let mut skipped_height = 0.0;
+
+  for (line_number, block) in view.document.blocks.iter().enumerate() {
+    if is_hidden(block, view.style) {
+      continue;
+    }
+
+    let height = block_height(
+      ui,
+      &cached.heights,
+      size_key,
+      line_number,
+      block,
+      &view.font_id,
+      view.css,
+    );
+    let top = ui.available_rect_before_wrap().top() + skipped_height;
+    if Some(line_number) != target_block
+      && (top + height < visible.top() || top > visible.bottom())
+    {
+      skipped_height += height + view.line_spacing;
+      continue;
+    }
+    ui.add_space(skipped_height);
+    skipped_height = 0.0;
+
+    if block.kind == BlockKind::Rule {
+      let response = ui.add(egui::Separator::default().spacing(RULE_HEIGHT));
+      cached
+        .heights
+        .insert(line_number, (size_key, response.rect.height()));
+      continue;
+    }
+
+    let image = match &block.kind {
+      BlockKind::Image { src, .. } => {
+        load_chapter_image(images, book, &view.path, src)
+      }
+      _ => None,
+    };
+
+    let is_image = image.is_some();
+    let line_response = if let Some(image) = image {
+      let available_width = ui.available_width();
+      let size = image_size(image, available_width, view.max_image_height());
+
+      let (rect, response) =
+        ui.allocate_exact_size(vec2(available_width, size.y), Sense::click());
+      egui::Image::new(image.texture_id(ui.ctx()), size)
+        .paint_at(ui, Rect::from_center_size(rect.center(), size));
+
+      response
+    } else {
+      text_block_ui(
+        ui,
+        view,
+        &mut cached.layouts,
+        line_number,
+        block,
+        &mut ui_state.reader_selection,
+        shown,
+      )
+    };
+
+    cached
+      .heights
+      .insert(line_number, (size_key, line_response.rect.height()));
+
+    let text_layout = cached.layouts.get(&line_number).filter(|_| !is_image);
+
+    if view.style.show_page_numbers {
+      page_number_labels(
+        ui,
+        view,
+        page_numbers,
+        line_number,
+        block,
+        &line_response,
+        text_layout,
+      );
+    }
+    if Some(line_number) == target_block {
+      let offset = match (view.target, text_layout) {
+        (Some((_, char_index)), Some(layout)) => char_top(layout, char_index),
+        _ => 0.0,
+      };
+      shown.target_rect = Some(line_response.rect.translate(vec2(0.0, offset)));
     }
+    if shown.reading_position.is_none()
+      && line_response.rect.bottom() > reading_line
+    {
+      let char_index = text_layout.map_or(0, |layout| {
+        let y = reading_line - line_response.rect.top();
+        text_index(
+          layout,
+          layout.galley.cursor_from_pos(vec2(0.0, y)).ccursor.index,
+        )
+      });
+      shown.reading_position = Some((line_number, char_index));
+    }
+    if line_response.rect.top() < ui.clip_rect().bottom() {
+      shown.shown_end = Some((line_number, block.char_count()));
+    }
+
+    block_context_menu(
+      line_response,
+      view,
+      line_number,
+      block,
+      book_userdata,
+      ui_state,
+    );
+  }
+
+  // Keeps the full height of the chapter for the scroll bar
+  ui.add_space(skipped_height);
+}
+
+/// A block of text, which can be selected and has links that can be
+/// followed (or for notes, previewed)
+fn text_block_ui(
+  ui: &mut egui::Ui,
+  view: &ChapterView,
+  layouts: &mut HashMap<usize, BlockLayout>,
+  line_number: usize,
+  block: &Block,
+  selection: &mut Option<ReaderSelection>,
+  shown: &mut BlocksShown,
+) -> egui::Response {
+  let (left, right) = block_margins(block, &view.font_id, view.css);
+  let layout = block_layout(
+    ui,
+    layouts,
+    line_number,
+    block,
+    &view.font_id,
+    view.theme,
+    wrap_width(ui.available_width(), left, right, &view.font_id),
+    &block_highlights(&view.highlights, line_number),
+    view.css,
+  );
+  let galley = Arc::clone(&layout.galley);
+
+  let (rect, line_response) = ui.allocate_exact_size(
+    vec2(ui.available_width(), galley.size().y),
+    Sense::click_and_drag(),
+  );
+  let text_position = rect.min + vec2(left + align_offset(&galley), 0.0);
+  paint_block_text(
+    ui,
+    block,
+    &layout,
+    text_position,
+    &view.font_id,
+    view.theme,
+  );
+
+  handle_selection(
+    ui,
+    view,
+    &layout,
+    line_number,
+    block,
+    &line_response,
+    text_position,
+    selection,
+  );
+
+  // Links
+  if let Some(pointer) = line_response.hover_pos() {
+    if let Some(span) =
+      char_index_at(&layout, pointer - text_position.to_vec2())
+        .and_then(|index| block.span_at(index))
+        .filter(|span| span.link.is_some())
+    {
+      let href = span.link.clone().unwrap_or_default();
+      ui.output().cursor_icon = CursorIcon::PointingHand;
+
+      // Notes are previewed rather than followed
+      if span.is_noteref {
+        shown.hovered_noteref = Some((href, pointer, line_response.clicked()));
+      } else if line_response.clicked() {
+        shown.clicked_link = Some(href);
+      }
+    }
+  }
+
+  line_response
+}
+
+/// Starts a selection by dragging over a block, or selects a word by double
+/// clicking it or the whole block by triple clicking, and extends the
+/// selection being dragged to the pointer
+#[allow(clippy::too_many_arguments)]
+fn handle_selection(
+  ui: &egui::Ui,
+  view: &ChapterView,
+  layout: &BlockLayout,
+  line_number: usize,
+  block: &Block,
+  line_response: &egui::Response,
+  text_position: Pos2,
+  selection: &mut Option<ReaderSelection>,
+) {
+  if line_response.hovered() {
+    ui.output().cursor_icon = CursorIcon::Text;
+  }
+  let pointer = match ui.input().pointer.interact_pos() {
+    Some(pointer) => pointer,
+    None => return,
+  };
+
+  let now = ui.input().time;
+  let position = (
+    line_number,
+    text_index(
+      layout,
+      layout
+        .galley
+        .cursor_from_pos(pointer - text_position)
+        .ccursor
+        .index,
+    ),
+  );
+  let is_triple_click = matches!(
+    selection.as_ref(),
+    Some(ReaderSelection {
+      double_clicked: Some((time, block)),
+      ..
+    }) if now - *time < 0.5 && *block == line_number
+  );
+
+  if line_response.drag_started()
+    && ui.input().pointer.primary_down()
+    && !is_triple_click
+  {
+    *selection = Some(ReaderSelection {
+      dragging: true,
+      ..ReaderSelection::new(view.chapter, position)
+    });
+  } else if line_response.double_clicked() {
+    let word = word_at(
+      &block.text(),
+      char_index_at(layout, pointer - text_position.to_vec2())
+        .unwrap_or(position.1),
+    );
+    *selection = Some(ReaderSelection {
+      anchor: (line_number, word.start),
+      head: (line_number, word.end),
+      double_clicked: Some((now, line_number)),
+      ..ReaderSelection::new(view.chapter, position)
+    });
+  } else if line_response.clicked() && is_triple_click {
+    *selection = Some(ReaderSelection {
+      anchor: (line_number, 0),
+      head: (line_number, block.char_count()),
+      ..ReaderSelection::new(view.chapter, position)
+    });
+  }
+
+  // The gap below a block counts as part of it
+  if let Some(selection) =
+    selection.as_mut().filter(|selection| selection.dragging)
+  {
+    let rect = line_response.rect;
+    if pointer.y >= rect.top() && pointer.y < rect.bottom() + view.line_spacing
+    {
+      selection.head = position;
+    }
+  }
+}
+
+/// Stops dragging the selection once the button is let go of, clears it
+/// when empty or on Escape, and copies it
+fn end_selection(
+  ui: &egui::Ui,
+  selection: &mut Option<ReaderSelection>,
+  selected_text: Option<&String>,
+) {
+  if let Some(selection) = selection.as_mut() {
+    if !ui.input().pointer.primary_down() {
+      selection.dragging = false;
+    }
+  }
+  if matches!(selection, Some(s) if s.is_empty() && !s.dragging)
+    || ui.input().key_pressed(egui::Key::Escape)
+  {
+    *selection = None;
+  }
+  if let Some(text) = selected_text {
+    if ui
+      .input()
+      .events
+      .iter()
+      .any(|event| matches!(event, egui::Event::Copy))
+    {
+      ui.output().copied_text = text.clone();
+    }
+  }
+}
+
+/// Page numbers beside the lines of a block that pages start on
+fn page_number_labels(
+  ui: &egui::Ui,
+  view: &ChapterView,
+  page_numbers: &PageNumbers,
+  line_number: usize,
+  block: &Block,
+  line_response: &egui::Response,
+  text_layout: Option<&BlockLayout>,
+) {
+  let start = view.start + view.document.char_offset(line_number, 0);
+  for (offset, label) in
+    page_numbers.starts_in(start..start + block.char_count())
+  {
+    let y = text_layout.map_or(0.0, |layout| char_top(layout, offset - start));
+    ui.painter().text(
+      pos2(
+        line_response.rect.right() + 4.0,
+        line_response.rect.top() + y,
+      ),
+      Align2::LEFT_TOP,
+      label,
+      FontId::new(view.style.font_size * 0.6, view.style.font_family.clone()),
+      view.theme.text_color.linear_multiply(0.5),
+    );
+  }
+}
+
+/// Context menu of a block, to highlight, copy, add a note to or look up the
+/// selection, or the whole block if nothing is selected
+fn block_context_menu(
+  line_response: egui::Response,
+  view: &ChapterView,
+  line_number: usize,
+  block: &Block,
+  book_userdata: &mut LocalBookInfo,
+  ui_state: &mut UIState,
+) {
+  let range = || {
+    view.selected_range.clone().unwrap_or_else(|| {
+      TextRange::block(view.chapter, &view.document, line_number)
+    })
+  };
+
+  line_response.context_menu(|ui| {
+    ui.horizontal(|ui| {
+      for (index, color) in [
+        view.theme.highlight_color,
+        Color32::from_rgb(255, 150, 138),
+        Color32::from_rgb(255, 209, 138),
+        Color32::from_rgb(138, 255, 150),
+        Color32::from_rgb(150, 138, 255),
+      ]
+      .iter()
+      .enumerate()
+      {
+        // Separator placed after the first option to indicate the user's
+        // custom selected highlight color
+        if index == 1 {
+          ui.separator();
+          ui.add_space(6.0);
+        }
+
+        // Button & logic
+        if ui
+          .button(RichText::new("\u{25CF}").color(*color).size(32.0))
+          .clicked()
+        {
+          toggle_highlight(
+            &mut book_userdata.highlights,
+            range(),
+            *color,
+            view.chapter,
+            &view.document,
+          );
+
+          ui_state.reader_selection = None;
+          ui.close_menu();
+        }
+      }
+    });
+
+    // Actions apply to the selection if there is one, or the whole line
+    // otherwise
+    let text = view.selected_text.clone().unwrap_or_else(|| block.text());
+
+    if ui.button("Copy").clicked() {
+      ui.output().copied_text = text.clone();
+      ui.close_menu();
+    }
+
+    if ui.button("Add Note").clicked() {
+      let note = Note::new(range(), text.clone());
+
+      // Adds the note if one is not already in place for the specified range
+      if !book_userdata.notes.contains(&note) {
+        book_userdata.notes.push(note);
+        ui_state.left_panel_state = PanelState::Notes;
+
+        ui_state.reader_selection = None;
+        ui.close_menu();
+      }
+    }
+
+    if view.selected_text.is_some() && ui.button("Look Up").clicked() {
+      ui_state.confirm_external_link = Some(format!(
+        "https://en.wiktionary.org/wiki/Special:Search?search={}",
+        percent_encode(text.trim())
+      ));
+      ui.close_menu();
+    }
+  });
+}
+
+/// Keeps track of where the reader is: the position at the top of the view,
+/// the progress through the book, and the text that is shown
+fn record_reading_position(
+  state: &mut Pend,
+  uuid: &str,
+  document: &Document,
+  (block, char_index): (usize, usize),
+  shown_end: Option<(usize, usize)>,
+  now: f64,
+) {
+  let book_userdata = state.book_userdata.get_mut(uuid).unwrap();
+  let lengths = &state.chapter_lengths[uuid];
+  let chapter = book_userdata.chapter;
+
+  book_userdata.position =
+    Some(TextPosition::new(chapter, document, block, char_index));
+
+  let offset =
+    book_offset(lengths, chapter, document.char_offset(block, char_index));
+  book_userdata.progress = book_progress(lengths, offset);
+
+  let (end_block, end_char) = shown_end.unwrap_or((block, char_index));
+  state.ui_state.reader_shown = Some(TextRange {
+    start: book_userdata.position.clone().unwrap_or_default(),
+    end: TextPosition::new(chapter, document, end_block, end_char),
+  });
+
+  measure_reading_speed(
+    &mut state.ui_state.reading_mark,
+    &mut state.reading_speed,
+    uuid,
+    offset,
+    now,
+  );
+}
+
+/// Moving forwards through the book counts towards the measured reading
+/// speed, from the last (book, offset, time) it was marked at
+fn measure_reading_speed(
+  mark: &mut Option<(String, usize, f64)>,
+  speed: &mut ReadingSpeed,
+  uuid: &str,
+  offset: usize,
+  now: f64,
+) {
+  match mark {
+    Some((book, last, _)) if book == uuid && *last == offset => {}
+    Some((book, last, time)) if book == uuid && *last < offset => {
+      speed.record(offset - *last, now - *time);
+      *mark = Some((book.clone(), offset, now));
+    }
+    _ => *mark = Some((uuid.to_string(), offset, now)),
+  }
+}
+
+/// Footnote previews: a hovered note reference is previewed, and pinned open
+/// once clicked, and unpinned previews close once nothing is hovered
+fn update_footnote_popup(
+  popup: &mut Option<FootnotePopup>,
+  hovered_noteref: Option<(String, Pos2, bool)>,
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  document: &Document,
+  user_css: &str,
+) {
+  match hovered_noteref {
+    Some((href, pointer, clicked)) => {
+      let is_pinned = matches!(popup, Some(p) if p.pinned);
+
+      if popup.as_ref().map(|p| &p.href) != Some(&href)
+        && (!is_pinned || clicked)
+      {
+        *popup = Some(FootnotePopup {
+          blocks: note_blocks(book, document, &href, user_css),
+          href,
+          pinned: false,
+          position: pointer,
+        });
+      }
+      if clicked {
+        if let Some(popup) = popup {
+          popup.pinned = true;
+        }
+      }
+    }
+    None => {
+      if matches!(popup, Some(p) if !p.pinned) {
+        *popup = None;
+      }
+    }
+  }
+}
+
+/// Confirmation before leaving the program to open a link
+fn confirm_external_link_ui(state: &mut Pend, ui: &egui::Ui) {
+  if let Some(url) = state.ui_state.confirm_external_link.clone() {
+    egui::Window::new("Open Link")
+      .collapsible(false)
+      .resizable(false)
+      .anchor(Align2::CENTER_CENTER, vec2(0.0, 0.0))
+      .show(ui.ctx(), |ui| {
+        ui.label("This link leads outside of the book:");
+        ui.label(RichText::new(&url).monospace());
+
+        ui.horizontal(|ui| {
+          if ui.button("Open").clicked() {
+            ui.output().open_url = Some(OpenUrl::new_tab(&url));
+            state.ui_state.confirm_external_link = None;
+          }
+          if ui.button("Cancel").clicked()
+            || ui.ctx().input().key_pressed(egui::Key::Escape)
+          {
+            state.ui_state.confirm_external_link = None;
+          }
+        });
+      });
+  }
+}
+
+/// Search bar for finding text within the open book, along with a list of
+/// the matches that were found
+fn search_ui(
+  ui: &mut egui::Ui,
+  search: &mut BookSearch,
+  uuid: &str,
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  toc: &[TocEntry],
+  goto_target: &mut Option<GotoTarget>,
+) {
+  // Whether to move forwards or backwards through the matches
+  let mut step = None;
+
+  ui.horizontal(|ui| {
+    let response = ui.add(
+      egui::TextEdit::singleline(&mut search.query)
+        .hint_text("Search this book"),
+    );
+    if search.focus {
+      response.request_focus();
+      search.focus = false;
+    }
+    if response.lost_focus() && ui.input().key_pressed(egui::Key::Enter) {
+      step = Some(!ui.input().modifiers.shift);
+      // Stay in the field so Enter can be pressed again for the next match
+      response.request_focus();
+    }
+
+    ui.checkbox(&mut search.options.case_sensitive, "Aa")
+      .on_hover_text("Match case");
+    ui.checkbox(&mut search.options.whole_word, "Word")
+      .on_hover_text("Match whole words only");
+    ui.checkbox(&mut search.options.regex, ".*")
+      .on_hover_text("Regular expression");
+
+    if ui
+      .button("\u{2191}")
+      .on_hover_text("Previous match (Ctrl+Shift+G)")
+      .clicked()
+    {
+      step = Some(false);
+    }
+    if ui
+      .button("\u{2193}")
+      .on_hover_text("Next match (Ctrl+G)")
+      .clicked()
+    {
+      step = Some(true);
+    }
+
+    if let Some(error) = &search.error {
+      ui.label(RichText::new(error.lines().last().unwrap_or_default()).small());
+    } else if !search.is_stale(uuid) {
+      match search.current {
+        _ if search.hits.is_empty() => ui.label("No matches"),
+        Some(current) => {
+          ui.label(format!("{} / {}", current + 1, search.hits.len()))
+        }
+        None => ui.label(format!("{} matches", search.hits.len())),
+      };
+    }
+  });
+
+  if ui.input().modifiers.command && ui.input().key_pressed(egui::Key::G) {
+    step = Some(!ui.input().modifiers.shift);
+  }
+
+  if let Some(forwards) = step {
+    if search.is_stale(uuid) {
+      match build_pattern(&search.query, search.options) {
+        Ok(pattern) if !search.query.is_empty() => {
+          search.hits = search_book(book, toc, &pattern);
+          search.error = None;
+        }
+        Ok(_) => {
+          search.hits.clear();
+          search.error = None;
+        }
+        Err(error) => {
+          search.hits.clear();
+          search.error = Some(error.to_string());
+        }
+      }
+      search.current = None;
+      search.searched =
+        Some((uuid.to_string(), search.query.clone(), search.options));
+    }
+
+    if let Some(hit) = search.step(forwards) {
+      *goto_target = Some(GotoTarget::position(hit.position.clone()));
+    }
+  }
+
+  if search.hits.is_empty() || search.is_stale(uuid) {
+    return;
+  }
+
+  egui::CollapsingHeader::new("Matches")
+    .default_open(true)
+    .show(ui, |ui| {
+      ScrollArea::vertical()
+        .id_source("Search Matches")
+        .max_height(ui.available_height() / 4.0)
+        .auto_shrink([false, true])
+        .show(ui, |ui| {
+          let mut previous_chapter = None;
+          let mut clicked = None;
+
+          for (index, hit) in search.hits.iter().enumerate() {
+            // Matches are grouped by chapter
+            if previous_chapter != Some(hit.chapter) {
+              ui.label(RichText::new(&hit.chapter_title).strong());
+              previous_chapter = Some(hit.chapter);
+            }
+
+            let job = match_layout_job(&hit.context, &hit.context_match, ui);
+
+            if ui
+              .selectable_label(search.current == Some(index), job)
+              .clicked()
+            {
+              clicked = Some(index);
+            }
+          }
+
+          if let Some(index) = clicked {
+            search.current = Some(index);
+            *goto_target =
+              Some(GotoTarget::position(search.hits[index].position.clone()));
+          }
+        });
+    });
+}
+
+/// Lays out a search result with the matching part of it highlighted
+pub fn match_layout_job(
+  text: &str,
+  found: &Range<usize>,
+  ui: &egui::Ui,
+) -> LayoutJob {
+  let font_id = FontId::proportional(16.0);
+  let color = ui.visuals().text_color();
+  let match_color = ui.visuals().selection.bg_fill;
+
+  let mut job = LayoutJob::default();
+  for (range, background) in [
+    (0..found.start, Color32::TRANSPARENT),
+    (found.clone(), match_color),
+    (found.end..text.len(), Color32::TRANSPARENT),
+  ] {
+    job.append(
+      &text[range],
+      0.0,
+      TextFormat {
+        font_id: font_id.clone(),
+        color,
+        background,
+        ..Default::default()
+      },
+    );
+  }
+
+  job
+}
+
+/// Highlights a range of text. Existing highlights that overlap the range are
+/// replaced, or if any of them already has the same color, only removed
+fn toggle_highlight(
+  highlights: &mut Vec<Highlight>,
+  range: TextRange,
+  color: Color32,
+  chapter: usize,
+  document: &Document,
+) {
+  let new_ranges = range.block_ranges(chapter, document);
+  let overlaps = |highlight: &Highlight| {
+    highlight.range == range
+      || highlight.range.block_ranges(chapter, document).iter().any(
+        |(block, existing)| {
+          new_ranges.iter().any(|(new_block, new)| {
+            block == new_block
+              && existing.start < new.end
+              && new.start < existing.end
+          })
+        },
+      )
+  };
+
+  let is_removal = highlights
+    .iter()
+    .any(|highlight| highlight.color == color && overlaps(highlight));
+
+  highlights.retain(|highlight| !overlaps(highlight));
+  if !is_removal {
+    highlights.push(Highlight { range, color });
+  }
+}
+
+/// Resolves an internal link (from within the current chapter) into a
+/// position in the book
+fn link_target(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  href: &str,
+) -> Option<GotoTarget> {
+  let chapter =
+    spine_index_of(book, &resolve_href(&current_chapter_path(book), href))?;
+
+  Some(GotoTarget::anchor(
+    chapter,
+    href
+      .split_once('#')
+      .map(|(_, anchor)| percent_decode(anchor)),
+  ))
+}
+
+/// Finds the blocks of the note a note reference points to
+fn note_blocks(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  current_document: &Document,
+  href: &str,
+  user_css: &str,
+) -> Vec<Block> {
+  let anchor = match href.split_once('#') {
+    Some((_, anchor)) => percent_decode(anchor),
+    None => return Vec::new(),
+  };
+  let chapter =
+    spine_index_of(book, &resolve_href(&current_chapter_path(book), href));
+
+  if chapter.is_none() || chapter == Some(book.get_current_page()) {
+    current_document
+      .note_blocks(&anchor)
+      .into_iter()
+      .cloned()
+      .collect()
+  } else {
+    let mut user_stylesheet = Stylesheet::default();
+    user_stylesheet.extend_user(user_css);
+
+    chapter
+      .and_then(|chapter| {
+        user_styled_chapter_document(book, chapter, &user_stylesheet)
+      })
+      .map(|document| {
+        document.note_blocks(&anchor).into_iter().cloned().collect()
+      })
+      .unwrap_or_default()
+  }
+}
+
+/// Displays the contents of the footnote being previewed (if any)
+fn show_footnote_popup(state: &mut Pend, ui: &mut egui::Ui) {
+  let popup = match &state.ui_state.footnote_popup {
+    Some(popup) => popup,
+    None => return,
+  };
+
+  let style = match state
+    .selected_book_uuid
+    .as_ref()
+    .and_then(|uuid| state.book_userdata.get(uuid))
+  {
+    Some(book_info) => state.book_style.for_book(book_info),
+    None => state.book_style.clone(),
+  };
+  let theme = &state.theme;
+  let font_id = FontId::new(style.font_size * 0.8, style.font_family.clone());
+  let width = (font_id.size * 20.0).min(ui.available_width());
+
+  let mut close = false;
+  let mut go_to_note = false;
+
+  egui::Area::new("Footnote Popup")
+    .fixed_pos(popup.position + vec2(0.0, font_id.size))
+    .order(egui::Order::Foreground)
+    .show(ui.ctx(), |ui| {
+      egui::Frame::popup(ui.style())
+        .fill(theme.page_color)
+        .show(ui, |ui| {
+          ui.set_max_width(width);
+
+          if popup.blocks.is_empty() {
+            ui.colored_label(theme.text_color, "Note could not be found");
+          }
+          let fonts = ui.fonts().families();
+          for block in &popup.blocks {
+            let job = block_layout_job(
+              block,
+              &font_id,
+              theme,
+              &[],
+              width,
+              !style.ignore_publisher_styles,
+              &fonts,
+            );
+            let wraps = line_wraps(&ui.fonts(), &job);
+            ui.label(with_line_breaks(job, &wraps));
+          }
+
+          if popup.pinned {
+            ui.separator();
+            ui.horizontal(|ui| {
+              close = ui.button("Close").clicked();
+              go_to_note = ui.button("Go to Note").clicked();
+            });
+          }
+        });
+    });
+
+  if go_to_note {
+    if let Some(uuid) = &state.selected_book_uuid {
+      if let Some(target) = state
+        .epub_cache
+        .get_mut(uuid)
+        .and_then(|book| link_target(book, &popup.href))
+      {
+        state.goto_target = Some(target);
+      }
+    }
+  }
+  if close || go_to_note || ui.ctx().input().key_pressed(egui::Key::Escape) {
+    state.ui_state.footnote_popup = None;
+  }
+}
+
+/// The text within ranges of blocks, with each block on its own line
+fn range_text(document: &Document, ranges: &[(usize, Range<usize>)]) -> String {
+  ranges
+    .iter()
+    .map(|(block, range)| {
+      document.blocks[*block]
+        .text()
+        .chars()
+        .skip(range.start)
+        .take(range.len())
+        .collect::<String>()
+    })
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+/// The range (in characters) of the word containing a character. Characters
+/// that aren't part of a word are selected on their own
+fn word_at(text: &str, index: usize) -> Range<usize> {
+  let chars: Vec<char> = text.chars().collect();
+  let is_word = |c: &char| {
+    c.is_alphanumeric() || matches!(c, '\'' | '\u{2019}' | '-' | '_')
+  };
+
+  if !matches!(chars.get(index), Some(c) if is_word(c)) {
+    return index.min(chars.len())..(index + 1).min(chars.len());
+  }
+
+  let start = chars[..index]
+    .iter()
+    .rposition(|c| !is_word(c))
+    .map_or(0, |position| position + 1);
+  let end = chars[index..]
+    .iter()
+    .position(|c| !is_word(c))
+    .map_or(chars.len(), |position| index + position);
+
+  start..end
+}
+
+/// Finds the index (in the block's text) of the character under a position
+/// relative to a block's galley
+fn char_index_at(layout: &BlockLayout, position: Pos2) -> Option<usize> {
+  let galley = &layout.galley;
+  if !galley.rect.contains(position) {
+    return None;
+  }
+
+  let cursor = galley.cursor_from_pos(position.to_vec2());
+  let index = cursor.ccursor.index;
+
+  // The cursor is placed at the closest gap between characters, so it may be
+  // after the character that is actually under the position
+  if index > 0 && galley.pos_from_cursor(&cursor).min.x > position.x {
+    Some(text_index(layout, index - 1))
+  } else {
+    Some(text_index(layout, index))
+  }
+}
+
+/// The index in a block's galley of a character of the block's text, which
+/// is moved along by the newlines added where its lines wrap
+fn galley_index(layout: &BlockLayout, char_index: usize) -> usize {
+  char_index
+    + layout
+      .wraps
+      .iter()
+      .filter(|wrap| **wrap <= char_index)
+      .count()
+}
+
+/// The index in a block's text of a character in its galley. The newlines
+/// added where lines wrap count as the character after them
+fn text_index(layout: &BlockLayout, galley_index: usize) -> usize {
+  let added = layout
+    .wraps
+    .iter()
+    .enumerate()
+    .take_while(|(count, wrap)| *wrap + count < galley_index)
+    .count();
+  galley_index - added
+}
+
+/// Hash of everything (apart from the block itself) that the layout of a
+/// block depends on
+fn layout_key(
+  font_id: &FontId,
+  theme: &DocumentColors,
+  wrap_width: f32,
+  highlights: &[(Range<usize>, Color32)],
+  css: bool,
+) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  font_id.hash(&mut hasher);
+  theme.text_color.hash(&mut hasher);
+  theme.link_color.hash(&mut hasher);
+  wrap_width.to_bits().hash(&mut hasher);
+  highlights.hash(&mut hasher);
+  css.hash(&mut hasher);
+  hasher.finish()
+}
+
+/// Gets the layout of a block's text, reusing the last one made for it unless
+/// something it depends on has changed
+#[allow(clippy::too_many_arguments)]
+fn block_layout(
+  ui: &egui::Ui,
+  layouts: &mut HashMap<usize, BlockLayout>,
+  index: usize,
+  block: &Block,
+  font_id: &FontId,
+  theme: &DocumentColors,
+  wrap_width: f32,
+  highlights: &[(Range<usize>, Color32)],
+  css: bool,
+) -> BlockLayout {
+  let key = layout_key(font_id, theme, wrap_width, highlights, css);
+
+  match layouts.get(&index) {
+    Some(layout) if layout.key == key => layout.clone(),
+    _ => {
+      let job = block_layout_job(
+        block,
+        font_id,
+        theme,
+        highlights,
+        wrap_width,
+        css,
+        &ui.fonts().families(),
+      );
+      let wraps = line_wraps(&ui.fonts(), &job);
+      let bold_galley = bold_overlay_job(&job, block, css).map(|bold_job| {
+        ui.fonts().layout_job(with_line_breaks(bold_job, &wraps))
+      });
+      let galley = ui.fonts().layout_job(with_line_breaks(job, &wraps));
+
+      let layout = BlockLayout {
+        key,
+        galley,
+        bold_galley,
+        wraps,
+      };
+      layouts.insert(index, layout.clone());
+      layout
+    }
+  }
+}
+
+/// Where the lines of a layout are wrapped: before the characters at these
+/// indices, chosen among the places the Unicode line breaking algorithm
+/// allows so that each line fits within the layout's wrap width. egui would
+/// otherwise only wrap lines at spaces (and Chinese ideographs). Lines that
+/// can't be broken anywhere are left for egui to wrap
+fn line_wraps(fonts: &Fonts, job: &LayoutJob) -> Vec<usize> {
+  // The text is laid out on as few lines as possible, left aligned, to
+  // measure where each character ends
+  let mut unwrapped = job.clone();
+  unwrapped.wrap_width = f32::INFINITY;
+  unwrapped.halign = Align::LEFT;
+  let galley = fonts.layout_job(unwrapped);
+
+  let opportunities = break_opportunities(&job.text);
+  let mut opportunities = opportunities.iter().peekable();
+  let mut wraps = Vec::new();
+  let mut index = 0;
+
+  for row in &galley.rows {
+    let mut line_start = 0.0;
+    // (Character the line can be broken before, where the next line would
+    // then start)
+    let mut last_opportunity = None;
+
+    for glyph in &row.glyphs {
+      while opportunities.next_if(|wrap| **wrap < index).is_some() {}
+      if opportunities.next_if_eq(&&index).is_some() {
+        last_opportunity = Some((index, glyph.pos.x));
+      }
+
+      if glyph.max_x() - line_start > job.wrap_width {
+        if let Some((wrap, x)) = last_opportunity.take() {
+          wraps.push(wrap);
+          line_start = x;
+        }
+      }
+      index += 1;
+    }
+
+    if row.ends_with_newline {
+      index += 1;
+    }
+  }
+
+  wraps
+}
+
+/// Adds a newline to a layout before each of the characters at `wraps`. Each
+/// newline is part of the section before it
+fn with_line_breaks(mut job: LayoutJob, wraps: &[usize]) -> LayoutJob {
+  if wraps.is_empty() {
+    return job;
+  }
+
+  // Byte offsets (in the original text) the newlines are added at
+  let mut added = Vec::new();
+  let mut text = String::with_capacity(job.text.len() + wraps.len());
+  let mut wraps = wraps.iter().peekable();
+  for (index, (byte, character)) in job.text.char_indices().enumerate() {
+    if wraps.next_if_eq(&&index).is_some() {
+      added.push(byte);
+      text.push('\n');
+    }
+    text.push(character);
+  }
+
+  let moved = |offset: usize| {
+    offset + added.iter().filter(|byte| **byte <= offset).count()
+  };
+  for section in &mut job.sections {
+    section.byte_range =
+      moved(section.byte_range.start)..moved(section.byte_range.end);
+  }
+  job.text = text;
+
+  job
+}
+
+/// The highlighted ranges within one block, out of (block, range, color)
+fn block_highlights(
+  highlights: &[(usize, Range<usize>, Color32)],
+  block: usize,
+) -> Vec<(Range<usize>, Color32)> {
+  highlights
+    .iter()
+    .filter(|(index, ..)| *index == block)
+    .map(|(_, range, color)| (range.clone(), *color))
+    .collect()
+}
+
+/// Size to draw an image at: scaled down to fit within a width (and height)
+fn image_size(image: &RetainedImage, max_width: f32, max_height: f32) -> Vec2 {
+  let size = image.size_vec2();
+
+  size * (max_width / size.x).min(max_height / size.y).min(1.0)
+}
+
+/// Hash of everything the pages of a chapter depend on, besides its contents
+fn pagination_key(
+  font_id: &FontId,
+  page_size: Vec2,
+  line_spacing: f32,
+  style: &BookTextStyle,
+) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  font_id.hash(&mut hasher);
+  page_size.x.to_bits().hash(&mut hasher);
+  page_size.y.to_bits().hash(&mut hasher);
+  line_spacing.to_bits().hash(&mut hasher);
+  style.hide_footnotes.hash(&mut hasher);
+  style.ignore_publisher_styles.hash(&mut hasher);
+  style.user_css.hash(&mut hasher);
+  hasher.finish()
+}
+
+/// Splits a chapter into pages of a given height. Pages break between rows of
+/// text or between blocks, unless a single row (or image) is taller than a
+/// page.
+///
+/// `blocks` holds the height of each block and the offsets of the tops of its
+/// rows, or `None` for hidden blocks
+fn paginate(
+  key: u64,
+  blocks: &[Option<(f32, Vec<f32>)>],
+  spacing: f32,
+  page_height: f32,
+) -> Pagination {
+  let mut block_tops = Vec::with_capacity(blocks.len());
+  // Offsets that a page could start at
+  let mut breaks = Vec::new();
+  let mut top = 0.0;
+
+  for block in blocks {
+    match block {
+      Some((height, rows)) => {
+        block_tops.push(Some(top));
+        breaks.extend(rows.iter().map(|row| top + row));
+        top += height + spacing;
+      }
+      None => block_tops.push(None),
+    }
+  }
+
+  let height = (top - spacing).max(0.0);
+  breaks.push(height);
+
+  let page_height = page_height.max(1.0);
+  let mut starts = vec![0.0];
+  let mut start = 0.0;
+  let mut previous = 0.0;
+
+  for offset in breaks {
+    while offset - start > page_height {
+      start = if previous > start {
+        previous
+      } else {
+        start + page_height
+      };
+      starts.push(start);
+    }
+    previous = offset;
+  }
+
+  Pagination {
+    key,
+    starts,
+    block_tops,
+    height,
+  }
+}
+
+/// Number of pages in each chapter of a book. Chapters that have been split
+/// into pages with the same key are counted exactly, and the rest are
+/// estimated from their length
+fn chapter_page_counts(
+  lengths: &[usize],
+  chapters: &HashMap<usize, CachedChapter>,
+  key: u64,
+) -> Vec<usize> {
+  let exact = |chapter| {
+    chapters
+      .get(&chapter)
+      .and_then(|cached| cached.pages.as_ref())
+      .filter(|pagination| pagination.key == key)
+      .map(|pagination| pagination.starts.len())
+  };
+
+  let (characters, pages) = lengths
+    .iter()
+    .enumerate()
+    .filter_map(|(chapter, length)| Some((length, exact(chapter)?)))
+    .fold((0, 0), |(characters, pages), (length, count)| {
+      (characters + length, pages + count)
+    });
+  let per_page = (characters / pages.max(1)).max(1);
+
+  lengths
+    .iter()
+    .enumerate()
+    .map(|(chapter, length)| {
+      exact(chapter).unwrap_or_else(|| {
+        ((*length as f32 / per_page as f32).ceil() as usize).max(1)
+      })
+    })
+    .collect()
+}
+
+/// The (chapter, page) of a page counted from the start of a book
+fn locate_page(counts: &[usize], mut page: usize) -> (usize, usize) {
+  for (chapter, count) in counts.iter().enumerate() {
+    if page < *count {
+      return (chapter, page);
+    }
+    page -= count;
+  }
+
+  (counts.len().saturating_sub(1), usize::MAX)
+}
+
+/// Moves forwards (or backwards) by a number of pages, continuing into the
+/// next (or the end of the previous) chapter from either end of one
+fn turn_page(
+  reader_page: &mut (usize, usize),
+  chapter: &mut usize,
+  pages: usize,
+  chapters: usize,
+  step: usize,
+  forwards: bool,
+) {
+  let page = if reader_page.0 == *chapter {
+    reader_page.1
+  } else {
+    0
+  };
+
+  *reader_page = if forwards && page + step < pages {
+    (*chapter, page + step)
+  } else if forwards && *chapter + 1 < chapters {
+    *chapter += 1;
+    (*chapter, 0)
+  } else if !forwards && page >= step {
+    (*chapter, page - step)
+  } else if !forwards && *chapter > 1 {
+    // Clamped to the last page once the chapter has been split into pages
+    *chapter -= 1;
+    (*chapter, usize::MAX)
+  } else {
+    (*chapter, page)
+  };
+}
+
+/// Marks places (given as fractions of the way along it) on a slider
+fn slider_markers(
+  ui: &egui::Ui,
+  rect: Rect,
+  fractions: impl Iterator<Item = f32>,
+) {
+  // Sliders keep their handle within the rect, so its ends are inset
+  let handle_radius = rect.height() / 2.5;
+  let left = rect.left() + handle_radius;
+  let width = rect.width() - handle_radius * 2.0;
+  let stroke = Stroke::new(2.0, ui.visuals().selection.bg_fill);
+
+  for fraction in fractions {
+    let x = left + width * fraction.clamp(0.0, 1.0);
+    ui.painter()
+      .line_segment([pos2(x, rect.top()), pos2(x, rect.bottom())], stroke);
+  }
+}
+
+/// Offset of the top of the row a character is on, from the top of a block
+fn char_top(layout: &BlockLayout, char_index: usize) -> f32 {
+  let galley = &layout.galley;
+  let index = galley_index(layout, char_index);
+
+  galley
+    .pos_from_cursor(&galley.from_ccursor(CCursor::new(index)))
+    .min
+    .y
+}
+
+/// Draws a chapter before or after the current one, in the continuous mode.
+/// As with the current chapter, only the part of it that is visible is laid
+/// out; it can't be interacted with until it becomes the current chapter
+#[allow(clippy::too_many_arguments)]
+fn neighbour_chapter_ui(
+  ui: &mut egui::Ui,
+  cached: &mut CachedChapter,
+  chapter: usize,
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  images: &mut HashMap<String, Option<RetainedImage>>,
+  style: &BookTextStyle,
+  theme: &DocumentColors,
+  user_highlights: &[Highlight],
+) {
+  let document = Arc::clone(&cached.document);
+  let chapter_path = chapter_path(book, chapter);
+  let font_id = FontId::new(style.font_size, style.font_family.clone());
+  let line_spacing = ui.spacing().item_spacing.y;
+  let css = !style.ignore_publisher_styles;
+
+  let highlights = chapter_highlights(user_highlights, chapter, &document);
+
+  let visible = ui.clip_rect().expand(ui.clip_rect().height());
+  let size_key = size_key(&font_id, ui.available_width(), css);
+  let mut skipped_height = 0.0;
+
+  for (index, block) in document.blocks.iter().enumerate() {
+    if is_hidden(block, style) {
+      continue;
+    }
+
+    let height =
+      block_height(ui, &cached.heights, size_key, index, block, &font_id, css);
+    let top = ui.available_rect_before_wrap().top() + skipped_height;
+    if top + height < visible.top() || top > visible.bottom() {
+      skipped_height += height + line_spacing;
+      continue;
+    }
+    ui.add_space(skipped_height);
+    skipped_height = 0.0;
+
+    let image = match &block.kind {
+      BlockKind::Image { src, .. } => {
+        load_chapter_image(images, book, &chapter_path, src)
+      }
+      _ => None,
+    };
+
+    let rect = if block.kind == BlockKind::Rule {
+      ui.add(egui::Separator::default().spacing(RULE_HEIGHT)).rect
+    } else if let Some(image) = image {
+      let size = image_size(image, ui.available_width(), f32::INFINITY);
+      let (rect, _) = ui.allocate_exact_size(
+        vec2(ui.available_width(), size.y),
+        Sense::hover(),
+      );
+      egui::Image::new(image.texture_id(ui.ctx()), size)
+        .paint_at(ui, Rect::from_center_size(rect.center(), size));
+
+      rect
+    } else {
+      let (left, right) = block_margins(block, &font_id, css);
+      let layout = block_layout(
+        ui,
+        &mut cached.layouts,
+        index,
+        block,
+        &font_id,
+        theme,
+        wrap_width(ui.available_width(), left, right, &font_id),
+        &block_highlights(&highlights, index),
+        css,
+      );
+      let (rect, _) = ui.allocate_exact_size(
+        vec2(ui.available_width(), layout.galley.size().y),
+        Sense::hover(),
+      );
+      paint_block_text(
+        ui,
+        block,
+        &layout,
+        rect.min + vec2(left + align_offset(&layout.galley), 0.0),
+        &font_id,
+        theme,
+      );
+
+      rect
+    };
+
+    cached.heights.insert(index, (size_key, rect.height()));
+  }
+
+  ui.add_space(skipped_height);
+}
+
+/// Draws the laid out text of a block, along with its list marker
+fn paint_block_text(
+  ui: &egui::Ui,
+  block: &Block,
+  layout: &BlockLayout,
+  text_position: Pos2,
+  font_id: &FontId,
+  theme: &DocumentColors,
+) {
+  // List markers sit in the space left by the indent
+  if let BlockKind::ListItem {
+    marker: Some(marker),
+    ..
+  } = &block.kind
+  {
+    ui.painter().text(
+      text_position
+        - vec2(align_offset(&layout.galley) + font_id.size * 0.5, 0.0),
+      Align2::RIGHT_TOP,
+      marker,
+      font_id.clone(),
+      theme.text_color,
+    );
+  }
+
+  ui.painter()
+    .galley(text_position, Arc::clone(&layout.galley));
+
+  // egui has no bold fonts, so bold text is drawn a second time, offset
+  // slightly
+  if let Some(bold_galley) = &layout.bold_galley {
+    ui.painter().galley(
+      text_position + vec2(font_id.size / 30.0, 0.0),
+      Arc::clone(bold_galley),
+    );
+  }
+}
+
+/// Height of a block in the reader: the height it was last drawn at, if that
+/// was with the same font & width, or an estimate otherwise
+fn block_height(
+  ui: &egui::Ui,
+  heights: &HashMap<usize, (u64, f32)>,
+  size_key: u64,
+  index: usize,
+  block: &Block,
+  font_id: &FontId,
+  css: bool,
+) -> f32 {
+  match heights.get(&index) {
+    Some((key, height)) if *key == size_key => *height,
+    _ => estimate_height(ui, block, font_id, css),
+  }
+}
+
+/// Height of the whole of a chapter in the reader, as it is drawn while none
+/// of it is visible
+fn chapter_height(
+  ui: &egui::Ui,
+  cached: &CachedChapter,
+  style: &BookTextStyle,
+  font_id: &FontId,
+) -> f32 {
+  let css = !style.ignore_publisher_styles;
+  let size_key = size_key(font_id, ui.available_width(), css);
+
+  cached
+    .document
+    .blocks
+    .iter()
+    .enumerate()
+    .filter(|(_, block)| !is_hidden(block, style))
+    .map(|(index, block)| {
+      block_height(ui, &cached.heights, size_key, index, block, font_id, css)
+        + ui.spacing().item_spacing.y
+    })
+    .sum()
+}
+
+/// Hash of what the heights of blocks depend on, besides their contents and
+/// highlights
+fn size_key(font_id: &FontId, width: f32, css: bool) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  font_id.hash(&mut hasher);
+  width.to_bits().hash(&mut hasher);
+  css.hash(&mut hasher);
+  hasher.finish()
+}
+
+/// A guess at the height of a block that has not been laid out yet, assuming
+/// an average character is half as wide as the font is tall
+fn estimate_height(
+  ui: &egui::Ui,
+  block: &Block,
+  font_id: &FontId,
+  css: bool,
+) -> f32 {
+  match block.kind {
+    BlockKind::Rule => RULE_HEIGHT,
+    BlockKind::Image { .. } => ui.available_width() / 2.0,
+    _ => {
+      let size = block_font_size(block, font_id, css);
+      let (left, right) = block_margins(block, font_id, css);
+      let width = wrap_width(ui.available_width(), left, right, font_id);
+      let lines = (block.char_count() as f32 * size * 0.5 / width).ceil();
+
+      lines.max(1.0)
+        * ui
+          .fonts()
+          .row_height(&FontId::new(size, font_id.family.clone()))
+    }
+  }
+}
+
+/// Horizontal space to leave on the (left, right) of the text of a block,
+/// including the margins set by its styles
+fn block_margins(block: &Block, font_id: &FontId, css: bool) -> (f32, f32) {
+  let indent = match block.kind {
+    BlockKind::ListItem { depth, .. } => {
+      (depth + 1) as f32 * font_id.size * 2.0
+    }
+    BlockKind::Quote => font_id.size * 2.0,
+    _ => 0.0,
+  };
+  let block_css = block_css(block, css);
+
+  (
+    indent + block_css.margin_left.max(0.0) * font_id.size,
+    block_css.margin_right.max(0.0) * font_id.size,
+  )
+}
+
+/// The styles of a block: those of the publisher with the reader's over them,
+/// or (when the publisher's are ignored) only the reader's
+fn block_css(block: &Block, css: bool) -> &BlockCss {
+  if css {
+    &block.css
+  } else {
+    &block.user_css
+  }
+}
+
+/// Like [`block_css`], for a span of text
+fn span_css(span: &Span, css: bool) -> &SpanCss {
+  if css {
+    &span.css
+  } else {
+    &span.user_css
+  }
+}
+
+/// Width to lay out the text of a block at, between its margins. Margins
+/// never squeeze the text narrower than a few characters
+fn wrap_width(width: f32, left: f32, right: f32, font_id: &FontId) -> f32 {
+  (width - left - right).max((font_id.size * 4.0).min(width))
+}
+
+/// Whether a block is left out of the reader, as a hidden footnote or through
+/// its styles
+fn is_hidden(block: &Block, style: &BookTextStyle) -> bool {
+  (style.hide_footnotes && block.is_footnote)
+    || block_css(block, !style.ignore_publisher_styles).hidden
+}
+
+/// How far right of where a block's text starts its laid out text is drawn
+/// from: centered & right aligned text is laid out around its origin
+fn align_offset(galley: &Galley) -> f32 {
+  match galley.job.halign {
+    Align::Center => galley.job.wrap_width / 2.0,
+    Align::Max => galley.job.wrap_width,
+    Align::Min => 0.0,
+  }
+}
+
+/// The formatting of a span, with its styles applied
+fn span_style(span: &Span, css: bool) -> SpanStyle {
+  let mut style = span.style;
+  let span_css = span_css(span, css);
+
+  style.bold = span_css.bold.unwrap_or(style.bold);
+  style.italic = span_css.italic.unwrap_or(style.italic);
+  style.small_caps = span_css.small_caps.unwrap_or(style.small_caps);
+
+  style
+}
+
+/// Size of the text of a block: the reader's font size (unless its styles
+/// set another), scaled up for headings
+fn block_font_size(block: &Block, font_id: &FontId, css: bool) -> f32 {
+  let size = block_css(block, css)
+    .font_size
+    .map_or(font_id.size, |size| size.points(font_id.size));
+
+  size * block_size_multiplier(&block.kind)
+}
+
+/// Size multiplier applied to the body font for a given kind of block
+fn block_size_multiplier(kind: &BlockKind) -> f32 {
+  match kind {
+    BlockKind::Heading(1) => 1.75,
+    BlockKind::Heading(2) => 1.5,
+    BlockKind::Heading(3) => 1.25,
+    BlockKind::Heading(_) => 1.1,
+    _ => 1.0,
+  }
+}
+
+/// Creates the layout of a block, with the formatting of each of its spans.
+/// Fonts named by its styles (and those for its language) are used if they
+/// are among `fonts` (those that egui has)
+fn block_layout_job(
+  block: &Block,
+  font_id: &FontId,
+  theme: &DocumentColors,
+  highlights: &[(Range<usize>, Color32)],
+  wrap_width: f32,
+  css: bool,
+  fonts: &[FontFamily],
+) -> LayoutJob {
+  let color = theme.text_color;
+  let mut job = LayoutJob {
+    wrap_width,
+    ..Default::default()
+  };
+
+  let size = block_font_size(block, font_id, css);
+
+  // Justified text is left aligned, as egui would lose its first line indent
+  let block_css = block_css(block, css);
+  job.halign = match block_css.align {
+    Some(TextAlign::Center) => Align::Center,
+    Some(TextAlign::Right) => Align::RIGHT,
+    _ => Align::LEFT,
+  };
+  // Hanging indents stay within the block's margin
+  let mut indent = 0.0;
+  if job.halign == Align::LEFT {
+    indent = (block_css.indent * size).max(-block_css.margin_left * size);
+  }
+
+  if let BlockKind::Image { alt, .. } = &block.kind {
+    job.append(
+      &format!("[Image: {}]", alt),
+      0.0,
+      TextFormat {
+        font_id: FontId::new(
+          size,
+          family_for_language(
+            font_id.family.clone(),
+            block.language.as_deref(),
+            fonts,
+          ),
+        ),
+        color,
+        italics: true,
+        ..Default::default()
+      },
+    );
+    return job;
+  }
+
+  let mut offset = 0;
+  for span in &block.spans {
+    let span_style = span_style(span, css);
+    let color = span_css(span, css).color.unwrap_or(color);
+    let family = span_css(span, css)
+      .font_family
+      .iter()
+      .flatten()
+      .map(|name| css_font_family(name))
+      .find(|family| fonts.contains(family))
+      .unwrap_or_else(|| font_id.family.clone());
+    let mut format = TextFormat {
+      font_id: FontId::new(size, family),
+      color,
+      italics: span_style.italic,
+      ..Default::default()
+    };
+
+    if span_style.code || block.kind == BlockKind::Preformatted {
+      format.font_id = FontId::new(size * 0.9, FontFamily::Monospace);
+    }
+    // Characters the font doesn't have are drawn with the fallback fonts for
+    // the block's language
+    format.font_id.family = family_for_language(
+      format.font_id.family.clone(),
+      block.language.as_deref(),
+      fonts,
+    );
+    if span_style.superscript || span_style.subscript {
+      format.font_id.size *= 0.7;
+      format.valign = if span_style.superscript {
+        Align::TOP
+      } else {
+        Align::BOTTOM
+      };
+    }
+    if span_style.underline || span.link.is_some() {
+      format.underline = Stroke::new(size / 16.0, color);
+    }
+    // Links that leave the book stand out, unless their color is set
+    if matches!(span.link.as_deref(), Some(href) if is_external_link(href))
+      && span_css(span, css).color.is_none()
+    {
+      format.color = theme.link_color;
+      format.underline.color = theme.link_color;
+    }
+    if span_style.strikethrough {
+      format.strikethrough = Stroke::new(size / 16.0, color);
+    }
+
+    // Spans are split wherever a highlight starts or ends
+    let length = span.text.chars().count();
+    let mut boundaries = vec![0, length];
+    for (range, _) in highlights {
+      for boundary in [range.start, range.end] {
+        if boundary > offset && boundary < offset + length {
+          boundaries.push(boundary - offset);
+        }
+      }
+    }
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    for piece in boundaries.windows(2) {
+      let mut format = format.clone();
+      if let Some((_, background)) = highlights
+        .iter()
+        .rev()
+        .find(|(range, _)| range.contains(&(offset + piece[0])))
+      {
+        format.background = *background;
+      }
+
+      let text: String = span
+        .text
+        .chars()
+        .skip(piece[0])
+        .take(piece[1] - piece[0])
+        .collect();
+      append_text(&mut job, &text, span_style.small_caps, format);
+    }
+
+    offset += length;
+  }
+
+  if let Some(first) = job.sections.first_mut() {
+    first.leading_space = indent;
+  }
+
+  job
+}
+
+/// Appends text to a layout, emulating small caps (if needed) by replacing
+/// lowercase letters with smaller uppercase ones
+fn append_text(
+  job: &mut LayoutJob,
+  text: &str,
+  small_caps: bool,
+  format: TextFormat,
+) {
+  if !small_caps {
+    job.append(text, 0.0, format);
+    return;
+  }
+
+  let mut small_format = format.clone();
+  small_format.font_id.size *= 0.8;
+
+  let mut run = String::new();
+  let mut run_is_small = false;
+
+  for character in text.chars() {
+    let mut uppercase = character.to_uppercase();
+    // Only swap characters that stay a single character, so that character
+    // positions within the block are unaffected
+    let (character, is_small) =
+      match (character.is_lowercase(), uppercase.next(), uppercase.next()) {
+        (true, Some(upper), None) => (upper, true),
+        _ => (character, false),
+      };
+
+    if is_small != run_is_small && !run.is_empty() {
+      job.append(
+        &run,
+        0.0,
+        if run_is_small {
+          small_format.clone()
+        } else {
+          format.clone()
+        },
+      );
+      run.clear();
+    }
+
+    run_is_small = is_small;
+    run.push(character);
+  }
+
+  if !run.is_empty() {
+    job.append(&run, 0.0, if run_is_small { small_format } else { format });
+  }
+}
+
+/// Creates a copy of a layout where only bold text is visible, to be drawn
+/// over the original. Returns `None` if the block has no bold text
+fn bold_overlay_job(
+  job: &LayoutJob,
+  block: &Block,
+  css: bool,
+) -> Option<LayoutJob> {
+  let is_heading = matches!(block.kind, BlockKind::Heading(_));
+
+  // Small caps may change the length of characters in bytes (though not
+  // their number), so spans are found in the layout's text by character
+  let byte_offsets: Vec<usize> = job
+    .text
+    .char_indices()
+    .map(|(index, _)| index)
+    .chain([job.text.len()])
+    .collect();
+  let byte_offset =
+    |offset: usize| byte_offsets[offset.min(byte_offsets.len() - 1)];
+
+  // Headings are bold unless their styles say otherwise
+  let mut span_ranges = Vec::new();
+  let mut offset = 0;
+  for span in &block.spans {
+    let bold = span_css(span, css)
+      .bold
+      .unwrap_or(span.style.bold || is_heading);
+    let length = span.text.chars().count();
+    span_ranges.push((byte_offset(offset)..byte_offset(offset + length), bold));
+    offset += length;
+  }
+
+  if !span_ranges.iter().any(|(_, bold)| *bold) {
+    return None;
+  }
+
+  let mut bold_job = job.clone();
+  for section in &mut bold_job.sections {
+    section.format.background = Color32::TRANSPARENT;
+
+    let is_bold = span_ranges
+      .iter()
+      .any(|(range, bold)| *bold && range.contains(&section.byte_range.start));
+
+    if !is_bold {
+      section.format.color = Color32::TRANSPARENT;
+    }
+  }
+
+  Some(bold_job)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bold_text_is_found_after_small_caps_change_its_length() {
+    // The dotless i is two bytes long, but its uppercase form only one
+    let document = Document::from_xhtml(
+      "<body><p><span class=\"sc\">\u{131}\u{131}\u{131}\u{131}</span>x\
+      <b>bold</b></p></body>",
+    );
+    let block = &document.blocks[0];
+    let job = block_layout_job(
+      block,
+      &FontId::proportional(16.0),
+      &DocumentColors::default(),
+      &[],
+      500.0,
+      true,
+      &[],
+    );
+    let bold_job = bold_overlay_job(&job, block, true).unwrap();
+
+    let visible: Vec<&str> = bold_job
+      .sections
+      .iter()
+      .filter(|section| section.format.color != Color32::TRANSPARENT)
+      .map(|section| &bold_job.text[section.byte_range.clone()])
+      .collect();
+    assert_eq!(job.text, "IIIIxbold");
+    assert_eq!(visible, ["bold"]);
+  }
+
+  #[test]
+  fn pages_break_between_rows_and_skip_hidden_blocks() {
+    let blocks = [
+      Some((30.0, vec![0.0, 10.0, 20.0])),
+      None,
+      Some((20.0, vec![0.0, 10.0])),
+    ];
+    let pagination = paginate(7, &blocks, 5.0, 25.0);
+
+    assert_eq!(pagination.key, 7);
+    assert_eq!(pagination.starts, [0.0, 20.0, 45.0]);
+    assert_eq!(pagination.block_tops, [Some(0.0), None, Some(35.0)]);
+    assert_eq!(pagination.height, 55.0);
+
+    assert_eq!(pagination.page_range(1), 20.0..45.0);
+    assert_eq!(pagination.page_range(2), 45.0..55.0);
+    assert_eq!(pagination.page_at(44.0), 1);
+  }
+
+  #[test]
+  fn rows_taller_than_a_page_are_split() {
+    let pagination = paginate(0, &[Some((100.0, vec![0.0]))], 5.0, 30.0);
+
+    assert_eq!(pagination.starts, [0.0, 30.0, 60.0, 90.0]);
+    assert_eq!(pagination.height, 100.0);
+  }
+
+  #[test]
+  fn empty_chapters_have_a_single_page() {
+    for blocks in [Vec::new(), vec![None, None]] {
+      let pagination = paginate(0, &blocks, 5.0, 30.0);
+
+      assert_eq!(pagination.starts, [0.0]);
+      assert_eq!(pagination.height, 0.0);
+      assert_eq!(pagination.page_range(0), 0.0..0.0);
+    }
+  }
+
+  #[test]
+  fn tiny_pages_still_make_progress() {
+    let pagination = paginate(0, &[Some((3.0, vec![0.0, 1.0, 2.0]))], 0.0, 0.0);
+
+    assert_eq!(pagination.starts, [0.0, 1.0, 2.0]);
   }
 }
@@ -1,7 +1,12 @@
 use std::{fs, io::Cursor};
 
-use crate::backend::{load_directory, register_epub, RenameState, Shelf};
-use egui::{vec2, Align2, RichText, TextEdit};
+use crate::{
+  backend::{load_directory, register_epub, RenameState, Shelf},
+  index::search_library,
+  panels::reader::match_layout_job,
+  ui::GotoTarget,
+};
+use egui::{vec2, Align2, RichText, ScrollArea, TextEdit};
 use epub::doc::EpubDoc;
 
 pub fn ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
@@ -13,14 +18,14 @@ pub fn ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
 
       if let Ok(epub) = EpubDoc::from_reader(bytes_cursor) {
         state.selected_book_uuid = epub.unique_identifier.clone();
-        register_epub(state, epub);
+        register_epub(state, epub, None);
       };
     // Loading files for the native version
     } else if let Some(path) = &file.path {
       if let Ok(epub) =
         EpubDoc::from_reader(Cursor::new(fs::read(path).unwrap()))
       {
-        register_epub(state, epub);
+        register_epub(state, epub, Some(path));
       }
     }
   }
@@ -37,9 +42,24 @@ pub fn ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
         .hint_text("Path to books...")
         .show(ui);
     } else {
-      TextEdit::singleline(&mut state.shelf_search)
+      let search_response = TextEdit::singleline(&mut state.shelf_search)
         .hint_text("Search Library...")
-        .show(ui);
+        .show(ui)
+        .response
+        .on_hover_text("Press Enter to search the text of every book");
+
+      if search_response.changed() {
+        state.library_hits.clear();
+      }
+      if search_response.lost_focus()
+        && ui.input().key_pressed(egui::Key::Enter)
+      {
+        state.library_hits = search_library(
+          &state.library_index,
+          &mut state.epub_cache,
+          &state.shelf_search,
+        );
+      }
 
       ui.with_layout(egui::Layout::right_to_left(), |ui| {
         if ui
@@ -68,6 +88,12 @@ pub fn ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
     });
   }
 
+  // Results of searching the text of the library
+  if !state.library_hits.is_empty() {
+    library_hits_ui(state, ui);
+    ui.separator();
+  }
+
   // Loop over all shelves
   for (shelf_index, path_group) in state.shelves.clone().iter().enumerate() {
     // Renaming window
@@ -148,6 +174,20 @@ pub fn ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
                   .sense(egui::Sense::click_and_drag()),
                 );
 
+                // How far through the book the user is
+                if let Some(progress) = state
+                  .book_userdata
+                  .get(uuid)
+                  .map(|info| info.progress)
+                  .filter(|progress| *progress > 0.0)
+                {
+                  ui.add(
+                    egui::ProgressBar::new(progress)
+                      .desired_width(140.0 * state.book_cover_width_multiplier),
+                  )
+                  .on_hover_text(format!("{:.0}% read", progress * 100.0));
+                }
+
                 // Book data / information
                 ui.label(
                   RichText::new(&title)
@@ -329,3 +369,40 @@ pub fn ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
       });
   }
 }
+
+/// Lists the places within books that match the library search, opening the
+/// book at the place when clicked
+fn library_hits_ui(state: &mut crate::Pend, ui: &mut egui::Ui) {
+  let mut clicked = None;
+
+  ui.collapsing(
+    format!("Text Matches ({})", state.library_hits.len()),
+    |ui| {
+      ScrollArea::vertical()
+        .id_source("Library Hits")
+        .max_height(ui.available_height() / 3.0)
+        .auto_shrink([false, true])
+        .show(ui, |ui| {
+          for (index, hit) in state.library_hits.iter().enumerate() {
+            let title = state
+              .epub_cache
+              .get(&hit.uuid)
+              .and_then(|epub| epub.mdata("title"))
+              .unwrap_or_else(|| "<Missing Title>".to_string());
+            ui.label(RichText::new(title).strong());
+
+            let job = match_layout_job(&hit.snippet, &hit.snippet_match, ui);
+            if ui.selectable_label(false, job).clicked() {
+              clicked = Some(index);
+            }
+          }
+        });
+    },
+  );
+
+  if let Some(index) = clicked {
+    let hit = &state.library_hits[index];
+    state.selected_book_uuid = Some(hit.uuid.clone());
+    state.goto_target = Some(GotoTarget::position(hit.position.clone()));
+  }
+}
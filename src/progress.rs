@@ -0,0 +1,183 @@
+//! How far through a book the user is, measured in characters of text, and
+//! how long the rest of it should take to read
+
+use serde::{Deserialize, Serialize};
+
+/// Characters read per second assumed before any reading has been measured
+/// (roughly 250 words a minute)
+const DEFAULT_SPEED: f64 = 23.0;
+
+/// Moving further than this many characters at once is a jump (e.g. following
+/// a link), not reading
+const MAX_READ_AT_ONCE: usize = 5000;
+
+/// Longer pauses than this (in seconds) between moving through the text are
+/// breaks from reading
+const MAX_PAUSE: f64 = 300.0;
+
+/// Faster movement than this (in characters per second) is skimming
+const MAX_SPEED: f64 = 100.0;
+
+/// The user's reading speed, measured from how quickly they move through books
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ReadingSpeed {
+  /// Characters read in total
+  pub characters: f64,
+  /// Seconds spent reading in total
+  pub seconds: f64,
+}
+
+impl Default for ReadingSpeed {
+  /// A minute of reading at the default speed, which measurements quickly
+  /// outweigh
+  fn default() -> Self {
+    Self {
+      characters: DEFAULT_SPEED * 60.0,
+      seconds: 60.0,
+    }
+  }
+}
+
+impl ReadingSpeed {
+  /// Counts moving forwards through a number of characters in a number of
+  /// seconds as reading, unless it was a jump, a break, or skimming
+  pub fn record(&mut self, characters: usize, seconds: f64) {
+    if characters <= MAX_READ_AT_ONCE
+      && seconds > 0.0
+      && seconds <= MAX_PAUSE
+      && characters as f64 / seconds <= MAX_SPEED
+    {
+      self.characters += characters as f64;
+      self.seconds += seconds;
+    }
+  }
+
+  /// Seconds it should take to read a number of characters
+  pub fn time_to_read(&self, characters: usize) -> f64 {
+    characters as f64 * self.seconds / self.characters.max(1.0)
+  }
+}
+
+/// Number of characters into a book a position is, given the length of each
+/// chapter and the number of characters into its own chapter
+pub fn book_offset(lengths: &[usize], chapter: usize, offset: usize) -> usize {
+  lengths.iter().take(chapter).sum::<usize>() + offset
+}
+
+/// Fraction (from 0 to 1) of a book's text before a number of characters
+pub fn book_progress(lengths: &[usize], offset: usize) -> f32 {
+  let total: usize = lengths.iter().sum();
+
+  if total == 0 {
+    0.0
+  } else {
+    (offset as f32 / total as f32).min(1.0)
+  }
+}
+
+/// The (chapter, characters into the chapter) a fraction of the way through a
+/// book
+pub fn locate_progress(lengths: &[usize], fraction: f32) -> (usize, usize) {
+  let total: usize = lengths.iter().sum();
+
+  locate_offset(lengths, (total as f32 * fraction.clamp(0.0, 1.0)) as usize)
+}
+
+/// The (chapter, characters into the chapter) a number of characters into a
+/// book
+pub fn locate_offset(lengths: &[usize], mut offset: usize) -> (usize, usize) {
+  for (chapter, length) in lengths.iter().enumerate() {
+    if offset < *length {
+      return (chapter, offset);
+    }
+    offset -= length;
+  }
+
+  (
+    lengths.len().saturating_sub(1),
+    lengths.last().copied().unwrap_or(0),
+  )
+}
+
+/// Writes a duration in hours & minutes, e.g. "2h 5m"
+pub fn format_duration(seconds: f64) -> String {
+  let minutes = (seconds / 60.0).round() as u64;
+
+  match (minutes / 60, minutes % 60) {
+    (0, 0) => "less than a minute".to_string(),
+    (0, minutes) => format!("{}m", minutes),
+    (hours, minutes) => format!("{}h {}m", hours, minutes),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Chapters of 10, 0 and 30 characters
+  const LENGTHS: [usize; 3] = [10, 0, 30];
+
+  #[test]
+  fn offsets_count_the_chapters_before() {
+    assert_eq!(book_offset(&LENGTHS, 0, 5), 5);
+    assert_eq!(book_offset(&LENGTHS, 2, 3), 13);
+    assert_eq!(book_progress(&LENGTHS, 0), 0.0);
+    assert_eq!(book_progress(&LENGTHS, 10), 0.25);
+    assert_eq!(book_progress(&LENGTHS, 40), 1.0);
+    assert_eq!(book_progress(&LENGTHS, 50), 1.0);
+    assert_eq!(book_progress(&[], 0), 0.0);
+  }
+
+  #[test]
+  fn fractions_are_located_within_chapters() {
+    assert_eq!(locate_progress(&LENGTHS, 0.0), (0, 0));
+    assert_eq!(locate_progress(&LENGTHS, 0.2), (0, 8));
+    // The end of a chapter is the start of the next one with any text
+    assert_eq!(locate_progress(&LENGTHS, 0.25), (2, 0));
+    assert_eq!(locate_progress(&LENGTHS, 0.5), (2, 10));
+    assert_eq!(locate_progress(&LENGTHS, 1.0), (2, 30));
+    assert_eq!(locate_progress(&LENGTHS, -1.0), (0, 0));
+    assert_eq!(locate_progress(&LENGTHS, 2.0), (2, 30));
+    assert_eq!(locate_progress(&[], 0.5), (0, 0));
+  }
+
+  #[test]
+  fn offsets_are_located_within_chapters() {
+    assert_eq!(locate_offset(&LENGTHS, 0), (0, 0));
+    assert_eq!(locate_offset(&LENGTHS, 9), (0, 9));
+    assert_eq!(locate_offset(&LENGTHS, 10), (2, 0));
+    assert_eq!(locate_offset(&LENGTHS, 39), (2, 29));
+    assert_eq!(locate_offset(&LENGTHS, 40), (2, 30));
+    assert_eq!(locate_offset(&LENGTHS, 100), (2, 30));
+    for (chapter, offset) in [(0, 3), (2, 0), (2, 17)] {
+      let located =
+        locate_offset(&LENGTHS, book_offset(&LENGTHS, chapter, offset));
+      assert_eq!(located, (chapter, offset));
+    }
+  }
+
+  #[test]
+  fn only_steady_reading_counts_towards_the_speed() {
+    let mut speed = ReadingSpeed::default();
+    assert_eq!(speed.time_to_read(DEFAULT_SPEED as usize * 60), 60.0);
+
+    // A jump, a break & skimming
+    speed.record(MAX_READ_AT_ONCE + 1, 100.0);
+    speed.record(100, MAX_PAUSE + 1.0);
+    speed.record(1000, 1.0);
+    speed.record(100, 0.0);
+    assert_eq!(speed.seconds, 60.0);
+
+    speed.record(1620, 60.0);
+    assert_eq!(speed.time_to_read(250), 10.0);
+  }
+
+  #[test]
+  fn durations_are_written_in_hours_and_minutes() {
+    assert_eq!(format_duration(0.0), "less than a minute");
+    assert_eq!(format_duration(29.0), "less than a minute");
+    assert_eq!(format_duration(90.0), "2m");
+    assert_eq!(format_duration(3600.0), "1h 0m");
+    assert_eq!(format_duration(2.0 * 3600.0 + 300.0), "2h 5m");
+  }
+}
@@ -0,0 +1,313 @@
+//! Searching through the text of the open book
+
+use std::{io::Cursor, ops::Range};
+
+use epub::doc::EpubDoc;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  backend::chapter_document,
+  location::TextPosition,
+  toc::{entry_for_chapter, TocEntry},
+};
+
+/// Characters of context shown on either side of a match
+const CONTEXT_LENGTH: usize = 40;
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SearchOptions {
+  pub case_sensitive: bool,
+  /// Only match whole words
+  pub whole_word: bool,
+  /// Treat the query as a regular expression
+  pub regex: bool,
+}
+
+/// A match of a search within a book
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+  pub chapter: usize,
+  pub chapter_title: String,
+  /// Index of the block within the chapter
+  pub block: usize,
+  /// Range of characters within the block
+  pub range: Range<usize>,
+  /// Start of the match, to jump to
+  pub position: TextPosition,
+  /// The match, with some of the text around it
+  pub context: String,
+  /// Byte range of the match within `context`
+  pub context_match: Range<usize>,
+}
+
+/// State of the search bar in the reader
+#[derive(Serialize, Deserialize, Default)]
+pub struct BookSearch {
+  pub open: bool,
+  pub query: String,
+  pub options: SearchOptions,
+  /// Hits of the last search that was run
+  #[serde(skip)]
+  pub hits: Vec<SearchHit>,
+  /// Index of the hit that was last jumped to
+  #[serde(skip)]
+  pub current: Option<usize>,
+  /// Why the query could not be searched for (an invalid regex)
+  #[serde(skip)]
+  pub error: Option<String>,
+  /// UUID of the book & the query / options the hits are for
+  #[serde(skip)]
+  pub searched: Option<(String, String, SearchOptions)>,
+  /// Whether the search field should take keyboard focus
+  #[serde(skip)]
+  pub focus: bool,
+}
+
+impl BookSearch {
+  /// Whether the hits are out of date with the query or selected book
+  pub fn is_stale(&self, uuid: &str) -> bool {
+    match &self.searched {
+      Some((book, query, options)) => {
+        book != uuid || *query != self.query || *options != self.options
+      }
+      None => true,
+    }
+  }
+
+  /// Moves to the next (or previous) hit, returning it
+  pub fn step(&mut self, forwards: bool) -> Option<&SearchHit> {
+    if self.hits.is_empty() {
+      return None;
+    }
+
+    let count = self.hits.len();
+    self.current = Some(match self.current {
+      Some(current) if forwards => (current + 1) % count,
+      Some(current) => (current + count - 1) % count,
+      None if forwards => 0,
+      None => count - 1,
+    });
+
+    self.current.map(|current| &self.hits[current])
+  }
+}
+
+/// Turns a query into a regular expression according to the search options
+pub fn build_pattern(
+  query: &str,
+  options: SearchOptions,
+) -> Result<Regex, regex::Error> {
+  let mut pattern = if options.regex {
+    query.to_string()
+  } else {
+    regex::escape(query)
+  };
+  if options.whole_word {
+    pattern = format!(r"\b(?:{})\b", pattern);
+  }
+
+  RegexBuilder::new(&pattern)
+    .case_insensitive(!options.case_sensitive)
+    .build()
+}
+
+/// Finds all matches of a pattern within every chapter of a book
+pub fn search_book(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  toc: &[TocEntry],
+  pattern: &Regex,
+) -> Vec<SearchHit> {
+  let mut hits = Vec::new();
+
+  for chapter in 0..book.spine.len() {
+    let document = match chapter_document(book, chapter) {
+      Some(document) => document,
+      None => continue,
+    };
+
+    let chapter_title = entry_for_chapter(toc, chapter)
+      .map(|entry| entry.label.clone())
+      .or_else(|| document.title.clone())
+      .unwrap_or_else(|| format!("Chapter {}", chapter));
+
+    for (index, block) in document.blocks.iter().enumerate() {
+      let text = block.text();
+
+      for found in pattern.find_iter(&text) {
+        // Empty matches (e.g. from `a*`) can't be shown
+        if found.range().is_empty() {
+          continue;
+        }
+
+        let (context, context_match) = match_context(&text, found.range());
+        let start = text[..found.start()].chars().count();
+
+        hits.push(SearchHit {
+          chapter,
+          chapter_title: chapter_title.clone(),
+          block: index,
+          range: start..start + found.as_str().chars().count(),
+          position: TextPosition::new(chapter, &document, index, start),
+          context,
+          context_match,
+        });
+      }
+    }
+  }
+
+  hits
+}
+
+/// Cuts out a match (given as a byte range) along with some text on either
+/// side of it
+pub(crate) fn match_context(
+  text: &str,
+  range: Range<usize>,
+) -> (String, Range<usize>) {
+  let before: String = text[..range.start]
+    .chars()
+    .rev()
+    .take(CONTEXT_LENGTH)
+    .collect::<Vec<_>>()
+    .into_iter()
+    .rev()
+    .collect();
+  let after: String = text[range.end..].chars().take(CONTEXT_LENGTH).collect();
+
+  let mut context = String::new();
+  if before.len() < range.start {
+    context.push_str("...");
+  }
+  context.push_str(&before);
+  let start = context.len();
+  context.push_str(&text[range.clone()]);
+  let end = context.len();
+  context.push_str(&after);
+  if range.end + after.len() < text.len() {
+    context.push_str("...");
+  }
+
+  (context, start..end)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn matches(query: &str, options: SearchOptions, text: &str) -> Vec<String> {
+    build_pattern(query, options)
+      .unwrap()
+      .find_iter(text)
+      .map(|found| found.as_str().to_string())
+      .collect()
+  }
+
+  fn hit(block: usize) -> SearchHit {
+    SearchHit {
+      chapter: 0,
+      chapter_title: String::new(),
+      block,
+      range: 0..1,
+      position: TextPosition::default(),
+      context: String::new(),
+      context_match: 0..1,
+    }
+  }
+
+  #[test]
+  fn queries_ignore_case_unless_asked_not_to() {
+    let text = "The cat. THE CAT.";
+
+    assert_eq!(
+      matches("the cat", SearchOptions::default(), text),
+      ["The cat", "THE CAT"]
+    );
+    let options = SearchOptions {
+      case_sensitive: true,
+      ..Default::default()
+    };
+    assert_eq!(matches("THE CAT", options, text), ["THE CAT"]);
+  }
+
+  #[test]
+  fn whole_word_queries_dont_match_within_words() {
+    let options = SearchOptions {
+      whole_word: true,
+      ..Default::default()
+    };
+
+    assert_eq!(
+      matches("cat", SearchOptions::default(), "cat concat cats"),
+      ["cat", "cat", "cat"]
+    );
+    assert_eq!(
+      matches("cat", options, "cat concat cats cat."),
+      ["cat", "cat"]
+    );
+    assert_eq!(matches("a|b", options, "a|b ab"), ["a|b"]);
+  }
+
+  #[test]
+  fn queries_are_only_patterns_in_regex_mode() {
+    let options = SearchOptions {
+      regex: true,
+      ..Default::default()
+    };
+
+    assert_eq!(matches("c.t", SearchOptions::default(), "cat c.t"), ["c.t"]);
+    assert_eq!(matches("c.t", options, "cat c.t"), ["cat", "c.t"]);
+    assert_eq!(
+      matches(
+        "a|b",
+        SearchOptions {
+          whole_word: true,
+          ..options
+        },
+        "a ab b"
+      ),
+      ["a", "b"]
+    );
+    assert!(build_pattern("(unclosed", options).is_err());
+    assert!(build_pattern("(unclosed", SearchOptions::default()).is_ok());
+  }
+
+  #[test]
+  fn context_is_cut_at_character_boundaries() {
+    let text = format!("{}ねこ{}", "あ".repeat(50), "い".repeat(50));
+    let start = text.find("ねこ").unwrap();
+    let (context, found) = match_context(&text, start..start + "ねこ".len());
+
+    assert_eq!(&context[found.clone()], "ねこ");
+    assert_eq!(
+      context,
+      format!("...{}ねこ{}...", "あ".repeat(40), "い".repeat(40))
+    );
+
+    let (context, found) = match_context("éa ñ", 4..6);
+    assert_eq!((context.as_str(), found), ("éa ñ", 4..6));
+  }
+
+  #[test]
+  fn stepping_through_hits_wraps_around() {
+    let mut search = BookSearch {
+      hits: vec![hit(0), hit(1), hit(2)],
+      ..Default::default()
+    };
+    let mut step = |forwards| search.step(forwards).map(|hit| hit.block);
+
+    assert_eq!(step(true), Some(0));
+    assert_eq!(step(true), Some(1));
+    assert_eq!(step(true), Some(2));
+    assert_eq!(step(true), Some(0));
+    assert_eq!(step(false), Some(2));
+    assert_eq!(step(false), Some(1));
+
+    let mut search = BookSearch {
+      hits: vec![hit(0), hit(1), hit(2)],
+      ..Default::default()
+    };
+    assert_eq!(search.step(false).map(|hit| hit.block), Some(2));
+    assert!(BookSearch::default().step(true).is_none());
+  }
+}
@@ -0,0 +1,60 @@
+//! Presses of the mouse's back & forward (side) buttons on the web.
+//!
+//! egui only reports the primary, secondary & middle buttons, so the side
+//! buttons are listened for on the browser's window instead. Their default
+//! action (the browser navigating away from the app) is prevented. On native
+//! there is no way to get at them through eframe, so they aren't supported.
+
+use std::{cell::RefCell, rc::Rc};
+
+use eframe::wasm_bindgen::{closure::Closure, JsCast};
+use egui::Context;
+use web_sys::MouseEvent;
+
+/// `MouseEvent.button` of the back button
+const BACK_BUTTON: i16 = 3;
+/// `MouseEvent.button` of the forward button
+const FORWARD_BUTTON: i16 = 4;
+
+/// Side button presses not yet taken by the app, as forwards (true) or back
+/// (false)
+pub type SideButtonPresses = Rc<RefCell<Vec<bool>>>;
+
+/// Starts listening for the side buttons. Each press is added to the returned
+/// list, and a repaint is requested so that it is taken straight away
+pub fn listen(ctx: &Context) -> SideButtonPresses {
+  let presses = SideButtonPresses::default();
+  let window = match web_sys::window() {
+    Some(window) => window,
+    None => return presses,
+  };
+
+  let (ctx, pressed) = (ctx.clone(), presses.clone());
+  let on_button = Closure::wrap(Box::new(move |event: MouseEvent| {
+    let forwards = match event.button() {
+      BACK_BUTTON => false,
+      FORWARD_BUTTON => true,
+      _ => return,
+    };
+    // Browsers differ in whether they navigate on the button going down or
+    // up, so both are prevented, and presses counted when it goes up
+    event.prevent_default();
+    if event.type_() == "mouseup" {
+      pressed.borrow_mut().push(forwards);
+      ctx.request_repaint();
+    }
+  }) as Box<dyn FnMut(MouseEvent)>);
+
+  for event in ["mousedown", "mouseup"] {
+    window
+      .add_event_listener_with_callback(
+        event,
+        on_button.as_ref().unchecked_ref(),
+      )
+      .ok();
+  }
+  // The listener lasts as long as the app
+  on_button.forget();
+
+  presses
+}
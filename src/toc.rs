@@ -0,0 +1,309 @@
+//! Reading of a book's table of contents, from either the EPUB3 navigation
+//! document or the EPUB2 NCX file
+
+use std::io::Cursor;
+
+use epub::doc::EpubDoc;
+
+use crate::{
+  backend::{percent_decode, resolve_href, spine_index_of},
+  xhtml::{self, Element},
+};
+
+/// An entry in a book's table of contents
+#[derive(Debug, Clone, PartialEq)]
+pub struct TocEntry {
+  pub label: String,
+  /// Spine index of the chapter the entry points to (if it could be found)
+  pub chapter: Option<usize>,
+  /// `id` of the element within the chapter the entry points to
+  pub anchor: Option<String>,
+  pub children: Vec<TocEntry>,
+}
+
+impl TocEntry {
+  /// Creates an entry from an `href` found within the file at `base_file`
+  fn new(
+    book: &EpubDoc<Cursor<Vec<u8>>>,
+    base_file: &str,
+    label: String,
+    href: &str,
+  ) -> Self {
+    Self {
+      label,
+      chapter: spine_index_of(book, &resolve_href(base_file, href)),
+      anchor: href
+        .split_once('#')
+        .map(|(_, fragment)| percent_decode(fragment)),
+      children: Vec::new(),
+    }
+  }
+}
+
+/// Loads the table of contents of a book, preferring the EPUB3 navigation
+/// document over the NCX. Returns an empty list if neither could be read
+pub fn load_toc(book: &mut EpubDoc<Cursor<Vec<u8>>>) -> Vec<TocEntry> {
+  let package = match read_package(book) {
+    Some(package) => package,
+    None => return Vec::new(),
+  };
+
+  if let Some((path, nav)) = nav_document(book, &package) {
+    let entries = find_nav(&nav, "toc")
+      .or_else(|| nav.find("nav"))
+      .and_then(|toc| toc.find("ol"))
+      .map(|list| nav_list_entries(book, &path, list))
+      .unwrap_or_default();
+
+    if !entries.is_empty() {
+      return entries;
+    }
+  }
+
+  if let Some((path, ncx)) = ncx_document(book, &package) {
+    if let Some(nav_map) = ncx.find("navmap") {
+      return ncx_entries(book, &path, nav_map);
+    }
+  }
+
+  Vec::new()
+}
+
+/// Loads the print page numbers of a book from the EPUB3 `page-list` (or the
+/// NCX `pageList`), as one entry per page in reading order. Returns an empty
+/// list if the book has neither
+pub fn load_page_list(book: &mut EpubDoc<Cursor<Vec<u8>>>) -> Vec<TocEntry> {
+  let package = match read_package(book) {
+    Some(package) => package,
+    None => return Vec::new(),
+  };
+
+  if let Some((path, nav)) = nav_document(book, &package) {
+    let entries = find_nav(&nav, "page-list")
+      .and_then(|page_list| page_list.find("ol"))
+      .map(|list| nav_list_entries(book, &path, list))
+      .unwrap_or_default();
+
+    if !entries.is_empty() {
+      return entries;
+    }
+  }
+
+  if let Some((path, ncx)) = ncx_document(book, &package) {
+    if let Some(page_list) = ncx.find("pagelist") {
+      return page_list
+        .child_elements()
+        .filter(|target| target.name == "pagetarget")
+        .map(|target| {
+          let href = target
+            .find("content")
+            .and_then(|content| content.attr("src"))
+            .unwrap_or_default();
+
+          TocEntry::new(book, &path, ncx_label(target), href)
+        })
+        .collect();
+    }
+  }
+
+  Vec::new()
+}
+
+/// Flattens a table of contents into a list of entries in reading order,
+/// along with their depth in the hierarchy
+pub fn flatten(entries: &[TocEntry]) -> Vec<(usize, &TocEntry)> {
+  fn visit<'a>(
+    entries: &'a [TocEntry],
+    depth: usize,
+    output: &mut Vec<(usize, &'a TocEntry)>,
+  ) {
+    for entry in entries {
+      output.push((depth, entry));
+      visit(&entry.children, depth + 1, output);
+    }
+  }
+
+  let mut output = Vec::new();
+  visit(entries, 0, &mut output);
+  output
+}
+
+/// Finds the entry that best describes a given chapter: the first entry that
+/// points to it, or failing that the last entry before it
+pub fn entry_for_chapter(
+  entries: &[TocEntry],
+  chapter: usize,
+) -> Option<&TocEntry> {
+  let flattened = flatten(entries);
+
+  flattened
+    .iter()
+    .find(|(_, entry)| entry.chapter == Some(chapter))
+    .or_else(|| {
+      flattened
+        .iter()
+        .rev()
+        .find(|(_, entry)| matches!(entry.chapter, Some(c) if c < chapter))
+    })
+    .map(|(_, entry)| *entry)
+}
+
+/// The parsed OPF package document, along with its path
+pub(crate) struct Package {
+  pub path: String,
+  pub root: Element,
+}
+
+impl Package {
+  /// Finds the path of a manifest item matching a predicate
+  fn manifest_href(
+    &self,
+    predicate: impl Fn(&Element) -> bool,
+  ) -> Option<String> {
+    self
+      .root
+      .find("manifest")?
+      .child_elements()
+      .find(|item| item.name == "item" && predicate(item))
+      .and_then(|item| item.attr("href"))
+      .map(|href| resolve_href(&self.path, href))
+  }
+}
+
+pub(crate) fn read_package(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+) -> Option<Package> {
+  let path = book.root_file.to_string_lossy().replace('\\', "/");
+  let bytes = book.get_resource_by_path(&path).ok()?;
+
+  Some(Package {
+    root: xhtml::parse(&String::from_utf8_lossy(&bytes)),
+    path,
+  })
+}
+
+/// Reads and parses a file within the epub
+fn read_document(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  path: String,
+) -> Option<(String, Element)> {
+  let bytes = book.get_resource_by_path(&path).ok()?;
+  Some((path, xhtml::parse(&String::from_utf8_lossy(&bytes))))
+}
+
+/// The EPUB3 navigation document (manifest item with the `nav` property)
+pub(crate) fn nav_document(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  package: &Package,
+) -> Option<(String, Element)> {
+  let path = package.manifest_href(|item| {
+    item
+      .attr("properties")
+      .unwrap_or_default()
+      .split_ascii_whitespace()
+      .any(|property| property == "nav")
+  })?;
+
+  read_document(book, path)
+}
+
+/// The EPUB2 NCX file (referenced by the spine's `toc` attribute)
+fn ncx_document(
+  book: &mut EpubDoc<Cursor<Vec<u8>>>,
+  package: &Package,
+) -> Option<(String, Element)> {
+  let toc_id = package.root.find("spine")?.attr("toc").map(str::to_string);
+
+  let path = package.manifest_href(|item| {
+    item.attr("id") == toc_id.as_deref()
+      || item.attr("media-type") == Some("application/x-dtbncx+xml")
+  })?;
+
+  read_document(book, path)
+}
+
+/// Finds the `<nav>` element with the given `epub:type` (e.g. `toc`)
+pub(crate) fn find_nav<'a>(
+  root: &'a Element,
+  nav_type: &str,
+) -> Option<&'a Element> {
+  for child in root.child_elements() {
+    if child.name == "nav"
+      && child
+        .attr("epub:type")
+        .unwrap_or_default()
+        .split_ascii_whitespace()
+        .any(|t| t == nav_type)
+    {
+      return Some(child);
+    }
+    if let Some(found) = find_nav(child, nav_type) {
+      return Some(found);
+    }
+  }
+
+  None
+}
+
+/// Reads the entries of an `<ol>` within a navigation document
+pub(crate) fn nav_list_entries(
+  book: &EpubDoc<Cursor<Vec<u8>>>,
+  path: &str,
+  list: &Element,
+) -> Vec<TocEntry> {
+  list
+    .child_elements()
+    .filter(|item| item.name == "li")
+    .filter_map(|item| {
+      let link = item
+        .child_elements()
+        .find(|child| child.name == "a" || child.name == "span")?;
+      let label = link.text().split_whitespace().collect::<Vec<_>>().join(" ");
+
+      let mut entry =
+        TocEntry::new(book, path, label, link.attr("href").unwrap_or_default());
+      if link.attr("href").is_none() {
+        entry.chapter = None;
+      }
+      if let Some(sublist) = item.child_elements().find(|c| c.name == "ol") {
+        entry.children = nav_list_entries(book, path, sublist);
+      }
+
+      Some(entry)
+    })
+    .collect()
+}
+
+/// Reads the `<navPoint>`s within an element of an NCX file
+fn ncx_entries(
+  book: &EpubDoc<Cursor<Vec<u8>>>,
+  path: &str,
+  parent: &Element,
+) -> Vec<TocEntry> {
+  parent
+    .child_elements()
+    .filter(|point| point.name == "navpoint")
+    .map(|point| {
+      let label = ncx_label(point);
+      let href = point
+        .find("content")
+        .and_then(|content| content.attr("src"))
+        .unwrap_or_default();
+
+      let mut entry = TocEntry::new(book, path, label, href);
+      entry.children = ncx_entries(book, path, point);
+      entry
+    })
+    .collect()
+}
+
+/// The text of the `<navLabel>` of an NCX `<navPoint>` (or `<pageTarget>`)
+fn ncx_label(point: &Element) -> String {
+  point
+    .find("navlabel")
+    .map(|label| label.text())
+    .unwrap_or_default()
+    .split_whitespace()
+    .collect::<Vec<_>>()
+    .join(" ")
+}
@@ -2,13 +2,17 @@ use std::cmp::Ordering;
 
 use eframe::{
   egui::{self, Context, RichText, ScrollArea},
-  epaint::{vec2, Color32},
+  epaint::{vec2, Color32, Pos2},
 };
 use egui::FontFamily;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-  panels::{config, notes, reader, shelf},
+  backend::LocalBookInfo,
+  document::{Block, Document},
+  location::{TextPosition, TextRange},
+  panels::{bookmarks, config, contents, notes, reader, shelf},
+  search::BookSearch,
   Pend,
 };
 
@@ -26,6 +30,99 @@ pub struct UIState {
   pub reader_focus_mode: bool,
   pub display_ofl_popup: bool,
   pub display_raw_text: bool,
+  /// External link the user has clicked, awaiting confirmation to open it
+  #[serde(skip)]
+  pub confirm_external_link: Option<String>,
+  #[serde(skip)]
+  pub footnote_popup: Option<FootnotePopup>,
+  #[serde(skip)]
+  pub reader_selection: Option<ReaderSelection>,
+  #[serde(default)]
+  pub book_search: BookSearch,
+  /// (Chapter, page) shown in the paginated reading mode. The page is reset
+  /// to the first when the chapter changes
+  #[serde(skip)]
+  pub reader_page: (usize, usize),
+  /// Offset to scroll the reader to on the next frame, used to keep the view
+  /// in place when scrolling from one chapter into another
+  #[serde(skip)]
+  pub reader_scroll: Option<f32>,
+  /// UUID of the book the reader showed last, used to tell when another book
+  /// is opened
+  #[serde(skip)]
+  pub reader_book: Option<String>,
+  /// Hash of the font & size of the reader the last time it was drawn, used
+  /// to tell when its text is laid out differently
+  #[serde(skip)]
+  pub reader_view: Option<u64>,
+  /// Percentage of the way through the book to go to
+  #[serde(skip)]
+  pub goto_percent: f32,
+  /// Label of the page typed into the "go to page" field
+  #[serde(skip)]
+  pub goto_page: String,
+  /// The text that was shown in the reader when it was last drawn
+  #[serde(skip)]
+  pub reader_shown: Option<TextRange>,
+  /// (UUID of the book, characters into it, time) of the last time the
+  /// reading position moved, used to measure reading speed
+  #[serde(skip)]
+  pub reading_mark: Option<(String, usize, f64)>,
+}
+
+/// A footnote / endnote being previewed in the reader
+pub struct FootnotePopup {
+  /// `href` of the note reference that was hovered / clicked
+  pub href: String,
+  pub blocks: Vec<Block>,
+  /// Pinned popups stay open until closed, instead of only while hovered
+  pub pinned: bool,
+  pub position: Pos2,
+}
+
+/// Text selected in the reader. Both ends are (block, character) positions
+/// within the chapter
+pub struct ReaderSelection {
+  pub chapter: usize,
+  /// Where the selection was started
+  pub anchor: (usize, usize),
+  /// The end of the selection that follows the pointer while dragging
+  pub head: (usize, usize),
+  pub dragging: bool,
+  /// (Time, block) of the double click that made the selection, used to
+  /// detect triple clicks
+  pub double_clicked: Option<(f64, usize)>,
+}
+
+impl ReaderSelection {
+  #[must_use]
+  pub const fn new(chapter: usize, position: (usize, usize)) -> Self {
+    Self {
+      chapter,
+      anchor: position,
+      head: position,
+      dragging: false,
+      double_clicked: None,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.anchor == self.head
+  }
+
+  /// The range of text that is selected
+  pub fn range(&self, document: &Document) -> TextRange {
+    let (start, end) = if self.anchor <= self.head {
+      (self.anchor, self.head)
+    } else {
+      (self.head, self.anchor)
+    };
+
+    TextRange {
+      start: TextPosition::new(self.chapter, document, start.0, start.1),
+      end: TextPosition::new(self.chapter, document, end.0, end.1),
+    }
+  }
 }
 
 #[derive(PartialEq, Serialize, Deserialize)]
@@ -34,13 +131,59 @@ pub enum PanelState {
   Config,
   Shelf,
   Notes,
+  Bookmarks,
+  Contents,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct BookTextStyle {
   pub font_size: f32,
   pub font_family: FontFamily,
   pub line_spacing_multiplier: f32,
+  /// Hide footnotes from the text (they can still be read through their
+  /// references)
+  pub hide_footnotes: bool,
+  pub reading_mode: ReadingMode,
+  /// Narrowest window (in points) that two pages are shown side by side in,
+  /// in the spread reading mode
+  pub spread_min_width: f32,
+  /// Show page numbers in the margin of the reader
+  pub show_page_numbers: bool,
+  /// Lay out books without their own stylesheets
+  pub ignore_publisher_styles: bool,
+  /// The reader's own CSS for every book, which the style above is the
+  /// default for
+  pub user_css: String,
+}
+
+impl BookTextStyle {
+  /// The style to read a particular book with: its own CSS follows that for
+  /// every book, and it may ignore its publisher's styles on its own
+  pub fn for_book(&self, book_info: &LocalBookInfo) -> Self {
+    let mut style = self.clone();
+    style.ignore_publisher_styles |= book_info.ignore_publisher_styles;
+    if !book_info.user_css.trim().is_empty() {
+      style.user_css.push('\n');
+      style.user_css.push_str(&book_info.user_css);
+    }
+    style
+  }
+}
+
+/// How the text of a chapter is presented in the reader
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ReadingMode {
+  /// The chapter is scrolled through
+  Scroll,
+  /// Scrolling continues from the end of one chapter into the next
+  Continuous,
+  /// The chapter is split into pages the size of the reader, which are
+  /// turned one at a time
+  Paginated,
+  /// Like `Paginated`, but with two pages shown side by side (when the window
+  /// is wide enough)
+  Spread,
 }
 
 impl Default for BookTextStyle {
@@ -49,6 +192,12 @@ impl Default for BookTextStyle {
       font_size: 22.0,
       font_family: FontFamily::Name("Merriweather".into()),
       line_spacing_multiplier: 1.0,
+      hide_footnotes: false,
+      reading_mode: ReadingMode::Scroll,
+      spread_min_width: 1200.0,
+      show_page_numbers: true,
+      ignore_publisher_styles: false,
+      user_css: String::new(),
     }
   }
 }
@@ -57,14 +206,28 @@ impl Default for BookTextStyle {
 #[derive(Serialize, Deserialize, Clone, Debug, Eq)]
 pub struct Note {
   pub chapter: u16,
+  /// Line of the chapter the note was attached to by older versions (as they
+  /// split chapters into lines, not by block), which is only used to convert
+  /// the note into a `range`
+  #[serde(default, skip_serializing)]
   pub line: u16,
+  /// Text the note is attached to. Only missing for notes from older versions
+  /// that could not be converted
+  #[serde(default)]
+  pub range: Option<TextRange>,
+  /// The text the note is attached to, shown alongside it
+  #[serde(default)]
+  pub excerpt: String,
   pub content: String,
 }
 
 // Slightly modified partialeq that disregards content
 impl PartialEq for Note {
   fn eq(&self, other: &Self) -> bool {
-    self.chapter == other.chapter && self.line == other.line
+    match (&self.range, &other.range) {
+      (Some(range), Some(other_range)) => range == other_range,
+      _ => self.chapter == other.chapter && self.line == other.line,
+    }
   }
 }
 
@@ -79,10 +242,12 @@ impl Ord for Note {
     match self.chapter.cmp(&other.chapter) {
       Ordering::Greater => Ordering::Greater,
       Ordering::Less => Ordering::Less,
-      Ordering::Equal => match self.line.cmp(&other.line) {
-        Ordering::Greater => Ordering::Greater,
-        Ordering::Less => Ordering::Less,
-        Ordering::Equal => Ordering::Equal,
+      Ordering::Equal => match (&self.range, &other.range) {
+        (Some(range), Some(other_range)) => range
+          .start
+          .cmp(&other_range.start)
+          .then_with(|| range.end.cmp(&other_range.end)),
+        _ => self.line.cmp(&other.line),
       },
     }
   }
@@ -90,20 +255,114 @@ impl Ord for Note {
 
 impl Note {
   #[must_use]
-  pub const fn new(chapter: u16, line: u16) -> Self {
+  pub fn new(range: TextRange, excerpt: String) -> Self {
+    Self {
+      chapter: range.start.chapter as u16,
+      line: 0,
+      range: Some(range),
+      excerpt,
+      content: String::new(),
+    }
+  }
+}
+
+/// A place within the selected book for the reader to jump to
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GotoTarget {
+  pub chapter: usize,
+  /// Index of the block (line) within the chapter
+  pub line: usize,
+  /// `id` of an element within the chapter, takes precedence over `line`
+  #[serde(default)]
+  pub anchor: Option<String>,
+  /// Position of some text, takes precedence over `anchor` & `line`
+  #[serde(default)]
+  pub position: Option<TextPosition>,
+}
+
+impl GotoTarget {
+  #[must_use]
+  pub const fn new(chapter: usize, line: usize) -> Self {
     Self {
       chapter,
       line,
-      content: String::new(),
+      anchor: None,
+      position: None,
+    }
+  }
+
+  #[must_use]
+  pub fn anchor<S: Into<String>>(chapter: usize, anchor: Option<S>) -> Self {
+    Self {
+      chapter,
+      line: 0,
+      anchor: anchor.map(Into::into),
+      position: None,
+    }
+  }
+
+  #[must_use]
+  pub fn position(position: TextPosition) -> Self {
+    Self {
+      chapter: position.chapter,
+      line: 0,
+      anchor: None,
+      position: Some(position),
+    }
+  }
+}
+
+/// Most places kept in each direction of a book's navigation history
+const MAX_HISTORY: usize = 100;
+
+/// Places jumped away from within a book, to go back (and forward again) to
+#[derive(Default)]
+pub struct NavigationHistory {
+  pub back: Vec<GotoTarget>,
+  pub forward: Vec<GotoTarget>,
+}
+
+impl NavigationHistory {
+  /// Remembers a place before jumping away from it, which makes going forward
+  /// impossible until going back again
+  pub fn record(&mut self, from: GotoTarget) {
+    if self.back.last() != Some(&from) {
+      self.back.push(from);
+    }
+    if self.back.len() > MAX_HISTORY {
+      self.back.remove(0);
     }
+    self.forward.clear();
+  }
+
+  /// Goes back to the last place jumped away from, remembering the current
+  /// place to go forward to
+  pub fn back(&mut self, current: GotoTarget) -> Option<GotoTarget> {
+    let target = self.back.pop()?;
+    self.forward.push(current);
+    Some(target)
+  }
+
+  /// Undoes going back, remembering the current place to go back to
+  pub fn forward(&mut self, current: GotoTarget) -> Option<GotoTarget> {
+    let target = self.forward.pop()?;
+    self.back.push(current);
+    Some(target)
   }
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct DocumentColors {
   pub highlight_color: Color32,
   pub text_color: Color32,
   pub page_color: Color32,
+  /// Color of links that lead outside of the book
+  pub link_color: Color32,
+  /// Background of text selected in the reader
+  pub selection_color: Color32,
+  /// Background of matches of the search in the reader
+  pub search_color: Color32,
 }
 
 impl Default for DocumentColors {
@@ -112,6 +371,9 @@ impl Default for DocumentColors {
       highlight_color: Color32::YELLOW,
       text_color: Color32::BLACK,
       page_color: Color32::from_rgb(239, 229, 213),
+      link_color: Color32::from_rgb(38, 88, 178),
+      selection_color: Color32::from_rgba_unmultiplied(82, 120, 220, 110),
+      search_color: Color32::from_rgba_unmultiplied(255, 160, 40, 110),
     }
   }
 }
@@ -191,6 +453,22 @@ pub fn main(ctx: &Context, state: &mut Pend) {
 								"Notes",
 							);
 						});
+						ui.vertical(|ui| {
+							ui.set_enabled(state.selected_book_uuid.is_some());
+							ui.selectable_value(
+								&mut state.ui_state.left_panel_state,
+								PanelState::Bookmarks,
+								"Bookmarks",
+							);
+						});
+						ui.vertical(|ui| {
+							ui.set_enabled(state.selected_book_uuid.is_some());
+							ui.selectable_value(
+								&mut state.ui_state.left_panel_state,
+								PanelState::Contents,
+								"Contents",
+							);
+						});
 
 						ui.with_layout(egui::Layout::right_to_left(), |ui| {
 							ui.selectable_value(
@@ -212,6 +490,12 @@ pub fn main(ctx: &Context, state: &mut Pend) {
 						PanelState::Notes => {
 							notes::ui(state, ui);
 						}
+						PanelState::Bookmarks => {
+							bookmarks::ui(state, ui);
+						}
+						PanelState::Contents => {
+							contents::ui(state, ui);
+						}
 						PanelState::Reader => {
 							ui.label("Invalid Panel");
 						}
@@ -0,0 +1,567 @@
+//! A small, forgiving XHTML parser that produces a DOM-like tree.
+//!
+//! EPUB content documents are *supposed* to be well formed XML, but plenty of
+//! books in the wild contain stray HTML entities, unclosed tags and the like,
+//! so this parser never fails: it simply does the most sensible thing it can
+//! with whatever it is given.
+
+/// A node within a parsed document
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+  Element(Element),
+  Text(String),
+}
+
+/// An element (tag) within a parsed document
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Element {
+  /// Lowercase name of the tag with any namespace prefix removed
+  pub name: String,
+  /// Attributes in source order. Names are kept as written (e.g. `epub:type`)
+  pub attributes: Vec<(String, String)>,
+  pub children: Vec<Node>,
+}
+
+impl Element {
+  pub fn new<S: Into<String>>(name: S) -> Self {
+    Self {
+      name: name.into(),
+      attributes: Vec::new(),
+      children: Vec::new(),
+    }
+  }
+
+  /// Looks up an attribute, first by its exact name and then by its name
+  /// without a namespace prefix (so `href` also finds `xlink:href`)
+  pub fn attr(&self, name: &str) -> Option<&str> {
+    self
+      .attributes
+      .iter()
+      .find(|(key, _)| key == name)
+      .or_else(|| {
+        self
+          .attributes
+          .iter()
+          .find(|(key, _)| key.rsplit(':').next() == Some(name))
+      })
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// Iterates over the direct children of this element that are elements
+  pub fn child_elements(&self) -> impl Iterator<Item = &Element> {
+    self.children.iter().filter_map(|child| match child {
+      Node::Element(element) => Some(element),
+      Node::Text(_) => None,
+    })
+  }
+
+  /// Finds the first descendant element (depth first) with the given name
+  pub fn find(&self, name: &str) -> Option<&Element> {
+    for child in self.child_elements() {
+      if child.name == name {
+        return Some(child);
+      }
+      if let Some(found) = child.find(name) {
+        return Some(found);
+      }
+    }
+
+    None
+  }
+
+  /// All of the text contained within this element, concatenated
+  pub fn text(&self) -> String {
+    let mut output = String::new();
+
+    for child in &self.children {
+      match child {
+        Node::Text(text) => output.push_str(text),
+        Node::Element(element) => output.push_str(&element.text()),
+      }
+    }
+
+    output
+  }
+}
+
+/// Elements that never have contents / closing tags in HTML
+const VOID_ELEMENTS: [&str; 14] = [
+  "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta",
+  "param", "source", "track", "wbr",
+];
+
+/// Elements whose contents are not markup
+const RAW_TEXT_ELEMENTS: [&str; 2] = ["script", "style"];
+
+/// Parses (X)HTML into a tree of nodes.
+///
+/// The returned element is a synthetic `#document` root containing the
+/// top level nodes of the input.
+pub fn parse(input: &str) -> Element {
+  let mut stack = vec![Element::new("#document")];
+  let mut position = 0;
+
+  while position < input.len() {
+    let rest = &input[position..];
+
+    if !rest.starts_with('<') {
+      // Plain text up until the next tag
+      let end = rest.find('<').unwrap_or(rest.len());
+      push_text(&mut stack, &decode_entities(&rest[..end]));
+      position += end;
+    } else if let Some(comment) = rest.strip_prefix("<!--") {
+      position += 4 + comment.find("-->").map_or(comment.len(), |end| end + 3);
+    } else if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+      let end = cdata.find("]]>").unwrap_or(cdata.len());
+      push_text(&mut stack, &cdata[..end]);
+      position += 9 + (end + 3).min(cdata.len());
+    } else if rest.starts_with("<!") || rest.starts_with("<?") {
+      // Doctypes, processing instructions, etc.
+      position += rest.find('>').map_or(rest.len(), |end| end + 1);
+    } else if let Some(closing) = rest.strip_prefix("</") {
+      let end = closing.find('>').unwrap_or(closing.len());
+      let name = local_name(closing[..end].trim());
+      position += 2 + (end + 1).min(closing.len());
+
+      // Close the most recent matching element (and anything left open
+      // inside of it). Closing tags without a match are ignored
+      if let Some(index) = stack.iter().rposition(|e| e.name == name) {
+        if index > 0 {
+          while stack.len() > index {
+            close_element(&mut stack);
+          }
+        }
+      }
+    } else if let Some((element, self_closing, length)) = parse_tag(rest) {
+      position += length;
+
+      if RAW_TEXT_ELEMENTS.contains(&element.name.as_str()) && !self_closing {
+        // Everything up until the closing tag is kept as-is
+        let closing_tag = format!("</{}", element.name);
+        let contents = &input[position..];
+        let end =
+          find_ignore_case(contents, &closing_tag).unwrap_or(contents.len());
+        let mut element = element;
+        element
+          .children
+          .push(Node::Text(contents[..end].to_string()));
+        push_node(&mut stack, Node::Element(element));
+
+        position += end;
+        position += input[position..]
+          .find('>')
+          .map_or(input.len() - position, |end| end + 1);
+      } else if self_closing || VOID_ELEMENTS.contains(&element.name.as_str()) {
+        push_node(&mut stack, Node::Element(element));
+      } else {
+        stack.push(element);
+      }
+    } else {
+      // A lone `<` that does not begin a tag
+      push_text(&mut stack, "<");
+      position += 1;
+    }
+  }
+
+  while stack.len() > 1 {
+    close_element(&mut stack);
+  }
+
+  stack.pop().unwrap_or_else(|| Element::new("#document"))
+}
+
+/// Pops the top element of the stack and appends it to its parent
+fn close_element(stack: &mut Vec<Element>) {
+  if let Some(element) = stack.pop() {
+    push_node(stack, Node::Element(element));
+  }
+}
+
+fn push_node(stack: &mut [Element], node: Node) {
+  if let Some(parent) = stack.last_mut() {
+    parent.children.push(node);
+  }
+}
+
+/// Appends text to the top element, merging with the previous text node
+fn push_text(stack: &mut [Element], text: &str) {
+  if text.is_empty() {
+    return;
+  }
+
+  if let Some(parent) = stack.last_mut() {
+    if let Some(Node::Text(previous)) = parent.children.last_mut() {
+      previous.push_str(text);
+    } else {
+      parent.children.push(Node::Text(text.to_string()));
+    }
+  }
+}
+
+/// Parses an opening tag at the start of `input`.
+///
+/// Returns the element, whether it was self closing, and the length of the
+/// tag in bytes.
+fn parse_tag(input: &str) -> Option<(Element, bool, usize)> {
+  let bytes = input.as_bytes();
+  let mut position = 1;
+
+  let name_end = input[position..]
+    .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+    .map_or(input.len(), |end| end + position);
+  let name = &input[position..name_end];
+
+  if name.is_empty() || !name.starts_with(|c: char| c.is_alphabetic()) {
+    return None;
+  }
+
+  let mut element = Element::new(local_name(name));
+  let mut self_closing = false;
+  position = name_end;
+
+  // Any whitespace separates names, the same as where the tag name ends, so
+  // that each step moves past at least one character
+  while let Some(character) = input[position..].chars().next() {
+    match character {
+      '>' => return Some((element, self_closing, position + 1)),
+      '/' => {
+        self_closing = true;
+        position += 1;
+      }
+      character if character.is_whitespace() => {
+        position += character.len_utf8();
+      }
+      _ => {
+        self_closing = false;
+
+        // Attribute name
+        let attribute_end = input[position..]
+          .find(|c: char| c.is_whitespace() || c == '=' || c == '>' || c == '/')
+          .map_or(input.len(), |end| end + position);
+        let attribute_name = input[position..attribute_end].to_lowercase();
+        position = skip_whitespace(input, attribute_end);
+
+        // Attribute value (if any)
+        let mut value = String::new();
+        if position < bytes.len() && bytes[position] == b'=' {
+          position = skip_whitespace(input, position + 1);
+
+          if position < bytes.len()
+            && (bytes[position] == b'"' || bytes[position] == b'\'')
+          {
+            let quote = bytes[position] as char;
+            let value_end = input[position + 1..]
+              .find(quote)
+              .map_or(input.len(), |end| end + position + 1);
+            value = decode_entities(&input[position + 1..value_end]);
+            position = (value_end + 1).min(input.len());
+          } else {
+            let value_end = input[position..]
+              .find(|c: char| c.is_whitespace() || c == '>')
+              .map_or(input.len(), |end| end + position);
+            value = decode_entities(&input[position..value_end]);
+            position = value_end;
+          }
+        }
+
+        element.attributes.push((attribute_name, value));
+      }
+    }
+  }
+
+  // Unterminated tag: treat whatever was read as the whole tag
+  Some((element, self_closing, input.len()))
+}
+
+/// The position of the first character at or after `position` that isn't
+/// whitespace
+fn skip_whitespace(input: &str, position: usize) -> usize {
+  input[position..]
+    .find(|c: char| !c.is_whitespace())
+    .map_or(input.len(), |offset| position + offset)
+}
+
+/// Lowercases a tag name and strips any namespace prefix (`svg:image`)
+fn local_name(name: &str) -> String {
+  name.rsplit(':').next().unwrap_or(name).to_lowercase()
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+  haystack
+    .to_lowercase()
+    .find(&needle.to_lowercase())
+    // Lowercasing can (rarely) change byte lengths, guard against that
+    .filter(|index| haystack.is_char_boundary(*index))
+}
+
+/// Replaces character references (`&amp;`, `&#8212;`, `&#x2014;` etc.) with
+/// the characters they represent. Unknown references are left untouched
+pub fn decode_entities(input: &str) -> String {
+  if !input.contains('&') {
+    return input.to_string();
+  }
+
+  let mut output = String::with_capacity(input.len());
+  let mut rest = input;
+
+  while let Some(start) = rest.find('&') {
+    output.push_str(&rest[..start]);
+    rest = &rest[start..];
+
+    let decoded =
+      rest[1..]
+        .find(';')
+        .filter(|end| *end <= 32)
+        .and_then(|end| {
+          let reference = &rest[1..=end];
+          let character = if let Some(number) = reference.strip_prefix('#') {
+            if let Some(hex) = number
+              .strip_prefix('x')
+              .or_else(|| number.strip_prefix('X'))
+            {
+              u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+            } else {
+              number.parse().ok().and_then(char::from_u32)
+            }
+          } else {
+            named_entity(reference)
+          };
+
+          character.map(|c| (c, end + 2))
+        });
+
+    if let Some((character, length)) = decoded {
+      output.push(character);
+      rest = &rest[length..];
+    } else {
+      output.push('&');
+      rest = &rest[1..];
+    }
+  }
+
+  output.push_str(rest);
+  output
+}
+
+/// The named HTML entities most commonly found in ebooks
+fn named_entity(name: &str) -> Option<char> {
+  Some(match name {
+    "amp" => '&',
+    "lt" => '<',
+    "gt" => '>',
+    "quot" => '"',
+    "apos" => '\'',
+    "nbsp" => '\u{A0}',
+    "shy" => '\u{AD}',
+    "ensp" => '\u{2002}',
+    "emsp" => '\u{2003}',
+    "thinsp" => '\u{2009}',
+    "zwnj" => '\u{200C}',
+    "zwj" => '\u{200D}',
+    "ndash" => '\u{2013}',
+    "mdash" => '\u{2014}',
+    "lsquo" => '\u{2018}',
+    "rsquo" => '\u{2019}',
+    "sbquo" => '\u{201A}',
+    "ldquo" => '\u{201C}',
+    "rdquo" => '\u{201D}',
+    "bdquo" => '\u{201E}',
+    "dagger" => '\u{2020}',
+    "Dagger" => '\u{2021}',
+    "bull" => '\u{2022}',
+    "hellip" => '\u{2026}',
+    "prime" => '\u{2032}',
+    "Prime" => '\u{2033}',
+    "lsaquo" => '\u{2039}',
+    "rsaquo" => '\u{203A}',
+    "laquo" => '\u{AB}',
+    "raquo" => '\u{BB}',
+    "iexcl" => '\u{A1}',
+    "iquest" => '\u{BF}',
+    "cent" => '\u{A2}',
+    "pound" => '\u{A3}',
+    "yen" => '\u{A5}',
+    "euro" => '\u{20AC}',
+    "sect" => '\u{A7}',
+    "para" => '\u{B6}',
+    "middot" => '\u{B7}',
+    "copy" => '\u{A9}',
+    "reg" => '\u{AE}',
+    "trade" => '\u{2122}',
+    "deg" => '\u{B0}',
+    "plusmn" => '\u{B1}',
+    "times" => '\u{D7}',
+    "divide" => '\u{F7}',
+    "frac14" => '\u{BC}',
+    "frac12" => '\u{BD}',
+    "frac34" => '\u{BE}',
+    "sup1" => '\u{B9}',
+    "sup2" => '\u{B2}',
+    "sup3" => '\u{B3}',
+    "acute" => '\u{B4}',
+    "micro" => '\u{B5}',
+    "ordf" => '\u{AA}',
+    "ordm" => '\u{BA}',
+    "not" => '\u{AC}',
+    "Agrave" => 'À',
+    "Aacute" => 'Á',
+    "Acirc" => 'Â',
+    "Atilde" => 'Ã',
+    "Auml" => 'Ä',
+    "Aring" => 'Å',
+    "AElig" => 'Æ',
+    "Ccedil" => 'Ç',
+    "Egrave" => 'È',
+    "Eacute" => 'É',
+    "Ecirc" => 'Ê',
+    "Euml" => 'Ë',
+    "Igrave" => 'Ì',
+    "Iacute" => 'Í',
+    "Icirc" => 'Î',
+    "Iuml" => 'Ï',
+    "Ntilde" => 'Ñ',
+    "Ograve" => 'Ò',
+    "Oacute" => 'Ó',
+    "Ocirc" => 'Ô',
+    "Otilde" => 'Õ',
+    "Ouml" => 'Ö',
+    "Oslash" => 'Ø',
+    "Ugrave" => 'Ù',
+    "Uacute" => 'Ú',
+    "Ucirc" => 'Û',
+    "Uuml" => 'Ü',
+    "Yacute" => 'Ý',
+    "szlig" => 'ß',
+    "agrave" => 'à',
+    "aacute" => 'á',
+    "acirc" => 'â',
+    "atilde" => 'ã',
+    "auml" => 'ä',
+    "aring" => 'å',
+    "aelig" => 'æ',
+    "ccedil" => 'ç',
+    "egrave" => 'è',
+    "eacute" => 'é',
+    "ecirc" => 'ê',
+    "euml" => 'ë',
+    "igrave" => 'ì',
+    "iacute" => 'í',
+    "icirc" => 'î',
+    "iuml" => 'ï',
+    "ntilde" => 'ñ',
+    "ograve" => 'ò',
+    "oacute" => 'ó',
+    "ocirc" => 'ô',
+    "otilde" => 'õ',
+    "ouml" => 'ö',
+    "oslash" => 'ø',
+    "ugrave" => 'ù',
+    "uacute" => 'ú',
+    "ucirc" => 'û',
+    "uuml" => 'ü',
+    "yacute" => 'ý',
+    "yuml" => 'ÿ',
+    "OElig" => 'Œ',
+    "oelig" => 'œ',
+    _ => return None,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn non_ascii_whitespace_separates_attributes() {
+    let root = parse("<p\u{a0}class=\"x\"\u{3000}id = 'y'>Text</p>");
+    let paragraph = root.find("p").unwrap();
+
+    assert_eq!(paragraph.attr("class"), Some("x"));
+    assert_eq!(paragraph.attr("id"), Some("y"));
+    assert_eq!(paragraph.text(), "Text");
+  }
+
+  #[test]
+  fn elements_nest_and_keep_their_text() {
+    let root = parse("<div><p>One <em>two</em> three</p><p>Four</p></div>");
+    let div = root.find("div").unwrap();
+
+    let paragraphs: Vec<&Element> = div.child_elements().collect();
+    assert_eq!(paragraphs.len(), 2);
+    assert_eq!(paragraphs[0].text(), "One two three");
+    assert_eq!(paragraphs[0].find("em").unwrap().text(), "two");
+    assert_eq!(paragraphs[1].text(), "Four");
+  }
+
+  #[test]
+  fn names_are_lowercased_without_their_prefix() {
+    let root =
+      parse("<SVG:Image XLINK:HREF=\"cover.jpg\" epub:type=noteref />");
+    let image = root.find("image").unwrap();
+
+    assert_eq!(image.attr("xlink:href"), Some("cover.jpg"));
+    assert_eq!(image.attr("href"), Some("cover.jpg"));
+    assert_eq!(image.attr("epub:type"), Some("noteref"));
+    assert_eq!(image.attr("type"), Some("noteref"));
+  }
+
+  #[test]
+  fn void_and_self_closing_elements_have_no_children() {
+    let root = parse("<p>a<br>b<img src='x.png'>c<span/>d</p>");
+    let paragraph = root.find("p").unwrap();
+
+    let names: Vec<&str> = paragraph
+      .child_elements()
+      .map(|element| element.name.as_str())
+      .collect();
+    assert_eq!(names, ["br", "img", "span"]);
+    assert_eq!(paragraph.text(), "abcd");
+  }
+
+  #[test]
+  fn broken_markup_is_recovered_from() {
+    // Unclosed elements are closed by their parent, stray closing tags are
+    // ignored, and a `<` that doesn't start a tag is text
+    let root = parse("<div><p>One<p>Two</span></div><b>1 < 2");
+
+    let div = root.find("div").unwrap();
+    assert_eq!(div.text(), "OneTwo");
+    assert_eq!(root.find("b").unwrap().text(), "1 < 2");
+  }
+
+  #[test]
+  fn markup_declarations_are_skipped() {
+    let root = parse(
+      "<?xml version=\"1.0\"?><!DOCTYPE html><p>a<!-- <b>not</b> -->b\
+      <![CDATA[<i>c</i>]]></p>",
+    );
+    let paragraph = root.find("p").unwrap();
+
+    assert_eq!(paragraph.child_elements().count(), 0);
+    assert_eq!(paragraph.text(), "ab<i>c</i>");
+  }
+
+  #[test]
+  fn style_and_script_contents_are_raw_text() {
+    let root = parse("<style>p > a { color: red }</STYLE><p>After</p>");
+
+    let style = root.find("style").unwrap();
+    assert_eq!(style.text(), "p > a { color: red }");
+    assert_eq!(style.child_elements().count(), 0);
+    assert_eq!(root.find("p").unwrap().text(), "After");
+  }
+
+  #[test]
+  fn entities_are_decoded() {
+    assert_eq!(
+      decode_entities("&amp;&lt;&#8212;&#x2014;&nbsp;&copy;"),
+      "&<\u{2014}\u{2014}\u{a0}\u{a9}"
+    );
+    assert_eq!(decode_entities("&unknown; & &#xZZ;"), "&unknown; & &#xZZ;");
+
+    let root = parse("<a title=\"Tom &amp; Jerry\">&lt;3</a>");
+    let link = root.find("a").unwrap();
+    assert_eq!(link.attr("title"), Some("Tom & Jerry"));
+    assert_eq!(link.text(), "<3");
+  }
+}